@@ -14,6 +14,8 @@
 
 //! High level JSON/HTTP client API
 
+use std::time::Duration;
+
 use hyper;
 use hyper::client::Response;
 use hyper::status::{StatusClass, StatusCode};
@@ -28,7 +30,15 @@ use rest::Error;
 pub fn get<'a, T>(url: &'a str) -> Result<T, Error>
 	where for<'de> T: Deserialize<'de>
 {
-	let client = hyper::Client::new();
+	get_timeout(url, None)
+}
+
+/// Same as get but gives up if the server takes longer than the provided
+/// timeout to send or respond.
+pub fn get_timeout<'a, T>(url: &'a str, timeout: Option<Duration>) -> Result<T, Error>
+	where for<'de> T: Deserialize<'de>
+{
+	let client = client(timeout);
 	let res = check_error(client.get(url).send())?;
 	serde_json::from_reader(res)
 		.map_err(|e| Error::Internal(format!("Server returned invalid JSON: {}", e)))
@@ -41,19 +51,39 @@ pub fn get<'a, T>(url: &'a str) -> Result<T, Error>
 pub fn post<'a, IN, OUT>(url: &'a str, input: &IN) -> Result<OUT, Error>
 	where IN: Serialize,
 	      for<'de> OUT: Deserialize<'de>
+{
+	post_timeout(url, input, None)
+}
+
+/// Same as post but gives up if the server takes longer than the provided
+/// timeout to send or respond.
+pub fn post_timeout<'a, IN, OUT>(
+	url: &'a str,
+	input: &IN,
+	timeout: Option<Duration>,
+) -> Result<OUT, Error>
+	where IN: Serialize,
+	      for<'de> OUT: Deserialize<'de>
 {
 	let in_json = serde_json::to_string(input)
 		.map_err(|e| Error::Internal(format!("Could not serialize data to JSON: {}", e)))?;
-	let client = hyper::Client::new();
+	let client = client(timeout);
 	let res = check_error(client.post(url).body(&mut in_json.as_bytes()).send())?;
 	serde_json::from_reader(res)
 		.map_err(|e| Error::Internal(format!("Server returned invalid JSON: {}", e)))
 }
 
+fn client(timeout: Option<Duration>) -> hyper::Client {
+	let mut client = hyper::Client::new();
+	client.set_read_timeout(timeout);
+	client.set_write_timeout(timeout);
+	client
+}
+
 // convert hyper error and check for non success response codes
 fn check_error(res: hyper::Result<Response>) -> Result<Response, Error> {
 	if let Err(e) = res {
-		return Err(Error::Internal(format!("Error during request: {}", e)));
+		return Err(Error::Unreachable(format!("Error during request: {}", e)));
 	}
 	let response = res.unwrap();
 	match response.status.class() {
@@ -18,6 +18,7 @@ use std::thread;
 
 use chain;
 use core::core::Transaction;
+use core::core::hash::Hashed;
 use core::ser;
 use pool;
 use rest::*;
@@ -86,6 +87,49 @@ impl ApiEndpoint for OutputApi {
 	}
 }
 
+/// ApiEndpoint implementation for the outputs created by the block at a given
+/// height that are still unspent, for wallets to find theirs without knowing
+/// their commitments.
+#[derive(Clone)]
+pub struct BlockOutputApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for BlockOutputApi {
+	type ID = String;
+	type T = Vec<Output>;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, id: String) -> ApiResult<Vec<Output>> {
+		debug!(LOGGER, "GET outputs at height {}", id);
+		let height = id.parse::<u64>().map_err(|_| {
+			Error::Argument(format!("Not a valid height: {}", id))
+		})?;
+
+		let header = self.chain
+			.get_header_by_height(height)
+			.map_err(|_| Error::NotFound)?;
+		let block = self.chain
+			.get_block(&header.hash())
+			.map_err(|e| Error::Internal(format!("{:?}", e)))?;
+
+		Ok(
+			block
+				.outputs
+				.iter()
+				.filter(|out| self.chain.get_unspent(&out.commit).is_ok())
+				.map(|out| Output::from_output(out, &header))
+				.collect(),
+		)
+	}
+}
+
 /// ApiEndpoint implementation for the transaction pool, to check its status
 /// and size as well as push new transactions.
 #[derive(Clone)]
@@ -171,6 +215,10 @@ pub fn start_rest_apis<T>(
 			"/chain/utxo".to_string(),
 			OutputApi { chain: chain.clone() },
 		);
+		apis.register_endpoint(
+			"/chain/utxos".to_string(),
+			BlockOutputApi { chain: chain.clone() },
+		);
 		apis.register_endpoint("/pool".to_string(), PoolApi { tx_pool: tx_pool });
 
 		apis.start(&addr[..]).unwrap_or_else(|e| {
@@ -22,11 +22,14 @@ use std::error;
 use std::fmt::{self, Display, Debug, Formatter};
 use std::io::Read;
 use std::net::ToSocketAddrs;
+use std::path::Path;
 use std::string::ToString;
 use std::str::FromStr;
 use std::mem;
 
 use iron::{Iron, Request, Response, IronResult, IronError, status, headers, Listening};
+#[cfg(unix)]
+use iron::Protocol;
 use iron::method::Method;
 use iron::modifiers::Header;
 use iron::middleware::Handler;
@@ -44,6 +47,9 @@ pub enum Error {
 	Internal(String),
 	Argument(String),
 	NotFound,
+	/// The client couldn't get a response from the server at all, it can't
+	/// be reached or took too long
+	Unreachable(String),
 }
 
 impl Display for Error {
@@ -52,6 +58,7 @@ impl Display for Error {
 			Error::Argument(ref s) => write!(f, "Bad arguments: {}", s),
 			Error::Internal(ref s) => write!(f, "Internal error: {}", s),
 			Error::NotFound => write!(f, "Not found."),
+			Error::Unreachable(ref s) => write!(f, "Unreachable: {}", s),
 		}
 	}
 }
@@ -62,6 +69,7 @@ impl error::Error for Error {
 			Error::Argument(_) => "Bad arguments.",
 			Error::Internal(_) => "Internal error.",
 			Error::NotFound => "Not found.",
+			Error::Unreachable(_) => "Unreachable.",
 		}
 	}
 }
@@ -72,6 +80,7 @@ impl From<Error> for IronError {
 			Error::Argument(_) => IronError::new(e, status::Status::BadRequest),
 			Error::Internal(_) => IronError::new(e, status::Status::InternalServerError),
 			Error::NotFound => IronError::new(e, status::Status::NotFound),
+			Error::Unreachable(_) => IronError::new(e, status::Status::BadGateway),
 		}
 	}
 }
@@ -268,6 +277,30 @@ impl ApiServer {
 		return_value
 	}
 
+	/// Starts the ApiServer on the Unix domain socket at the provided path,
+	/// replacing the socket file a previous run may have left behind. Fails
+	/// if a server is still listening on it.
+	#[cfg(unix)]
+	pub fn start_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+		let listener = unix_socket::UnixSocketListener::bind(path.as_ref())
+			.map_err(|e| e.to_string())?;
+		let r = mem::replace(&mut self.router, Router::new());
+		let listening = Iron::new(r)
+			.listen(listener, Protocol::http())
+			.map_err(|e| e.to_string())?;
+		self.server_listener = Some(listening);
+		Ok(())
+	}
+
+	/// Unix domain sockets only exist on unix platforms.
+	#[cfg(not(unix))]
+	pub fn start_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+		Err(format!(
+			"can't listen on {}, unix sockets aren't supported on this platform",
+			path.as_ref().display()
+		))
+	}
+
 	/// Stops the API server
 	pub fn stop(&mut self) {
 		let r = mem::replace(&mut self.server_listener, None);
@@ -348,6 +381,106 @@ impl ApiServer {
 }
 
 
+/// Unix domain socket listener serving an ApiServer, see
+/// ApiServer::start_unix.
+#[cfg(unix)]
+mod unix_socket {
+	use std::fs;
+	use std::io::{self, Read, Write};
+	use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+	use std::os::unix::fs::FileTypeExt;
+	use std::os::unix::net::{UnixListener, UnixStream};
+	use std::path::Path;
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use hyper;
+	use hyper::net::{NetworkListener, NetworkStream};
+
+	/// Address reported for the peers connecting through the socket, which
+	/// have none. They're on the same machine, so the loopback one.
+	fn local_socket_addr() -> SocketAddr {
+		SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0))
+	}
+
+	/// Listener on a Unix domain socket, shared by the server threads.
+	#[derive(Clone)]
+	pub struct UnixSocketListener(Arc<UnixListener>);
+
+	impl UnixSocketListener {
+		/// Binds the socket at the provided path, first removing a stale
+		/// socket file already there. A socket something still accepts
+		/// connections on is refused with AddrInUse, any other kind of file
+		/// is left alone and the bind fails.
+		pub fn bind(path: &Path) -> io::Result<UnixSocketListener> {
+			if let Ok(meta) = fs::symlink_metadata(path) {
+				if meta.file_type().is_socket() {
+					if UnixStream::connect(path).is_ok() {
+						return Err(io::Error::new(
+							io::ErrorKind::AddrInUse,
+							format!("{} is already in use by a running server", path.display()),
+						));
+					}
+					fs::remove_file(path)?;
+				}
+			}
+			UnixListener::bind(path).map(|listener| UnixSocketListener(Arc::new(listener)))
+		}
+	}
+
+	impl NetworkListener for UnixSocketListener {
+		type Stream = UnixSocketStream;
+
+		fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+			let (stream, _) = self.0.accept()?;
+			Ok(UnixSocketStream(Arc::new(stream)))
+		}
+
+		fn local_addr(&mut self) -> io::Result<SocketAddr> {
+			Ok(local_socket_addr())
+		}
+	}
+
+	/// Connection accepted on a UnixSocketListener. Its clones share the
+	/// socket rather than duplicating it, which can't fail.
+	#[derive(Clone)]
+	pub struct UnixSocketStream(Arc<UnixStream>);
+
+	impl Read for UnixSocketStream {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			(&*self.0).read(buf)
+		}
+	}
+
+	impl Write for UnixSocketStream {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			(&*self.0).write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			(&*self.0).flush()
+		}
+	}
+
+	impl NetworkStream for UnixSocketStream {
+		fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+			Ok(local_socket_addr())
+		}
+
+		fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+			self.0.set_read_timeout(dur)
+		}
+
+		fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+			self.0.set_write_timeout(dur)
+		}
+
+		fn close(&mut self, how: Shutdown) -> io::Result<()> {
+			self.0.shutdown(how)
+		}
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -386,4 +519,26 @@ mod test {
 		let mut apis = ApiServer::new("/v1".to_string());
 		apis.register_endpoint("/animal".to_string(), TestApi);
 	}
+
+	#[cfg(unix)]
+	#[test]
+	fn unix_socket_in_use() {
+		use std::{env, fs, io, process};
+		use super::unix_socket::UnixSocketListener;
+
+		let path = env::temp_dir().join(format!("grin_api_{}.sock", process::id()));
+		let _ = fs::remove_file(&path);
+		let listener = UnixSocketListener::bind(&path).unwrap();
+
+		// a running server keeps its socket
+		match UnixSocketListener::bind(&path) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::AddrInUse),
+			Ok(_) => panic!("bound a socket in use"),
+		}
+
+		// the file left behind once it's gone gets replaced
+		drop(listener);
+		UnixSocketListener::bind(&path).unwrap();
+		fs::remove_file(&path).unwrap();
+	}
 }
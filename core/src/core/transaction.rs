@@ -18,7 +18,7 @@ use byteorder::{ByteOrder, BigEndian};
 use blake2::blake2b::blake2b;
 use secp::{self, Secp256k1, Message, Signature};
 use secp::pedersen::{RangeProof, Commitment};
-use std::ops;
+use std::{error, fmt, ops};
 
 use core::Committed;
 use core::pmmr::Summable;
@@ -55,6 +55,24 @@ impl From<secp::Error> for Error {
 	}
 }
 
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::OddFee => write!(f, "transaction fee can't be odd"),
+			Error::Secp(ref e) => write!(f, "transaction secp error: {}", e),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(error::Error + 'static)> {
+		match *self {
+			Error::OddFee => None,
+			Error::Secp(ref e) => Some(e),
+		}
+	}
+}
+
 /// Construct msg bytes from tx fee and lock_height
 pub fn kernel_sig_msg(fee: u64, lock_height: u64) -> [u8; 32] {
 	let mut bytes = [0; 32];
@@ -564,6 +564,8 @@ impl Miner {
 			fees,
 			key_id,
 			height,
+			min_fees: None,
+			max_fees: None,
 		};
 
 		let (output, kernel, block_fees) = self.get_coinbase(block_fees);
@@ -595,7 +597,7 @@ impl Miner {
 		if self.config.burn_reward {
 			let keychain = Keychain::from_random_seed().unwrap();
 			let key_id = keychain.derive_key_id(1).unwrap();
-			let (out, kern) = core::Block::reward_output(&keychain, &key_id, block_fees.fees)
+			let (out, kern) = core::Block::reward_output(&keychain, &key_id, block_fees.coinbase_fees())
 				.unwrap();
 			(out, kern, block_fees)
 		} else {
@@ -604,13 +606,29 @@ impl Miner {
 				self.config.wallet_receiver_url.as_str()
 			);
 			let request = WalletReceiveRequest::Coinbase(block_fees.clone());
-			let res: CbData = api::client::post(url.as_str(), &request).expect(
-				format!(
-					"(Server ID: {}) Wallet receiver unreachable, could not claim reward. Is it running?",
-					self.debug_output_id
-						.as_str()
-				).as_str(),
-			);
+			// a response garbled on the way gets asked for again
+			let res: CbData = loop {
+				let res: CbData = api::client::post(url.as_str(), &request).expect(
+					format!(
+						"(Server ID: {}) Wallet receiver unreachable, could not claim reward. Is it running?",
+						self.debug_output_id
+							.as_str()
+					).as_str(),
+				);
+				match res.verify() {
+					Ok(()) => break res,
+					Err(e) => {
+						error!(
+							LOGGER,
+							"(Server ID: {}) Corrupted coinbase data from the wallet receiver, \
+							 asking again: {}",
+							self.debug_output_id,
+							e
+						);
+						thread::sleep(std::time::Duration::from_millis(100));
+					}
+				}
+			};
 			let out_bin = util::from_hex(res.output).unwrap();
 			let kern_bin = util::from_hex(res.kernel).unwrap();
 			let key_id_bin = util::from_hex(res.key_id).unwrap();
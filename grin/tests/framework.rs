@@ -284,7 +284,7 @@ impl LocalServerContainer {
 		let mut wallet_config = WalletConfig::default();
 
 		wallet_config.api_http_addr = format!("http://{}", url);
-		wallet_config.check_node_api_http_addr = self.config.wallet_validating_node_url.clone();
+		wallet_config.check_node_api_http_addr = vec![self.config.wallet_validating_node_url.clone()];
 		wallet_config.data_file_dir = self.working_dir.clone();
 
 		let mut api_server = api::ApiServer::new("/v1".to_string());
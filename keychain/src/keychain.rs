@@ -14,6 +14,7 @@
 
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
+use std::{error, fmt};
 
 use secp;
 use secp::{Message, Secp256k1, Signature};
@@ -43,6 +44,26 @@ impl From<extkey::Error> for Error {
 	}
 }
 
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::ExtendedKey(ref e) => write!(f, "{}", e),
+			Error::Secp(ref e) => write!(f, "keychain: secp error: {}", e),
+			Error::KeyDerivation(ref s) => write!(f, "keychain: key derivation error: {}", s),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(error::Error + 'static)> {
+		match *self {
+			Error::ExtendedKey(ref e) => Some(e),
+			Error::Secp(ref e) => Some(e),
+			Error::KeyDerivation(_) => None,
+		}
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct Keychain {
 	secp: Secp256k1,
@@ -179,7 +179,8 @@ fn main() {
 		.arg(Arg::with_name("api_server_address")
 			.short("a")
 			.long("api_server_address")
-			.help("Api address of running node on which to check inputs and post transactions")
+			.help("Api address of running node on which to check inputs and post transactions, \
+				several comma separated addresses are tried in order")
 			.takes_value(true))
 
 		.subcommand(SubCommand::with_name("receive")
@@ -204,6 +205,12 @@ fn main() {
 				.help("Send the transaction to the provided server")
 				.short("d")
 				.long("dest")
+				.takes_value(true))
+			.arg(Arg::with_name("change_outputs")
+				.help("Number of outputs of equal value to split our change in")
+				.short("c")
+				.long("change_outputs")
+				.default_value("1")
 				.takes_value(true)))
 
 		.subcommand(SubCommand::with_name("burn")
@@ -215,7 +222,14 @@ fn main() {
 				.index(1)))
 
 		.subcommand(SubCommand::with_name("info")
-			.about("basic wallet info (outputs)")))
+			.about("basic wallet info (outputs)"))
+
+		.subcommand(SubCommand::with_name("unlock_all")
+			.about("** RECOVERY ONLY ** Unlocks all the outputs locked by sends, \
+				including sends that could still confirm.")
+			.arg(Arg::with_name("confirm")
+				.help("Confirms all locked outputs should be unlocked")
+				.long("confirm"))))
 
 	.get_matches();
 
@@ -342,9 +356,14 @@ fn wallet_command(wallet_args: &ArgMatches) {
 	}
 
 	if let Some(sa) = wallet_args.value_of("api_server_address") {
-		wallet_config.check_node_api_http_addr = sa.to_string().clone();
+		wallet_config.check_node_api_http_addr =
+			sa.split(',').map(|addr| addr.trim().to_string()).collect();
 	}
 
+	wallet_config.validate().unwrap_or_else(|e| {
+		panic!("Invalid wallet configuration: {}", e);
+	});
+
 	match wallet_args.subcommand() {
 
 		("receive", Some(receive_args)) => {
@@ -369,7 +388,12 @@ fn wallet_command(wallet_args: &ArgMatches) {
 						config: wallet_config.clone(),
 					},
 				);
-				apis.start(wallet_config.api_http_addr).unwrap_or_else(|e| {
+				let started = match wallet_config.listen_addr() {
+					Ok(wallet::ListenAddr::Tcp(addr)) => apis.start(addr),
+					Ok(wallet::ListenAddr::Unix(path)) => apis.start_unix(path),
+					Err(e) => Err(e.to_string()),
+				};
+				started.unwrap_or_else(|e| {
 					error!(LOGGER, "Failed to start Grin wallet receiver: {}.", e);
 				});
 			}
@@ -380,11 +404,22 @@ fn wallet_command(wallet_args: &ArgMatches) {
 				.expect("Amount to send required")
 				.parse()
 				.expect("Could not parse amount as a whole number.");
+			let change_outputs = send_args
+				.value_of("change_outputs")
+				.unwrap()
+				.parse()
+				.expect("Could not parse the number of change outputs as a whole number.");
 			let mut dest = "stdout";
 			if let Some(d) = send_args.value_of("dest") {
 				dest = d;
 			}
-			wallet::issue_send_tx(&wallet_config, &keychain, amount, dest.to_string()).unwrap();
+			wallet::issue_send_tx(
+				&wallet_config,
+				&keychain,
+				amount,
+				change_outputs,
+				dest.to_string(),
+			).unwrap();
 		}
 		("burn", Some(send_args)) => {
 			let amount = send_args
@@ -397,6 +432,14 @@ fn wallet_command(wallet_args: &ArgMatches) {
 		("info", Some(_)) => {
 			wallet::show_info(&wallet_config, &keychain);
 		}
+		("unlock_all", Some(unlock_args)) => {
+			let confirm = unlock_args.is_present("confirm");
+			let res = wallet::WalletData::with_wallet(&wallet_config, |wallet_data| {
+				wallet_data.force_unlock_all(confirm)
+			}).unwrap();
+			let count = res.unwrap_or_else(|e| panic!("{}, pass --confirm to proceed", e));
+			println!("Unlocked {} outputs", count);
+		}
 		_ => panic!("Unknown wallet command, use 'grin help wallet' for details"),
 	}
 }
@@ -16,18 +16,37 @@
 //! the wallet storage and update them.
 
 use api;
+use secp::pedersen;
 use types::*;
 use keychain::Keychain;
 use util;
+use util::LOGGER;
 
-fn refresh_output(out: &mut OutputData, api_out: Option<api::Output>, tip: &api::Tip) {
+/// Updates our output from what the node knows about it. A coinbase output
+/// stays immature until it has coinbase_maturity confirmations, on top of
+/// the lock height given by the node. Tells whether the output is a coinbase
+/// we just saw mined, the miner asks for a new one with every block template
+/// so only those are worth a history entry.
+fn refresh_output(
+	out: &mut OutputData,
+	api_out: Option<api::Output>,
+	tip: &api::Tip,
+	coinbase_maturity: u64,
+) -> bool {
+	let was_unconfirmed = out.status == OutputStatus::Unconfirmed;
 	if let Some(api_out) = api_out {
 		out.height = api_out.height;
 		out.lock_height = api_out.lock_height;
+		out.is_coinbase = match api_out.output_type {
+			api::OutputType::Coinbase => true,
+			api::OutputType::Transaction => false,
+		};
 
 		if out.status == OutputStatus::Locked {
 			// leave it Locked locally for now
-		} else if api_out.lock_height > tip.height {
+		} else if api_out.lock_height > tip.height
+			|| (out.is_coinbase && tip.height.saturating_sub(out.height) < coinbase_maturity)
+		{
 			out.status = OutputStatus::Immature;
 		} else {
 			out.status = OutputStatus::Unspent;
@@ -35,6 +54,7 @@ fn refresh_output(out: &mut OutputData, api_out: Option<api::Output>, tip: &api:
 	} else if vec![OutputStatus::Unspent, OutputStatus::Locked].contains(&out.status) {
 		out.status = OutputStatus::Spent;
 	}
+	was_unconfirmed && out.is_coinbase && out.status != OutputStatus::Unconfirmed
 }
 
 /// Goes through the list of outputs that haven't been spent yet and check
@@ -42,34 +62,92 @@ fn refresh_output(out: &mut OutputData, api_out: Option<api::Output>, tip: &api:
 pub fn refresh_outputs(config: &WalletConfig, keychain: &Keychain) -> Result<(), Error> {
 	let tip = get_tip_from_node(config)?;
 
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
-		// check each output that's not spent
-		for mut out in wallet_data.outputs.values_mut().filter(|out| {
-			out.status != OutputStatus::Spent
-		})
-		{
-			// TODO check the pool for unconfirmed
-			match get_output_from_node(config, keychain, out.value, out.n_child) {
-				Ok(api_out) => refresh_output(&mut out, api_out, &tip),
-				Err(_) => {
-					// TODO find error with connection and return
-					// error!(LOGGER, "Error contacting server node at {}. Is it running?",
-					// config.check_node_api_http_addr);
+	WalletData::with_wallet(config, |wallet_data| {
+		refresh_from_node(wallet_data, config, keychain, &tip, config.coinbase_maturity)
+	})
+}
+
+/// Refreshes the outputs that haven't been spent yet from what the provided
+/// node has at the provided tip. The wallet is only marked as confirmed up to
+/// the tip if the node answered for every output, a failed lookup leaves it
+/// where it was.
+fn refresh_from_node(
+	wallet_data: &mut WalletData,
+	node_api: &NodeApi,
+	keychain: &Keychain,
+	tip: &api::Tip,
+	coinbase_maturity: u64,
+) {
+	let mut mined = vec![];
+	let mut all_answered = true;
+	// check each output that's not spent
+	for mut out in wallet_data.outputs.values_mut().filter(|out| {
+		out.status != OutputStatus::Spent
+	})
+	{
+		// TODO check the pool for unconfirmed
+		match get_output_from_node(node_api, keychain, out.value, out.n_child) {
+			Ok(api_out) => {
+				if refresh_output(&mut out, api_out, tip, coinbase_maturity) {
+					mined.push((out.value, out.height));
 				}
 			}
+			Err(e) => {
+				warn!(LOGGER, "Could not refresh output {}: {}", out.key_id, e);
+				all_answered = false;
+			}
 		}
-	})
+	}
+
+	for (value, height) in mined {
+		wallet_data.log_tx(TxLogEntryType::Coinbase, None, value, 0, 0);
+		if let Some(entry) = wallet_data.tx_log.last_mut() {
+			entry.confirmed_height = Some(height);
+		}
+	}
+
+	// release the outputs of sends that never confirmed
+	wallet_data.expire_locks(tip.height);
+	if all_answered {
+		wallet_data.last_confirmed_height = tip.height;
+	}
+}
+
+/// Runs a request against each of the configured nodes in turn, passing it
+/// the node address, until one of them responds. A NotFound is a legit
+/// response from the node and a rejected request would be rejected by the
+/// others too, both are returned as is without trying them. Otherwise the
+/// last failure is returned, the node couldn't be reached or failed itself.
+pub fn with_node<T, F>(config: &WalletConfig, f: F) -> Result<T, Error>
+where
+	F: Fn(&str) -> Result<T, api::Error>,
+{
+	let mut last_err = api::Error::Unreachable("No node address configured".to_string());
+	for endpoint in config.node_endpoints() {
+		match f(&endpoint) {
+			Ok(res) => return Ok(res),
+			Err(api::Error::NotFound) => return Err(Error::Node(api::Error::NotFound)),
+			Err(e @ api::Error::Argument(_)) => return Err(e.into()),
+			Err(e) => {
+				warn!(LOGGER, "Node at {} failed to respond: {}", endpoint, e);
+				last_err = e;
+			}
+		}
+	}
+	Err(last_err.into())
 }
 
 pub fn get_tip_from_node(config: &WalletConfig) -> Result<api::Tip, Error> {
-	let url = format!("{}/v1/chain/1", config.check_node_api_http_addr);
-	api::client::get::<api::Tip>(url.as_str()).map_err(|e| Error::Node(e))
+	with_node(config, |node_addr| {
+		let url = format!("{}/v1/chain/1", node_addr);
+		api::client::get_timeout::<api::Tip>(url.as_str(), config.node_api_timeout())
+	})
 }
 
 // queries a reachable node for a given output, checking whether it's been
 // confirmed
 fn get_output_from_node(
-	config: &WalletConfig,
+	node_api: &NodeApi,
 	keychain: &Keychain,
 	amount: u64,
 	derivation: u32,
@@ -78,14 +156,188 @@ fn get_output_from_node(
 	let key_id = keychain.derive_key_id(derivation)?;
 	let commit = keychain.commit(amount, &key_id)?;
 
-	let url = format!(
-		"{}/v1/chain/utxo/{}",
-		config.check_node_api_http_addr,
-		util::to_hex(commit.as_ref().to_vec())
-	);
-	match api::client::get::<api::Output>(url.as_str()) {
-		Ok(out) => Ok(Some(out)),
-		Err(api::Error::NotFound) => Ok(None),
-		Err(e) => Err(Error::Node(e)),
+	node_api.get_output(&commit)
+}
+
+/// The configured nodes, queried through their HTTP API.
+impl NodeApi for WalletConfig {
+	fn get_tip(&self) -> Result<api::Tip, Error> {
+		get_tip_from_node(self)
+	}
+
+	fn get_output(&self, commit: &pedersen::Commitment) -> Result<Option<api::Output>, Error> {
+		let res = with_node(self, |node_addr| {
+			let url = format!(
+				"{}/v1/chain/utxo/{}",
+				node_addr,
+				util::to_hex(commit.as_ref().to_vec())
+			);
+			api::client::get_timeout::<api::Output>(url.as_str(), self.node_api_timeout())
+		});
+		match res {
+			Ok(out) => Ok(Some(out)),
+			Err(Error::Node(api::Error::NotFound)) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn get_block_outputs(&self, height: u64) -> Result<Vec<api::Output>, Error> {
+		with_node(self, |node_addr| {
+			let url = format!("{}/v1/chain/utxos/{}", node_addr, height);
+			api::client::get_timeout::<Vec<api::Output>>(url.as_str(), self.node_api_timeout())
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use api;
+	use core::consensus;
+	use core::core::Block;
+	use keychain::Keychain;
+	use secp::pedersen;
+	use test_util::{keyed_output, MockNode};
+	use types::*;
+	use super::{get_tip_from_node, refresh_from_node, refresh_output};
+
+	/// Node that has a tip but fails every output lookup
+	struct FailingNode;
+
+	impl NodeApi for FailingNode {
+		fn get_tip(&self) -> Result<api::Tip, Error> {
+			Ok(api::Tip { height: 20 })
+		}
+
+		fn get_output(&self, _: &pedersen::Commitment) -> Result<Option<api::Output>, Error> {
+			Err(Error::NodeUnreachable("down".to_string()))
+		}
+
+		fn get_block_outputs(&self, _: u64) -> Result<Vec<api::Output>, Error> {
+			Err(Error::NodeUnreachable("down".to_string()))
+		}
+	}
+
+	#[test]
+	fn failed_lookups_keep_confirmed_height() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut wallet_data = WalletData::new();
+		wallet_data.add_output(keyed_output(&keychain, 1, 10, OutputStatus::Unspent)).unwrap();
+		wallet_data.last_confirmed_height = 5;
+
+		let tip = api::Tip { height: 20 };
+		refresh_from_node(&mut wallet_data, &FailingNode, &keychain, &tip, 3);
+		assert_eq!(wallet_data.last_confirmed_height(), 5);
+
+		// a node that answers, even without the output, moves it to the tip
+		let node = MockNode::new(20);
+		refresh_from_node(&mut wallet_data, &node, &keychain, &tip, 3);
+		assert_eq!(wallet_data.last_confirmed_height(), 20);
+	}
+
+	#[test]
+	fn node_api_timeout() {
+		// accepts connections but never responds
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let config = WalletConfig {
+			check_node_api_http_addr: vec![format!("http://{}", listener.local_addr().unwrap())],
+			node_api_timeout_secs: 1,
+			..WalletConfig::default()
+		};
+
+		let start = Instant::now();
+		match get_tip_from_node(&config) {
+			Err(Error::NodeUnreachable(_)) => {}
+			res => panic!("expected an unreachable node, got {:?}", res.map(|tip| tip.height)),
+		}
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn node_connection_refused() {
+		// grab a free port, nothing listens on it once the listener is dropped
+		let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let config = WalletConfig {
+			check_node_api_http_addr: vec![format!("http://{}", addr)],
+			node_api_timeout_secs: 1,
+			..WalletConfig::default()
+		};
+
+		match get_tip_from_node(&config) {
+			Err(Error::NodeUnreachable(_)) => {}
+			res => panic!("expected an unreachable node, got {:?}", res.map(|tip| tip.height)),
+		}
+	}
+
+	#[test]
+	fn node_bad_request() {
+		// answers the first request with a 400, the other node is never asked
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let unused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let config = WalletConfig {
+			check_node_api_http_addr: vec![
+				format!("http://{}", listener.local_addr().unwrap()),
+				format!("http://{}", unused),
+			],
+			node_api_timeout_secs: 1,
+			..WalletConfig::default()
+		};
+		let node = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut buf = [0; 1024];
+			let _ = stream.read(&mut buf).unwrap();
+			let res = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+			stream.write_all(res.as_bytes()).unwrap();
+		});
+
+		match get_tip_from_node(&config) {
+			Err(Error::NodeRejected(_)) => {}
+			res => panic!("expected a rejected request, got {:?}", res.map(|tip| tip.height)),
+		}
+		node.join().unwrap();
+	}
+
+	#[test]
+	fn coinbase_maturity_from_config() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (reward, _) = Block::reward_output(&keychain, &key_id, 0).unwrap();
+		// coinbase mined at 10 on a chain with a maturity of 3, 10 blocks ago
+		let api_out = || {
+			api::Output {
+				output_type: api::OutputType::Coinbase,
+				commit: reward.commitment(),
+				proof: reward.proof,
+				height: 10,
+				lock_height: 13,
+			}
+		};
+		let tip = api::Tip { height: 20 };
+		let refreshed = |coinbase_maturity| {
+			let mut out = keyed_output(&keychain, 1, consensus::REWARD, OutputStatus::Unconfirmed);
+			out.is_coinbase = true;
+			assert!(refresh_output(&mut out, Some(api_out()), &tip, coinbase_maturity));
+			// only seen mined once
+			let mut again = out.clone();
+			assert!(!refresh_output(&mut again, Some(api_out()), &tip, coinbase_maturity));
+			out
+		};
+
+		let config = WalletConfig {
+			coinbase_maturity: 3,
+			..WalletConfig::default()
+		};
+		let out = refreshed(config.coinbase_maturity);
+		assert_eq!(out.status, OutputStatus::Unspent);
+		assert!(out.is_spendable(tip.height, config.coinbase_maturity, config.min_confirmations));
+
+		let config = WalletConfig::default();
+		assert_eq!(config.coinbase_maturity, consensus::COINBASE_MATURITY);
+		let out = refreshed(config.coinbase_maturity);
+		assert_eq!(out.status, OutputStatus::Immature);
+		assert!(!out.is_spendable(tip.height, config.coinbase_maturity, config.min_confirmations));
 	}
 }
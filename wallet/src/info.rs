@@ -20,11 +20,11 @@ pub fn show_info(config: &WalletConfig, keychain: &Keychain) {
 	let root_key_id = keychain.root_key_id();
 	let _ = checker::refresh_outputs(&config, &keychain);
 
-	// operate within a lock on wallet data
-	let _ = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+	// read the wallet data, nothing gets written back
+	let _ = WalletData::read_wallet(config, |wallet_data| {
 
 		println!("Outputs - ");
-		println!("key_id, height, lock_height, status, zero_ok, value");
+		println!("key_id, height, lock_height, status, zero_ok, value, label");
 		println!("----------------------------------");
 
 		let mut outputs = wallet_data
@@ -35,13 +35,14 @@ pub fn show_info(config: &WalletConfig, keychain: &Keychain) {
 		outputs.sort_by_key(|out| out.n_child);
 		for out in outputs {
 			println!(
-				"{}, {}, {}, {:?}, {}, {}",
+				"{}, {}, {}, {:?}, {}, {}, {}",
 				out.key_id,
 				out.height,
 				out.lock_height,
 				out.status,
 				out.zero_ok,
-				out.value
+				out.value,
+				out.label.as_ref().map(|l| l.as_str()).unwrap_or("")
 			);
 		}
 	});
@@ -17,6 +17,8 @@
 extern crate byteorder;
 extern crate blake2_rfc as blake2;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate slog;
 extern crate rand;
 extern crate serde;
@@ -35,8 +37,16 @@ mod info;
 mod receiver;
 mod sender;
 mod types;
+#[cfg(test)]
+mod test_util;
 
 pub use info::show_info;
-pub use receiver::{WalletReceiver, receive_json_tx};
+pub use receiver::{WalletReceiver, receive_json_tx, receive_partial_tx};
 pub use sender::{issue_send_tx, issue_burn_tx};
-pub use types::{WalletConfig, WalletReceiveRequest, BlockFees, CbData};
+pub use types::{WalletConfig, ListenAddr, OutputOrdering, WalletFormat, WalletData, WalletSession,
+                WalletBackend, FileBackend, MemoryBackend, AccountView, WalletBalance, WalletDiff,
+                OutputKey, OutputData, OutputStatus, SelectionStrategy, MergeReport,
+                ReconcileReport, WalletReceiveRequest, PartialTx, BlockFees, CbData, FeePolicy,
+                NodeApi, Error, amount_from_str, amount_to_str, tx_fee_checked, reconcile_fee,
+                transaction_weight, verify_fee, partial_tx_to_bytes, partial_tx_from_bytes,
+                resolve_data_file_dir, DATA_DIR_ENV_VAR, STREAM_DAT_FILE};
@@ -20,6 +20,7 @@
 //!
 //! POST /v1/wallet/receive
 //! > {
+//! >   "version": 1,
 //! >   "amount": 10,
 //! >   "blind_sum": "a12b7f...",
 //! >   "tx": "f083de...",
@@ -43,6 +44,8 @@
 //! < {
 //! <   "output": "8a90bc...",
 //! <   "kernel": "f083de...",
+//! <   "key_id": "0100...",
+//! <   "checksum": "5c21e9...",
 //! < }
 //!
 //! Note that while at this point the finalize call is completely unecessary, a
@@ -51,8 +54,10 @@
 
 use core::consensus::reward;
 use core::core::{Block, Transaction, TxKernel, Output, build};
+use core::core::hash::Hashed;
 use core::ser;
 use api::{self, ApiEndpoint, Operation, ApiResult};
+use checker;
 use keychain::{BlindingFactor, Keychain};
 use types::*;
 use util;
@@ -71,14 +76,26 @@ pub fn receive_json_tx(config: &WalletConfig,
                        keychain: &Keychain,
                        partial_tx_str: &str)
                        -> Result<(), Error> {
-	let (amount, blinding, partial_tx) = partial_tx_from_json(keychain, partial_tx_str)?;
+	receive_partial_tx(config, keychain, &PartialTx::from_json(partial_tx_str)?)
+}
+
+/// Same as receive_json_tx with an already parsed partial transaction.
+pub fn receive_partial_tx(config: &WalletConfig,
+                          keychain: &Keychain,
+                          partial_tx: &PartialTx)
+                          -> Result<(), Error> {
+	let (amount, blinding, partial_tx) = partial_tx.decode(keychain)?;
 	let final_tx = receive_transaction(config, keychain, amount, blinding, partial_tx)?;
 	let tx_hex = util::to_hex(ser::ser_vec(&final_tx).unwrap());
 
-	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
-	let _: () = api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex })
-		.map_err(|e| Error::Node(e))?;
-	Ok(())
+	checker::with_node(config, |node_addr| {
+		let url = format!("{}/v1/pool/push", node_addr);
+		api::client::post_timeout(
+			url.as_str(),
+			&TxWrapper { tx_hex: tx_hex.clone() },
+			config.node_api_timeout(),
+		)
+	})
 }
 
 /// Component used to receive coins, implements all the receiving end of the
@@ -101,12 +118,16 @@ impl ApiEndpoint for WalletReceiver {
 	}
 
 	fn operation(&self, op: String, input: WalletReceiveRequest) -> ApiResult<CbData> {
+		input.validate().map_err(|e| {
+			api::Error::Argument(format!("Invalid request data for {}: {}", op, e))
+		})?;
 		match op.as_str() {
 			"coinbase" => {
 				match input {
 					WalletReceiveRequest::Coinbase(cb_fees) => {
 						debug!(LOGGER, "Operation {} with fees {:?}", op, cb_fees);
 						let (out, kern, block_fees) = receive_coinbase(
+							&self.config,
 							&self.config,
 							&self.keychain,
 							&cb_fees,
@@ -130,25 +151,25 @@ impl ApiEndpoint for WalletReceiver {
 							None => vec![],
 						};
 
-						Ok(CbData {
-							output: util::to_hex(out_bin),
-							kernel: util::to_hex(kern_bin),
-							key_id: util::to_hex(key_id_bin),
-						})
+						Ok(CbData::new(
+							util::to_hex(out_bin),
+							util::to_hex(kern_bin),
+							util::to_hex(key_id_bin),
+						))
 					}
 					_ => Err(api::Error::Argument(format!("Incorrect request data: {}", op))),
 				}
 			}
 			"receive_json_tx" => {
 				match input {
-					WalletReceiveRequest::PartialTransaction(partial_tx_str) => {
+					WalletReceiveRequest::PartialTransaction(partial_tx) => {
 						debug!(
 							LOGGER,
-							"Operation {} with transaction {}",
+							"Operation {} with transaction {:?}",
 							op,
-							&partial_tx_str,
+							&partial_tx,
 						);
-						receive_json_tx(&self.config, &self.keychain, &partial_tx_str)
+						receive_partial_tx(&self.config, &self.keychain, &partial_tx)
 							.map_err(|e| {
 								api::Error::Internal(
 									format!("Error processing partial transaction: {:?}", e),
@@ -157,11 +178,7 @@ impl ApiEndpoint for WalletReceiver {
 							.unwrap();
 
 						// TODO: Return emptiness for now, should be a proper enum return type
-						Ok(CbData {
-							output: String::from(""),
-							kernel: String::from(""),
-							key_id: String::from(""),
-						})
+						Ok(CbData::new(String::from(""), String::from(""), String::from("")))
 					}
 					_ => Err(api::Error::Argument(format!("Incorrect request data: {}", op))),
 				}
@@ -171,15 +188,20 @@ impl ApiEndpoint for WalletReceiver {
 	}
 }
 
-/// Build a coinbase output and the corresponding kernel
+/// Build a coinbase output and the corresponding kernel, for a block at a
+/// height close enough to the node chain tip, see BlockFees::check_height.
 fn receive_coinbase(config: &WalletConfig,
+                    node_api: &NodeApi,
                     keychain: &Keychain,
                     block_fees: &BlockFees)
                     -> Result<(Output, TxKernel, BlockFees), Error> {
 	let root_key_id = keychain.root_key_id();
 
+	let tip = node_api.get_tip()?;
+	block_fees.check_height(tip.height, config.coinbase_height_drift)?;
+
 	// operate within a lock on wallet data
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+	WalletData::with_wallet(config, |wallet_data| {
 		let key_id = block_fees.key_id();
 		let (key_id, derivation) = match key_id {
 			Some(key_id) => {
@@ -201,12 +223,18 @@ fn receive_coinbase(config: &WalletConfig,
 			root_key_id: root_key_id.clone(),
 			key_id: key_id.clone(),
 			n_child: derivation,
-			value: reward(block_fees.fees),
+			value: reward(block_fees.coinbase_fees()),
 			status: OutputStatus::Unconfirmed,
 			height: 0,
 			lock_height: 0,
 			zero_ok: false,
-		});
+			tx_hash: None,
+			spent_in_tx: None,
+			locked_until: None,
+			label: None,
+			watch_only: false,
+			is_coinbase: true,
+		})?;
 
 		debug!(
 			LOGGER,
@@ -226,7 +254,7 @@ fn receive_coinbase(config: &WalletConfig,
 		let (out, kern) = Block::reward_output(
 			&keychain,
 			&key_id,
-			block_fees.fees,
+			block_fees.coinbase_fees(),
 		)?;
 		Ok((out, kern, block_fees))
 	})?
@@ -243,35 +271,41 @@ fn receive_transaction(
 	let root_key_id = keychain.root_key_id();
 
 	// operate within a lock on wallet data
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+	WalletData::with_wallet(config, |wallet_data| {
 		let derivation = wallet_data.next_child(root_key_id.clone());
 		let key_id = keychain.derive_key_id(derivation)?;
 
 		// double check the fee amount included in the partial tx
 		// we don't necessarily want to just trust the sender
 		// we could just overwrite the fee here (but we won't) due to the ecdsa sig
-		let fee = tx_fee(partial.inputs.len(), partial.outputs.len() + 1, None);
-		if fee != partial.fee {
+		// a higher fee is fine, the sender folds dust change into it
+		let min_fee = tx_fee(partial.inputs.len(), partial.outputs.len() + 1, None);
+		let fee = partial.fee;
+		if fee < min_fee {
 			return Err(Error::FeeDispute {
-				sender_fee: partial.fee,
-				recipient_fee: fee,
+				sender_fee: fee,
+				recipient_fee: min_fee,
 			});
 		}
 
-		let out_amount = amount - fee;
+		let out_amount = amount.checked_sub(fee).ok_or_else(|| {
+			Error::WalletData(format!("fee of {} is above the amount of {}", fee, amount))
+		})?;
 
-		let (tx_final, _) = build::transaction(vec![
+		let (mut tx_final, _) = build::transaction(vec![
 			build::initial_tx(partial),
 			build::with_excess(blinding),
 			build::output(out_amount, key_id.clone()),
 			// build::with_fee(fee_amount),
 		], keychain)?;
+		config.output_ordering.order_outputs(&mut tx_final.outputs);
 
 		// make sure the resulting transaction is valid (could have been lied to on
 		// excess)
 		tx_final.validate(&keychain.secp())?;
 
 		// track the new output and return the finalized transaction to broadcast
+		let tx_hash = util::to_hex(tx_final.hash().to_vec());
 		wallet_data.add_output(OutputData {
 			root_key_id: root_key_id.clone(),
 			key_id: key_id.clone(),
@@ -281,7 +315,14 @@ fn receive_transaction(
 			height: 0,
 			lock_height: 0,
 			zero_ok: false,
-		});
+			tx_hash: Some(tx_hash.clone()),
+			spent_in_tx: None,
+			locked_until: None,
+			label: None,
+			watch_only: false,
+			is_coinbase: false,
+		})?;
+		wallet_data.log_tx(TxLogEntryType::Received, Some(tx_hash), out_amount, 0, fee);
 		debug!(
 			LOGGER,
 			"Received txn and built output - {:?}, {:?}, {}",
@@ -293,3 +334,102 @@ fn receive_transaction(
 		Ok(tx_final)
 	})?
 }
+
+#[cfg(test)]
+mod test {
+	use core::core::build;
+	use keychain::Keychain;
+	use test_util::{test_config, test_dir, MockNode};
+	use types::*;
+	use super::{receive_coinbase, receive_transaction};
+
+	#[test]
+	fn receive_logs_one_entry() {
+		let sender = Keychain::from_random_seed().unwrap();
+		let recipient = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("receive_log"));
+
+		// partial transaction as a sender would build it
+		let fee = tx_fee(1, 2, None);
+		let (partial, blind_sum) = build::transaction(vec![
+			build::input(1000, sender.derive_key_id(1).unwrap()),
+			build::output(700, sender.derive_key_id(2).unwrap()),
+			build::with_fee(fee),
+		], &sender).unwrap();
+
+		receive_transaction(&config, &recipient, 300, blind_sum, partial).unwrap();
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let log = wallet_data.tx_log();
+			assert_eq!(log.len(), 1);
+			assert_eq!(log[0].tx_type, TxLogEntryType::Received);
+			assert_eq!(log[0].amount_credited, 300 - fee);
+			assert_eq!(log[0].amount_debited, 0);
+			assert_eq!(log[0].fee, fee);
+		}).unwrap();
+	}
+
+	#[test]
+	fn receive_checks_fee() {
+		let sender = Keychain::from_random_seed().unwrap();
+		let recipient = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("receive_fee"));
+		let partial = |fee| {
+			build::transaction(vec![
+				build::input(1000, sender.derive_key_id(1).unwrap()),
+				build::with_fee(fee),
+			], &sender).unwrap()
+		};
+
+		// too low a fee is refused
+		let (tx, blind_sum) = partial(tx_fee(1, 1, None) - 1);
+		match receive_transaction(&config, &recipient, 1000, blind_sum, tx) {
+			Err(Error::FeeDispute { recipient_fee, .. }) => {
+				assert_eq!(recipient_fee, tx_fee(1, 1, None))
+			}
+			res => panic!("expected a fee dispute, got {:?}", res.map(|tx| tx.fee)),
+		}
+
+		// a higher one, taking in the sender's dust change, comes out of the amount
+		let fee = tx_fee(1, 1, None) + 7;
+		let (tx, blind_sum) = partial(fee);
+		receive_transaction(&config, &recipient, 1000, blind_sum, tx).unwrap();
+		WalletData::read_wallet(&config, |wallet_data| {
+			assert_eq!(wallet_data.tx_log()[0].amount_credited, 1000 - fee);
+			assert_eq!(wallet_data.tx_log()[0].fee, fee);
+		}).unwrap();
+	}
+
+	#[test]
+	fn coinbase_height_near_tip() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("coinbase_height"));
+		let node = MockNode::new(100);
+		let drift = config.coinbase_height_drift;
+		let block_fees = |height: u64| {
+			BlockFees {
+				fees: 0,
+				height: height,
+				key_id: None,
+				min_fees: None,
+				max_fees: None,
+			}
+		};
+
+		receive_coinbase(&config, &node, &keychain, &block_fees(101)).unwrap();
+		receive_coinbase(&config, &node, &keychain, &block_fees(101 + drift)).unwrap();
+
+		match receive_coinbase(&config, &node, &keychain, &block_fees(10_000)) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("block height 10000")),
+			res => panic!("expected a wallet data error, got {:?}", res.map(|r| r.2)),
+		}
+		assert!(receive_coinbase(&config, &node, &keychain, &block_fees(101 + drift + 1)).is_err());
+		assert!(receive_coinbase(&config, &node, &keychain, &block_fees(1)).is_err());
+
+		// only the accepted ones are tracked, and none is logged before mined
+		WalletData::read_wallet(&config, |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 2);
+			assert!(wallet_data.tx_log().is_empty());
+		}).unwrap();
+	}
+}
@@ -15,6 +15,7 @@
 use api;
 use checker;
 use core::core::{Transaction, build};
+use core::core::hash::Hashed;
 use core::ser;
 use keychain::{BlindingFactor, Keychain, Identifier};
 use receiver::TxWrapper;
@@ -22,15 +23,22 @@ use types::*;
 use util::LOGGER;
 use util;
 
+/// How many times we select our inputs again when the node reports some of
+/// them as gone, before giving up on the send.
+const MAX_INPUT_RETRIES: usize = 1;
+
 /// Issue a new transaction to the provided sender by spending some of our
 /// wallet
 /// UTXOs. The destination can be "stdout" (for command line) or a URL to the
-/// recipients wallet receiver (to be implemented).
+/// recipients wallet receiver (to be implemented). The recipient receives the
+/// whole amount, the fee comes on top. Our change gets split in
+/// num_change_outputs outputs of equal value.
 
 pub fn issue_send_tx(
 	config: &WalletConfig,
 	keychain: &Keychain,
 	amount: u64,
+	num_change_outputs: usize,
 	dest: String,
 ) -> Result<(), Error> {
 	checker::refresh_outputs(config, keychain)?;
@@ -38,15 +46,16 @@ pub fn issue_send_tx(
 	let chain_tip = checker::get_tip_from_node(config)?;
 	let lock_height = chain_tip.height;
 
-	let (tx, blind_sum) = build_send_tx(config, keychain, amount, lock_height)?;
-	let json_tx = partial_tx_to_json(amount, blind_sum, tx);
+	let (tx, blind_sum, amount) =
+		build_send_tx(config, config, keychain, amount, num_change_outputs, lock_height)?;
+	let partial_tx = PartialTx::new(amount, blind_sum, tx)?;
 
 	if dest == "stdout" {
-		println!("{}", json_tx);
+		println!("{}", partial_tx.to_json()?);
 	} else if &dest[..4] == "http" {
 		let url = format!("{}/v1/receive/receive_json_tx", &dest);
 		debug!(LOGGER, "Posting partial transaction to {}", url);
-		let request = WalletReceiveRequest::PartialTransaction(json_tx);
+		let request = WalletReceiveRequest::PartialTransaction(partial_tx);
 		let _: CbData = api::client::post(url.as_str(), &request).expect(&format!(
 			"Wallet receiver at {} unreachable, could not send transaction. Is it running?",
 			url
@@ -59,33 +68,100 @@ pub fn issue_send_tx(
 
 /// Builds a transaction to send to someone from the HD seed associated with the
 /// wallet and the amount to send. Handles reading through the wallet data file,
-/// selecting outputs to spend and building the change.
+/// selecting outputs to spend and building the change, see
+/// WalletData::estimate_and_select_spendable. The fee comes on top of the
+/// amount, which the recipient receives in full, so the partial transaction
+/// has to carry both and that total is returned along with it. Each selected
+/// output is looked up on the node, those it doesn't have anymore (spent by
+/// another instance of the wallet or gone in a reorg) are marked as spent
+/// and the selection made again.
 fn build_send_tx(
 	config: &WalletConfig,
+	node_api: &NodeApi,
 	keychain: &Keychain,
 	amount: u64,
+	num_change_outputs: usize,
 	lock_height: u64,
-) -> Result<(Transaction, BlindingFactor), Error> {
+) -> Result<(Transaction, BlindingFactor, u64), Error> {
 	let key_id = keychain.clone().root_key_id();
 
 	// operate within a lock on wallet data
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
-
-		// select some suitable outputs to spend from our local wallet
-		let (coins, _) = wallet_data.select(key_id.clone(), u64::max_value());
-
-		// build transaction skeleton with inputs and change
-		// TODO - should probably also check we are sending enough to cover the fees + non-zero output
-		let mut parts = inputs_and_change(&coins, keychain, key_id, wallet_data, amount)?;
+	WalletData::with_wallet(config, |wallet_data| {
+
+		// select some suitable outputs to spend from our local wallet, that
+		// the node still has
+		let mut retries = 0;
+		let mut all_gone = vec![];
+		let (coins, fee, change) = loop {
+			let (coins, fee, change) = wallet_data.estimate_and_select_spendable(
+				key_id.clone(),
+				amount,
+				None,
+				num_change_outputs,
+				config.dust_limit,
+				lock_height,
+				config.coinbase_maturity,
+				config.min_confirmations,
+			)?;
+			let gone = missing_inputs(node_api, keychain, &coins)?;
+			if gone.is_empty() {
+				break (coins, fee, change as u64);
+			}
+			for key in &gone {
+				warn!(LOGGER, "Output {} is gone from the node, marking it as spent", key);
+				if let Some(out) = wallet_data.outputs.get_mut(key) {
+					out.status = OutputStatus::Spent;
+				}
+			}
+			all_gone.extend(gone);
+			if retries == MAX_INPUT_RETRIES {
+				let keys = all_gone.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+				return Err(Error::WalletData(format!(
+					"Outputs {} are gone from the node, giving up after {} selections",
+					keys.join(", "),
+					retries + 1
+				)));
+			}
+			retries += 1;
+		};
+
+		// build transaction skeleton with inputs and change, a change
+		// folded into the fee doesn't get any output
+		let change_values = if change == 0 {
+			vec![]
+		} else {
+			split_change(change, num_change_outputs)?
+		};
+		let locked_until = Some(lock_height.saturating_add(config.output_lock_blocks));
+		let (mut parts, change_keys) = inputs_and_change(
+			&coins,
+			keychain,
+			key_id,
+			wallet_data,
+			fee,
+			change_values,
+			locked_until,
+		)?;
 
 		// This is more proof of concept than anything but here we set a
 		// lock_height on the transaction being sent (based on current chain height via
 		// api).
 		parts.push(build::with_lock_height(lock_height));
 
-		let (tx, blind) = build::transaction(parts, &keychain)?;
-
-		Ok((tx, blind))
+		let (mut tx, blind) = match build::transaction(parts, &keychain) {
+			Ok(built) => built,
+			Err(e) => {
+				undo_spend(wallet_data, &coins, &change_keys);
+				return Err(Error::from(e));
+			}
+		};
+		config.output_ordering.order_outputs(&mut tx.outputs);
+		let tx_hash = util::to_hex(tx.hash().to_vec());
+		let sent = amount + fee;
+		wallet_data.record_tx(&tx_hash, &coins, &change_keys);
+		log_sent_tx(wallet_data, tx_hash, &coins, sent, fee);
+
+		Ok((tx, blind, sent))
 	})?
 }
 
@@ -93,98 +169,215 @@ pub fn issue_burn_tx(config: &WalletConfig, keychain: &Keychain, amount: u64) ->
 	let keychain = &Keychain::burn_enabled(keychain, &Identifier::zero());
 
 	let _ = checker::refresh_outputs(config, keychain);
+	let chain_tip = checker::get_tip_from_node(config)?;
 
 	let key_id = keychain.root_key_id();
 
 	// operate within a lock on wallet data
-	WalletData::with_wallet(&config.data_file_dir, |mut wallet_data| {
-
-		// select all suitable outputs by passing largest amount
-		let (coins, _) = wallet_data.select(key_id.clone(), u64::max_value());
-
-		// build transaction skeleton with inputs and change
-		let mut parts = inputs_and_change(&coins, keychain, key_id, &mut wallet_data, amount)?;
-
-		// add burn output and fees
+	WalletData::with_wallet(config, |mut wallet_data| {
+
+		// select all the outputs we can spend by passing largest amount
+		let (coins, _) = wallet_data.select_spendable(
+			key_id.clone(),
+			u64::max_value(),
+			0,
+			chain_tip.height,
+			config.coinbase_maturity,
+			config.min_confirmations,
+		);
+
+		// build transaction skeleton with inputs and change, the fee comes
+		// out of the burnt amount
+		let total: u64 = coins.iter().map(|c| c.value).sum();
+		if total < amount {
+			return Err(Error::NotEnoughFunds {
+				available: total,
+				needed: amount,
+			});
+		}
 		let fee = tx_fee(coins.len(), 2, None);
-		parts.push(build::output(amount - fee, Identifier::zero()));
+		let (parts, change_keys) = inputs_and_change(
+			&coins,
+			keychain,
+			key_id,
+			&mut wallet_data,
+			fee,
+			vec![total - amount],
+			None,
+		)?;
+
+		// the burn never made it out if anything failed, so release what we
+		// locked for it
+		match push_burn_tx(config, keychain, parts, amount - fee) {
+			Ok(tx_hash) => {
+				wallet_data.record_tx(&tx_hash, &coins, &change_keys);
+				log_sent_tx(wallet_data, tx_hash, &coins, amount, fee);
+				Ok(())
+			}
+			Err(e) => {
+				undo_spend(wallet_data, &coins, &change_keys);
+				Err(e)
+			}
+		}
+	})?
+}
 
-		// finalize the burn transaction and send
-		let (tx_burn, _) = build::transaction(parts, &keychain)?;
-		tx_burn.validate(&keychain.secp())?;
+/// Finalizes the burn transaction from its parts, adding the burn output,
+/// and pushes it to the node pool. Returns the hash of the burn transaction.
+fn push_burn_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	mut parts: Vec<Box<build::Append>>,
+	burn_value: u64,
+) -> Result<String, Error> {
+	parts.push(build::output(burn_value, Identifier::zero()));
+
+	let (mut tx_burn, _) = build::transaction(parts, &keychain)?;
+	config.output_ordering.order_outputs(&mut tx_burn.outputs);
+	tx_burn.validate(&keychain.secp())?;
+
+	let tx_hex = util::to_hex(ser::ser_vec(&tx_burn).unwrap());
+	checker::with_node::<(), _>(config, |node_addr| {
+		let url = format!("{}/v1/pool/push", node_addr);
+		api::client::post_timeout(
+			url.as_str(),
+			&TxWrapper { tx_hex: tx_hex.clone() },
+			config.node_api_timeout(),
+		)
+	})?;
+	Ok(util::to_hex(tx_burn.hash().to_vec()))
+}
 
-		let tx_hex = util::to_hex(ser::ser_vec(&tx_burn).unwrap());
-		let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
-		let _: () = api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex })
-			.map_err(|e| Error::Node(e))?;
-		Ok(())
-	})?
+/// Key ids of the provided coins the node doesn't know about, so can't be
+/// spent anymore. Looks each of them up, one node request per coin.
+fn missing_inputs(
+	node_api: &NodeApi,
+	keychain: &Keychain,
+	coins: &Vec<OutputData>,
+) -> Result<Vec<OutputKey>, Error> {
+	let mut gone = vec![];
+	for coin in coins {
+		if node_api.get_output(&coin.commit(keychain)?)?.is_none() {
+			gone.push(coin.key());
+		}
+	}
+	Ok(gone)
 }
 
+/// Records a transaction we built spending the provided coins in the wallet
+/// history, crediting the change back.
+fn log_sent_tx(
+	wallet_data: &mut WalletData,
+	tx_hash: String,
+	coins: &Vec<OutputData>,
+	amount: u64,
+	fee: u64,
+) {
+	let total: u64 = coins.iter().map(|c| c.value).sum();
+	wallet_data.log_tx(TxLogEntryType::Sent, Some(tx_hash), total - amount, total, fee);
+}
+
+/// Transaction parts spending the provided coins with the provided fee, and
+/// building a change output of each of the change values, along with the
+/// key ids of those change outputs. The coins get locked until locked_until
+/// and the change outputs tracked, on failure the wallet data is left as it
+/// was.
 fn inputs_and_change(
 	coins: &Vec<OutputData>,
 	keychain: &Keychain,
 	root_key_id: Identifier,
 	wallet_data: &mut WalletData,
-	amount: u64,
-) -> Result<Vec<Box<build::Append>>, Error> {
+	fee: u64,
+	change_values: Vec<u64>,
+	locked_until: Option<u64>,
+) -> Result<(Vec<Box<build::Append>>, Vec<Identifier>), Error> {
 
 	let mut parts = vec![];
 
-	// calculate the total across all inputs, and how much is left
-	let total: u64 = coins.iter().map(|c| c.value).sum();
-	let shortage = (total as i64) - (amount as i64);
-	if shortage < 0 {
-		return Err(Error::NotEnoughFunds((-shortage) as u64));
-	}
-
 	// sender is responsible for setting the fee on the partial tx
 	// recipient should double check the fee calculation and not blindly trust the
 	// sender
-	let fee = tx_fee(coins.len(), 2, None);
 	parts.push(build::with_fee(fee));
 
-	// if we are spending 10,000 coins to send 1,000 then our change will be 9,000
-	// the fee will come out of the amount itself
-	// if the fee is 80 then the recipient will only receive 920
-	// but our change will still be 9,000
-	let change = total - amount;
-
 	// build inputs using the appropriate derived key_ids
 	for coin in coins {
 		let key_id = keychain.derive_key_id(coin.n_child)?;
 		parts.push(build::input(coin.value, key_id));
 	}
 
-	// derive an additional pubkey for change and build the change output
+	// now lock the ouputs we're spending so we avoid accidental double spend attempt
+	for coin in coins {
+		if let Err(e) = wallet_data.lock_output(coin, locked_until) {
+			undo_spend(wallet_data, coins, &[]);
+			return Err(e);
+		}
+	}
+
+	let mut change_keys = vec![];
+	for change_value in change_values {
+		match add_change_output(keychain, root_key_id.clone(), wallet_data, change_value) {
+			Ok(change_key) => {
+				parts.push(build::output(change_value, change_key.clone()));
+				change_keys.push(change_key);
+			}
+			Err(e) => {
+				undo_spend(wallet_data, coins, &change_keys);
+				return Err(e);
+			}
+		}
+	}
+
+	Ok((parts, change_keys))
+}
+
+/// Derives an additional key for change and starts tracking the change
+/// output of the provided value under it.
+fn add_change_output(
+	keychain: &Keychain,
+	root_key_id: Identifier,
+	wallet_data: &mut WalletData,
+	value: u64,
+) -> Result<Identifier, Error> {
 	let change_derivation = wallet_data.next_child(root_key_id.clone());
 	let change_key = keychain.derive_key_id(change_derivation)?;
-	parts.push(build::output(change, change_key.clone()));
-
-	// we got that far, time to start tracking the output representing our change
 	wallet_data.add_output(OutputData {
-		root_key_id: root_key_id.clone(),
+		root_key_id: root_key_id,
 		key_id: change_key.clone(),
 		n_child: change_derivation,
-		value: change as u64,
+		value: value,
 		status: OutputStatus::Unconfirmed,
 		height: 0,
 		lock_height: 0,
 		zero_ok: true,
-	});
+		tx_hash: None,
+		spent_in_tx: None,
+		locked_until: None,
+		label: None,
+		watch_only: false,
+		is_coinbase: false,
+	})?;
+	Ok(change_key)
+}
 
-	// now lock the ouputs we're spending so we avoid accidental double spend attempt
+/// Undoes the locking and change tracking of inputs_and_change for a
+/// transaction that never made it out, putting the coins back as they were
+/// selected and dropping the change outputs.
+fn undo_spend(wallet_data: &mut WalletData, coins: &Vec<OutputData>, change_keys: &[Identifier]) {
 	for coin in coins {
-		wallet_data.lock_output(coin);
+		wallet_data.outputs.insert(coin.key(), coin.clone());
+	}
+	for change_key in change_keys {
+		wallet_data.outputs.remove(&OutputKey::new(change_key));
 	}
-
-	Ok(parts)
 }
 
 #[cfg(test)]
 mod test {
 	use core::core::build::{input, output, transaction};
 	use keychain::Keychain;
+	use test_util::{keyed_output, test_config, test_dir, MockNode};
+	use types::*;
+	use super::{build_send_tx, inputs_and_change};
 
 	#[test]
 	// demonstrate that input.commitment == referenced output.commitment
@@ -198,4 +391,203 @@ mod test {
 
 		assert_eq!(tx1.outputs[0].commitment(), tx2.inputs[0].commitment());
 	}
+
+	#[test]
+	fn send_logs_one_entry() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_log"));
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let coin = keyed_output(&keychain, 1, 1000, OutputStatus::Unspent);
+			wallet_data.add_output(coin).unwrap();
+		}).unwrap();
+
+		let mut node = MockNode::new(1);
+		node.add(&keychain, 1000, 1, 0, 0);
+		let (tx, _, sent) = build_send_tx(&config, &node, &keychain, 300, 1, 1).unwrap();
+		assert_eq!(tx.fee, tx_fee(1, 2, None));
+		assert_eq!(sent, 300 + tx.fee);
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let log = wallet_data.tx_log();
+			assert_eq!(log.len(), 1);
+			assert_eq!(log[0].tx_type, TxLogEntryType::Sent);
+			assert_eq!(log[0].amount_debited, 1000);
+			assert_eq!(log[0].amount_credited, 700 - tx.fee);
+			assert_eq!(log[0].fee, tx.fee);
+		}).unwrap();
+	}
+
+	#[test]
+	fn failed_spend_leaves_no_locks() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_undo"));
+		let coin1 = keyed_output(&keychain, 1, 1000, OutputStatus::Unspent);
+		let coin2 = keyed_output(&keychain, 2, 1000, OutputStatus::Unspent);
+
+		// the second coin doesn't match what the wallet has, so locking it
+		// fails after the first got locked
+		let mut stale_coin2 = coin2.clone();
+		stale_coin2.value = 900;
+		WalletData::with_wallet(&config, |wallet_data| {
+			wallet_data.add_output(coin1.clone()).unwrap();
+			wallet_data.add_output(coin2.clone()).unwrap();
+			let coins = vec![coin1.clone(), stale_coin2];
+			let root_key_id = keychain.root_key_id();
+			let res =
+				inputs_and_change(&coins, &keychain, root_key_id, wallet_data, 0, vec![], None);
+			assert!(res.is_err());
+		}).unwrap();
+
+		WalletData::read_wallet(&config, |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 2);
+			assert!(wallet_data.outputs.values().all(|out| out.status == OutputStatus::Unspent));
+			assert!(wallet_data.outputs.values().all(|out| out.locked_until.is_none()));
+		}).unwrap();
+	}
+
+	#[test]
+	fn send_selects_only_needed_inputs() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_needed"));
+		let mut node = MockNode::new(1);
+		WalletData::with_wallet(&config, |wallet_data| {
+			for n_child in 1..4 {
+				let coin = keyed_output(&keychain, n_child, 1000, OutputStatus::Unspent);
+				wallet_data.add_output(coin).unwrap();
+				node.add(&keychain, 1000, n_child, 0, 0);
+			}
+		}).unwrap();
+
+		let (tx, _, _) = build_send_tx(&config, &node, &keychain, 1500, 1, 1).unwrap();
+		assert_eq!(tx.inputs.len(), 2);
+		assert_eq!(tx.fee, tx_fee(2, 2, None));
+		WalletData::read_wallet(&config, |wallet_data| {
+			let unspent = wallet_data
+				.outputs
+				.values()
+				.filter(|out| out.status == OutputStatus::Unspent)
+				.count();
+			assert_eq!(unspent, 1);
+		}).unwrap();
+	}
+
+	#[test]
+	fn send_splits_change() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_split"));
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let coin = keyed_output(&keychain, 1, 1000, OutputStatus::Unspent);
+			wallet_data.add_output(coin).unwrap();
+		}).unwrap();
+
+		let mut node = MockNode::new(1);
+		node.add(&keychain, 1000, 1, 0, 0);
+		let (tx, _, _) = build_send_tx(&config, &node, &keychain, 300, 3, 1).unwrap();
+		assert_eq!(tx.outputs.len(), 3);
+		assert_eq!(tx.fee, tx_fee(1, 4, None));
+		let expected_change = split_change(700 - tx.fee, 3).unwrap();
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let mut change = wallet_data
+				.outputs
+				.values()
+				.filter(|out| out.status == OutputStatus::Unconfirmed)
+				.map(|out| out.value)
+				.collect::<Vec<_>>();
+			change.sort();
+			let mut expected_change = expected_change.clone();
+			expected_change.sort();
+			assert_eq!(change, expected_change);
+		}).unwrap();
+	}
+
+	#[test]
+	fn send_folds_dust_change_into_fee() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_dust"));
+		WalletData::with_wallet(&config, |wallet_data| {
+			let coin = keyed_output(&keychain, 1, 1000, OutputStatus::Unspent);
+			wallet_data.add_output(coin).unwrap();
+		}).unwrap();
+
+		let mut node = MockNode::new(1);
+		node.add(&keychain, 1000, 1, 0, 0);
+		let amount = 1000 - tx_fee(1, 2, None) - config.dust_limit;
+		let (tx, _, sent) = build_send_tx(&config, &node, &keychain, amount, 1, 1).unwrap();
+		assert!(tx.outputs.is_empty());
+		assert_eq!(tx.fee, tx_fee(1, 2, None) + config.dust_limit);
+		assert!(tx.fee > tx_fee(1, 1, None));
+		// the recipient still gets the whole amount
+		assert_eq!(sent, 1000);
+		assert_eq!(sent - tx.fee, amount);
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			let log = wallet_data.tx_log();
+			assert_eq!((log[0].amount_credited, log[0].fee), (0, tx.fee));
+			let unconfirmed = OutputStatus::Unconfirmed;
+			assert!(wallet_data.outputs.values().all(|out| out.status != unconfirmed));
+		}).unwrap();
+	}
+
+	#[test]
+	fn send_reselects_gone_inputs() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_reselect"));
+		let coin = |n_child: u32| keyed_output(&keychain, n_child, 1000, OutputStatus::Unspent);
+		let status = |config: &WalletConfig, n_child: u32| {
+			let key_id = keychain.derive_key_id(n_child).unwrap();
+			WalletData::read_wallet(&config, |wallet_data| {
+				wallet_data.get_output(&key_id).unwrap().status.clone()
+			}).unwrap()
+		};
+
+		WalletData::with_wallet(&config, |wallet_data| {
+			wallet_data.add_output(coin(1)).unwrap();
+			wallet_data.add_output(coin(2)).unwrap();
+		}).unwrap();
+
+		// the first output got spent by another instance of the wallet
+		let mut node = MockNode::new(1);
+		node.add(&keychain, 1000, 2, 0, 0);
+		let (tx, _, _) = build_send_tx(&config, &node, &keychain, 300, 1, 1).unwrap();
+		assert_eq!(tx.inputs.len(), 1);
+		assert_eq!(status(&config, 1), OutputStatus::Spent);
+		assert_eq!(status(&config, 2), OutputStatus::Locked);
+
+		// not on the node anymore, nothing to send from
+		let config = test_config(&test_dir("send_reselect_none"));
+		WalletData::with_wallet(&config, |wallet_data| {
+			wallet_data.add_output(coin(1)).unwrap();
+		}).unwrap();
+		match build_send_tx(&config, &MockNode::new(1), &keychain, 300, 1, 1) {
+			Err(Error::NotEnoughFunds { available: 0, .. }) => {}
+			res => panic!("expected not enough funds, got {:?}", res.map(|r| r.0.fee)),
+		}
+		assert_eq!(status(&config, 1), OutputStatus::Spent);
+	}
+
+	#[test]
+	fn send_gives_up_on_vanishing_inputs() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let config = test_config(&test_dir("send_vanishing"));
+		WalletData::with_wallet(&config, |wallet_data| {
+			for n_child in 1..3 {
+				let coin = keyed_output(&keychain, n_child, 1000, OutputStatus::Unspent);
+				wallet_data.add_output(coin).unwrap();
+			}
+		}).unwrap();
+
+		// each selection picks an output the node doesn't have anymore
+		match build_send_tx(&config, &MockNode::new(1), &keychain, 300, 1, 1) {
+			Err(Error::WalletData(msg)) => {
+				for n_child in 1..3 {
+					let key_id = keychain.derive_key_id(n_child).unwrap();
+					assert!(msg.contains(&key_id.to_hex()));
+				}
+			}
+			res => panic!("expected gone outputs, got {:?}", res.map(|r| r.0.fee)),
+		}
+	}
 }
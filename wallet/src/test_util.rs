@@ -0,0 +1,136 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixtures shared by the tests of the wallet modules.
+
+use std::{env, fs, process};
+use std::collections::HashMap;
+
+use api;
+use core::core::build;
+use keychain::{Identifier, Keychain};
+use secp::pedersen;
+use types::*;
+
+/// Fresh and empty directory for tests needing to write wallet files
+pub fn test_dir(name: &str) -> String {
+	let dir = env::temp_dir().join(format!("grin_wallet_{}_{}", name, process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	dir.to_str().unwrap().to_string()
+}
+
+/// Configuration of a wallet in the provided directory, with the defaults
+/// but no backups nor syncing
+pub fn test_config(dir: &str) -> WalletConfig {
+	WalletConfig {
+		data_file_dir: dir.to_string(),
+		keep_backups: false,
+		data_format: WalletFormat::Json,
+		sync_writes: false,
+		..WalletConfig::default()
+	}
+}
+
+/// Output confirmed at height 0, with a key_id made up from the derivation
+/// index and a zero root key
+pub fn output(n_child: u32, value: u64, status: OutputStatus) -> OutputData {
+	OutputData {
+		root_key_id: Identifier::zero(),
+		key_id: Identifier::from_bytes(&[1, (n_child >> 8) as u8, n_child as u8]),
+		n_child: n_child,
+		value: value,
+		status: status,
+		height: 0,
+		lock_height: 0,
+		zero_ok: false,
+		tx_hash: None,
+		spent_in_tx: None,
+		locked_until: None,
+		label: None,
+		watch_only: false,
+		is_coinbase: false,
+	}
+}
+
+/// Output whose key_id is actually derived from the keychain
+pub fn keyed_output(
+	keychain: &Keychain,
+	n_child: u32,
+	value: u64,
+	status: OutputStatus,
+) -> OutputData {
+	let mut out = output(n_child, value, status);
+	out.root_key_id = keychain.root_key_id();
+	out.key_id = keychain.derive_key_id(n_child).unwrap();
+	out
+}
+
+/// Node at a fixed height serving a set of outputs by commitment
+pub struct MockNode {
+	pub height: u64,
+	outputs: HashMap<pedersen::Commitment, (pedersen::RangeProof, u64, u64)>,
+}
+
+impl MockNode {
+	/// Node at the provided height, without any output
+	pub fn new(height: u64) -> MockNode {
+		MockNode {
+			height: height,
+			outputs: HashMap::new(),
+		}
+	}
+
+	/// Adds the output with the provided value, derivation and heights
+	pub fn add(&mut self, keychain: &Keychain, value: u64, n_child: u32, height: u64, lock: u64) {
+		let key_id = keychain.derive_key_id(n_child).unwrap();
+		let (tx, _) = build::transaction(vec![build::output(value, key_id)], keychain).unwrap();
+		let out = tx.outputs[0];
+		self.outputs.insert(out.commit, (out.proof, height, lock));
+	}
+}
+
+impl NodeApi for MockNode {
+	fn get_tip(&self) -> Result<api::Tip, Error> {
+		Ok(api::Tip { height: self.height })
+	}
+
+	fn get_output(&self, commit: &pedersen::Commitment) -> Result<Option<api::Output>, Error> {
+		let found = self.outputs.get(commit).cloned();
+		Ok(found.map(|(proof, height, lock_height)| {
+			api::Output {
+				output_type: api::OutputType::Transaction,
+				commit: *commit,
+				proof: proof,
+				height: height,
+				lock_height: lock_height,
+			}
+		}))
+	}
+
+	fn get_block_outputs(&self, height: u64) -> Result<Vec<api::Output>, Error> {
+		let found = self.outputs.iter().filter(|&(_, &(_, out_height, _))| out_height == height);
+		Ok(found
+			.map(|(commit, &(proof, height, lock_height))| {
+				api::Output {
+					output_type: api::OutputType::Transaction,
+					commit: *commit,
+					proof: proof,
+					height: height,
+					lock_height: lock_height,
+				}
+			})
+			.collect())
+	}
+}
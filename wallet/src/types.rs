@@ -12,20 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fmt, num, thread, time};
+use std::{cmp, env, error, fmt, num, process, thread, time};
+use std::borrow::Borrow;
 use std::convert::From;
 use std::fs::{self, File, OpenOptions};
-use std::io::Write;
-use std::path::Path;
-use std::path::MAIN_SEPARATOR;
-use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use blake2::blake2b::Blake2b;
+use byteorder::{BigEndian, ByteOrder};
+use rand::{self, Rng};
+use serde::{self, de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 use secp;
+use secp::pedersen;
 
 use api;
+use core::consensus;
 use core::core::{Transaction, transaction};
-use core::ser;
+use core::ser::{self, Readable, Writeable};
 use keychain;
 use util;
 use util::LOGGER;
@@ -33,35 +42,304 @@ use util::LOGGER;
 const DAT_FILE: &'static str = "wallet.dat";
 const LOCK_FILE: &'static str = "wallet.lock";
 
+/// File name of the wallet data in the streaming format, see
+/// WalletData::write_streaming
+pub const STREAM_DAT_FILE: &'static str = "wallet.jsonl";
+
+/// Version of the wallet data layout, bump it along with a new migration
+/// step in WalletData::migrate whenever the layout changes
+const WALLET_DATA_VERSION: u32 = 3;
+
+/// Environment variable naming the wallet data directory, overriding the
+/// configured one, see resolve_data_file_dir
+pub const DATA_DIR_ENV_VAR: &'static str = "GRIN_WALLET_DATA_DIR";
+
+/// Leading bytes identifying wallet data in the binary format
+const WALLET_BIN_MAGIC: [u8; 4] = *b"GRWD";
+
+/// Version of the binary wallet data format, written right after the magic
+/// bytes. Version 2 added the child indexes issued by root key, version 3
+/// the height the outputs were last confirmed at, right after them, and
+/// version 4 whether the transaction history is complete.
+const WALLET_BIN_VERSION: u8 = 4;
+
+/// Distinguishes the temporary files written by a single process
+static TMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 const DEFAULT_BASE_FEE: u64 = 10;
 
+/// Blocks after which an output locked by a send is released, about a day
+const DEFAULT_OUTPUT_LOCK_BLOCKS: u64 = 1440;
+
+/// Seconds we wait on a node API call before giving up
+const DEFAULT_NODE_API_TIMEOUT_SECS: u64 = 30;
+
+/// Confirmations an output needs before we spend it, so at least one block
+/// on top of the one that included it
+const DEFAULT_MIN_CONFIRMATIONS: u64 = 1;
+
+/// Change up to the fee its own output adds to a transaction isn't worth
+/// creating an output for
+const DEFAULT_DUST_LIMIT: u64 = 4 * DEFAULT_BASE_FEE;
+
+/// Blocks the height of a coinbase request can be off from the next block
+/// height of the node
+const DEFAULT_COINBASE_HEIGHT_DRIFT: u64 = 10;
+
+/// Number of nanogrins in a grin
+const GRIN_BASE: u64 = 1_000_000_000;
+
+/// Parameters of the transaction fee calculation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeePolicy {
+	/// Fee per unit of transaction weight
+	pub base_fee: u64,
+	/// Minimum fee, whatever the transaction weight
+	pub min_fee: u64,
+	/// Additional fee for each kernel in the transaction
+	pub per_kernel_fee: u64,
+	/// Highest fee we agree to pay, if any, see tx_fee_checked
+	#[serde(default)]
+	pub max_fee: Option<u64>,
+	/// Largest difference between the fee a sender picked and the one we
+	/// computed that we still accept, settling on the higher of the two, see
+	/// reconcile_fee. Zero requires both fees to match exactly.
+	#[serde(default)]
+	pub fee_tolerance: u64,
+}
+
+impl Default for FeePolicy {
+	fn default() -> FeePolicy {
+		FeePolicy {
+			base_fee: DEFAULT_BASE_FEE,
+			min_fee: 0,
+			per_kernel_fee: 0,
+			max_fee: None,
+			fee_tolerance: 0,
+		}
+	}
+}
+
+/// Parses an amount in grins, like "12.5", into nanogrins. Takes at most 9
+/// decimals, surrounding whitespace is ignored.
+pub fn amount_from_str(amount: &str) -> Result<u64, Error> {
+	let amount = amount.trim();
+	let invalid = |reason: &str| Error::Format(format!("invalid amount {:?}: {}", amount, reason));
+
+	let mut parts = amount.splitn(2, '.');
+	let grins = parts.next().unwrap_or("");
+	let decimals = parts.next().unwrap_or("");
+	if grins.is_empty() && decimals.is_empty() {
+		return Err(invalid("no digits"));
+	}
+	if !grins.chars().chain(decimals.chars()).all(|c| c.is_digit(10)) {
+		return Err(invalid("only digits and a decimal point are allowed"));
+	}
+	if decimals.len() > 9 {
+		return Err(invalid("more than 9 decimals"));
+	}
+
+	let grins = if grins.is_empty() {
+		0
+	} else {
+		grins.parse::<u64>().map_err(|_| invalid("too large"))?
+	};
+	let nanogrins = format!("{:0<9}", decimals).parse::<u64>().unwrap_or(0);
+	grins
+		.checked_mul(GRIN_BASE)
+		.and_then(|n| n.checked_add(nanogrins))
+		.ok_or_else(|| invalid("too large"))
+}
+
+/// Formats an amount in nanogrins as grins, with all 9 decimals.
+pub fn amount_to_str(amount: u64) -> String {
+	format!("{}.{:09}", amount / GRIN_BASE, amount % GRIN_BASE)
+}
+
 /// Transaction fee calculation
 pub fn tx_fee(input_len: usize, output_len: usize, base_fee: Option<u64>) -> u64 {
-	let use_base_fee = match base_fee {
-		Some(bf) => bf,
-		None => DEFAULT_BASE_FEE,
-	};
-	let mut tx_weight = -1 * (input_len as i32) + 4 * (output_len as i32) + 1;
-	if tx_weight < 1 {
-		tx_weight = 1;
+	let mut policy = FeePolicy::default();
+	if let Some(bf) = base_fee {
+		policy.base_fee = bf;
+	}
+	tx_fee_with_policy(input_len, output_len, 1, &policy)
+}
+
+/// Transaction fee calculation under the provided fee policy, the weight
+/// based fee plus the kernel fee, but never less than the policy minimum.
+/// The policy maximum isn't enforced here, see tx_fee_checked.
+pub fn tx_fee_with_policy(
+	input_len: usize,
+	output_len: usize,
+	kernel_len: usize,
+	policy: &FeePolicy,
+) -> u64 {
+	let fee = (tx_weight(input_len, output_len) as u64)
+		.saturating_mul(policy.base_fee)
+		.saturating_add((kernel_len as u64).saturating_mul(policy.per_kernel_fee));
+	cmp::max(policy.min_fee, fee)
+}
+
+/// Weight of a transaction with the provided number of inputs and outputs,
+/// as used in the fee calculation. Never below 1.
+fn tx_weight(input_len: usize, output_len: usize) -> i64 {
+	// use i64 and saturating arithmetic so huge input counts can't wrap around
+	// into a bogus positive weight
+	let tx_weight = (4 * (output_len as i64))
+		.saturating_sub(input_len as i64)
+		.saturating_add(1);
+	cmp::max(tx_weight, 1)
+}
+
+/// Weight of an actual transaction, from its inputs and outputs, as used in
+/// the fee calculation.
+pub fn transaction_weight(tx: &Transaction) -> i64 {
+	tx_weight(tx.inputs.len(), tx.outputs.len())
+}
+
+/// Checks the fee claimed for a transaction is the one the policy computes
+/// for its real weight, with its single kernel. Fails with a FeeDispute
+/// holding the claimed and computed fees otherwise.
+pub fn verify_fee(tx: &Transaction, claimed_fee: u64, policy: &FeePolicy) -> Result<(), Error> {
+	let fee = tx_fee_with_policy(tx.inputs.len(), tx.outputs.len(), 1, policy);
+	if fee != claimed_fee {
+		return Err(Error::FeeDispute {
+			sender_fee: claimed_fee,
+			recipient_fee: fee,
+		});
+	}
+	Ok(())
+}
+
+/// Splits the change of a transaction into the provided number of outputs of
+/// equal value, the first one getting the remainder. Fails without any
+/// output to put the change in, or when there isn't enough change to give
+/// each of several outputs a non zero value.
+pub fn split_change(change: u64, num_change_outputs: usize) -> Result<Vec<u64>, Error> {
+	if num_change_outputs == 0 {
+		return Err(Error::WalletData("at least one change output is needed".to_owned()));
+	}
+	let count = num_change_outputs as u64;
+	if num_change_outputs > 1 && change < count {
+		return Err(Error::WalletData(format!(
+			"change of {} is too small to split into {} outputs",
+			change,
+			num_change_outputs
+		)));
+	}
+	let mut values = vec![change / count; num_change_outputs];
+	values[0] += change % count;
+	Ok(values)
+}
+
+/// Settles on a fee when the sender and recipient each computed one. Fees
+/// that match are accepted as is, fees within the policy tolerance of each
+/// other resolve to the higher one, anything further apart is a FeeDispute.
+pub fn reconcile_fee(
+	sender_fee: u64,
+	recipient_fee: u64,
+	policy: &FeePolicy,
+) -> Result<u64, Error> {
+	let diff = cmp::max(sender_fee, recipient_fee) - cmp::min(sender_fee, recipient_fee);
+	if diff > policy.fee_tolerance {
+		return Err(Error::FeeDispute {
+			sender_fee: sender_fee,
+			recipient_fee: recipient_fee,
+		});
 	}
+	Ok(cmp::max(sender_fee, recipient_fee))
+}
 
-	(tx_weight as u64) * use_base_fee
+/// Same as tx_fee_with_policy but fails with FeeTooHigh if the fee is above
+/// the policy maximum, guarding against accidental overpayment.
+pub fn tx_fee_checked(
+	input_len: usize,
+	output_len: usize,
+	kernel_len: usize,
+	policy: &FeePolicy,
+) -> Result<u64, Error> {
+	let fee = tx_fee_with_policy(input_len, output_len, kernel_len, policy);
+	match policy.max_fee {
+		Some(max_fee) if fee > max_fee => Err(Error::FeeTooHigh {
+			fee: fee,
+			max_fee: max_fee,
+		}),
+		_ => Ok(fee),
+	}
 }
 
 /// Wallet errors, mostly wrappers around underlying crypto or I/O errors.
 #[derive(Debug)]
 pub enum Error {
-	NotEnoughFunds(u64),
+	/// Our spendable outputs can't cover the amount needed, fee included
+	NotEnoughFunds{available: u64, needed: u64},
 	FeeDispute{sender_fee: u64, recipient_fee: u64},
+	/// The fee of a transaction is above the maximum we agreed to pay
+	FeeTooHigh{fee: u64, max_fee: u64},
 	Keychain(keychain::Error),
 	Transaction(transaction::Error),
 	Secp(secp::Error),
 	WalletData(String),
+	/// The wallet data file exists but can't be parsed, it needs restoring
+	/// from a backup
+	WalletCorrupt(String),
 	/// An error in the format of the JSON structures exchanged by the wallet
 	Format(String),
 	/// Error when contacting a node through its API
 	Node(api::Error),
+	/// None of the nodes could be reached or answered in time, worth retrying
+	/// later
+	NodeUnreachable(String),
+	/// A node answered but refused the request, retrying it as is won't help
+	NodeRejected(String),
+	/// Error reading or writing the wallet files
+	Io(io::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::NotEnoughFunds { available, needed } => write!(
+				f,
+				"not enough funds: have {}, need {}, short by {}",
+				available,
+				needed,
+				needed.saturating_sub(available)
+			),
+			Error::FeeDispute { sender_fee, recipient_fee } => write!(
+				f,
+				"fee dispute: sender fee is {} but we expected {}",
+				sender_fee,
+				recipient_fee
+			),
+			Error::FeeTooHigh { fee, max_fee } => {
+				write!(f, "fee too high: {} is above the maximum of {}", fee, max_fee)
+			}
+			Error::Keychain(ref e) => write!(f, "keychain error: {}", e),
+			Error::Transaction(ref e) => write!(f, "transaction error: {}", e),
+			Error::Secp(ref e) => write!(f, "secp error: {}", e),
+			Error::WalletData(ref s) => write!(f, "wallet data error: {}", s),
+			Error::WalletCorrupt(ref s) => write!(f, "wallet data corrupt: {}", s),
+			Error::Format(ref s) => write!(f, "format error: {}", s),
+			Error::Node(ref e) => write!(f, "node error: {}", e),
+			Error::NodeUnreachable(ref s) => write!(f, "node unreachable: {}", s),
+			Error::NodeRejected(ref s) => write!(f, "node rejected the request: {}", s),
+			Error::Io(ref e) => write!(f, "I/O error: {}", e),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(error::Error + 'static)> {
+		match *self {
+			Error::Keychain(ref e) => Some(e),
+			Error::Transaction(ref e) => Some(e),
+			Error::Secp(ref e) => Some(e),
+			Error::Node(ref e) => Some(e),
+			Error::Io(ref e) => Some(e),
+			_ => None,
+		}
+	}
 }
 
 impl From<keychain::Error> for Error {
@@ -96,21 +374,256 @@ impl From<num::ParseIntError> for Error {
 
 impl From<api::Error> for Error {
 	fn from(e: api::Error) -> Error {
-		Error::Node(e)
+		match e {
+			api::Error::Unreachable(s) => Error::NodeUnreachable(s),
+			api::Error::Argument(_) => Error::NodeRejected(e.to_string()),
+			_ => Error::Node(e),
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::Io(e)
 	}
 }
 
+/// Error for wallet data we can't parse, see Error::WalletCorrupt. The file
+/// is left as is for the user to inspect or replace.
+fn corrupt_error<E: fmt::Display>(location: &str, e: E) -> Error {
+	Error::WalletCorrupt(format!(
+		"could not parse {}: {}. Restore it from a backup, it won't be overwritten",
+		location,
+		e
+	))
+}
+
+/// Adds the path of the file we were working on to the message of an io
+/// error, keeping its kind.
+fn path_io_error(path: &str, e: io::Error) -> Error {
+	Error::Io(io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalletConfig {
 	// Whether to run a wallet
 	pub enable_wallet: bool,
-	// The api address that this api server (i.e. this wallet) will run
+	// The api address that this api server (i.e. this wallet) will run, either
+	// a TCP socket address or unix:/path/to/socket for a Unix domain socket
 	pub api_http_addr: String,
-	// The api address of a running server node, against which transaction inputs will be checked
-	// during send
-	pub check_node_api_http_addr: String,
+	// The api addresses of running server nodes, against which transaction inputs will be
+	// checked during send, tried in order. Either a single address or a list of them.
+	#[serde(deserialize_with = "deserialize_endpoints")]
+	pub check_node_api_http_addr: Vec<String>,
 	// The directory in which wallet files are stored
 	pub data_file_dir: String,
+	// Settings of the wallet lock file
+	#[serde(default)]
+	pub lock: LockConfig,
+	// Number of blocks after which the outputs locked by a send that never
+	// confirmed are released
+	#[serde(default = "default_output_lock_blocks")]
+	pub output_lock_blocks: u64,
+	// How long we wait on a node to respond to an API call, in seconds, 0 to wait forever
+	#[serde(default = "default_node_api_timeout_secs")]
+	pub node_api_timeout_secs: u64,
+	// Whether to keep copies of the last couple of versions of the wallet
+	// data, as wallet.dat.bak and wallet.dat.bak.1, rotated on each write
+	#[serde(default)]
+	pub keep_backups: bool,
+	// Format wallet.dat gets written in, reading works with any of them
+	#[serde(default)]
+	pub data_format: WalletFormat,
+	// Number of confirmations before a coinbase output can be spent, has to
+	// match the rules of the chain the node runs
+	#[serde(default = "default_coinbase_maturity")]
+	pub coinbase_maturity: u64,
+	// Number of confirmations before any output can be spent, raise it to
+	// wait out reorgs before spending what was just received
+	#[serde(default = "default_min_confirmations")]
+	pub min_confirmations: u64,
+	// Change at or below this amount goes to the fee instead of a change
+	// output that would cost about as much to spend later
+	#[serde(default = "default_dust_limit")]
+	pub dust_limit: u64,
+	// How far the height of a block we build a coinbase for can be from the
+	// next block height of the node, before we refuse the request
+	#[serde(default = "default_coinbase_height_drift")]
+	pub coinbase_height_drift: u64,
+	// Whether to wait for the wallet data to be on disk after each write, so
+	// an update isn't lost to a power failure right after it returned. Costs
+	// a few milliseconds per write, more on slow disks.
+	#[serde(default = "default_sync_writes")]
+	pub sync_writes: bool,
+	// Order of the outputs of the transactions we build, so the change can't
+	// be told apart by its position
+	#[serde(default)]
+	pub output_ordering: OutputOrdering,
+}
+
+fn default_output_lock_blocks() -> u64 {
+	DEFAULT_OUTPUT_LOCK_BLOCKS
+}
+
+fn default_node_api_timeout_secs() -> u64 {
+	DEFAULT_NODE_API_TIMEOUT_SECS
+}
+
+fn default_coinbase_maturity() -> u64 {
+	consensus::COINBASE_MATURITY
+}
+
+fn default_min_confirmations() -> u64 {
+	DEFAULT_MIN_CONFIRMATIONS
+}
+
+fn default_dust_limit() -> u64 {
+	DEFAULT_DUST_LIMIT
+}
+
+fn default_coinbase_height_drift() -> u64 {
+	DEFAULT_COINBASE_HEIGHT_DRIFT
+}
+
+fn default_sync_writes() -> bool {
+	true
+}
+
+/// Prefix of api_http_addr for the wallet listener to bind a Unix domain
+/// socket instead of a TCP one.
+const UNIX_ADDR_PREFIX: &'static str = "unix:";
+
+/// Socket the wallet listener binds, see WalletConfig::listen_addr.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+	/// TCP socket address, the default
+	Tcp(SocketAddr),
+	/// Path of a Unix domain socket
+	Unix(PathBuf),
+}
+
+impl WalletConfig {
+	/// Socket the wallet listener binds, parsed from api_http_addr. Addresses
+	/// starting with unix: are the path of a Unix domain socket, anything
+	/// else has to be a TCP socket address.
+	pub fn listen_addr(&self) -> Result<ListenAddr, Error> {
+		if self.api_http_addr.starts_with(UNIX_ADDR_PREFIX) {
+			let path = &self.api_http_addr[UNIX_ADDR_PREFIX.len()..];
+			if path.is_empty() {
+				return Err(Error::WalletData(
+					"api_http_addr has no unix socket path".to_string(),
+				));
+			}
+			return Ok(ListenAddr::Unix(PathBuf::from(path)));
+		}
+		self.api_http_addr
+			.parse::<SocketAddr>()
+			.map(ListenAddr::Tcp)
+			.map_err(|_| {
+				Error::WalletData(format!(
+					"api_http_addr is not a valid socket address: {:?}",
+					self.api_http_addr
+				))
+			})
+	}
+
+	/// The node API addresses to try, in order, until one of them responds.
+	pub fn node_endpoints(&self) -> Vec<String> {
+		self.check_node_api_http_addr.clone()
+	}
+
+	/// Timeout to apply to the node API calls, if any.
+	pub fn node_api_timeout(&self) -> Option<time::Duration> {
+		match self.node_api_timeout_secs {
+			0 => None,
+			secs => Some(time::Duration::from_secs(secs)),
+		}
+	}
+
+	/// Checks the configured addresses are well formed and that the data
+	/// directory can be created and written to, so a bad configuration is
+	/// reported upfront rather than when first used. The data directory is
+	/// the one in use, GRIN_WALLET_DATA_DIR if set, see
+	/// resolve_data_file_dir.
+	pub fn validate(&self) -> Result<(), Error> {
+		self.listen_addr()?;
+		if self.check_node_api_http_addr.is_empty() {
+			return Err(Error::WalletData(
+				"check_node_api_http_addr has no node address".to_string(),
+			));
+		}
+		for addr in &self.check_node_api_http_addr {
+			validate_http_url(addr).map_err(|why| {
+				Error::WalletData(format!("check_node_api_http_addr {:?} {}", addr, why))
+			})?;
+		}
+		check_writable_dir(&resolve_data_file_dir(&self.data_file_dir))
+	}
+}
+
+/// Reads the node addresses either as a single string, the original format,
+/// or as a list of strings.
+fn deserialize_endpoints<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+	D: de::Deserializer<'de>,
+{
+	deserializer.deserialize_any(EndpointsVisitor)
+}
+
+struct EndpointsVisitor;
+
+impl<'de> de::Visitor<'de> for EndpointsVisitor {
+	type Value = Vec<String>;
+
+	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str("a node address or a list of node addresses")
+	}
+
+	fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+	where
+		E: de::Error,
+	{
+		Ok(vec![s.to_string()])
+	}
+
+	fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+	where
+		A: de::SeqAccess<'de>,
+	{
+		let mut endpoints = vec![];
+		while let Some(endpoint) = seq.next_element::<String>()? {
+			endpoints.push(endpoint);
+		}
+		Ok(endpoints)
+	}
+}
+
+/// Minimal sanity check of an http(s) URL, that it has a scheme, a host and
+/// a valid port if any.
+fn validate_http_url(url: &str) -> Result<(), String> {
+	let rest = if url.starts_with("http://") {
+		&url["http://".len()..]
+	} else if url.starts_with("https://") {
+		&url["https://".len()..]
+	} else {
+		return Err("is missing its http:// or https:// scheme".to_string());
+	};
+	let authority = rest.split('/').next().unwrap_or("");
+	let mut parts = authority.rsplitn(2, ':');
+	let port = parts.next().unwrap_or("");
+	let host = match parts.next() {
+		Some(host) => {
+			if port.parse::<u16>().is_err() {
+				return Err(format!("has an invalid port {:?}", port));
+			}
+			host
+		}
+		None => port,
+	};
+	if host.is_empty() {
+		return Err("is missing a host".to_string());
+	}
+	Ok(())
 }
 
 impl Default for WalletConfig {
@@ -118,8 +631,103 @@ impl Default for WalletConfig {
 		WalletConfig {
 			enable_wallet: false,
 			api_http_addr: "127.0.0.1:13416".to_string(),
-			check_node_api_http_addr: "http://127.0.0.1:13413".to_string(),
+			check_node_api_http_addr: vec!["http://127.0.0.1:13413".to_string()],
 			data_file_dir: ".".to_string(),
+			lock: LockConfig::default(),
+			output_lock_blocks: DEFAULT_OUTPUT_LOCK_BLOCKS,
+			node_api_timeout_secs: DEFAULT_NODE_API_TIMEOUT_SECS,
+			keep_backups: false,
+			data_format: WalletFormat::default(),
+			coinbase_maturity: consensus::COINBASE_MATURITY,
+			min_confirmations: DEFAULT_MIN_CONFIRMATIONS,
+			dust_limit: DEFAULT_DUST_LIMIT,
+			coinbase_height_drift: DEFAULT_COINBASE_HEIGHT_DRIFT,
+			sync_writes: true,
+			output_ordering: OutputOrdering::default(),
+		}
+	}
+}
+
+/// Order of the outputs of a transaction we build, see
+/// OutputOrdering::order_outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputOrdering {
+	/// Sorted by commitment, the same outputs always come in the same order
+	Sorted,
+	/// In random order
+	Shuffled,
+}
+
+impl Default for OutputOrdering {
+	fn default() -> OutputOrdering {
+		OutputOrdering::Sorted
+	}
+}
+
+impl OutputOrdering {
+	/// Reorders the outputs of a transaction we just built, before anything
+	/// else sees it. Their order doesn't matter to the transaction itself,
+	/// its kernel only commits to their sum. Note that serializing a
+	/// transaction sorts its outputs by hash regardless, as consensus
+	/// requires.
+	pub fn order_outputs(&self, outputs: &mut Vec<transaction::Output>) {
+		self.order(outputs, |out| out.commitment())
+	}
+
+	fn order<T, F>(&self, items: &mut [T], commitment: F)
+	where
+		F: Fn(&T) -> pedersen::Commitment,
+	{
+		match *self {
+			OutputOrdering::Sorted => {
+				items.sort_by(|a, b| commitment(a).0[..].cmp(&commitment(b).0[..]))
+			}
+			OutputOrdering::Shuffled => rand::thread_rng().shuffle(items),
+		}
+	}
+}
+
+/// Format the wallet data gets written in. Reading detects the format of the
+/// file on disk, so this can be changed at any time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WalletFormat {
+	/// Indented JSON, easy to read and edit by hand
+	Json,
+	/// JSON without any whitespace
+	JsonCompact,
+	/// Compact binary encoding, for large wallets
+	Binary,
+}
+
+impl Default for WalletFormat {
+	fn default() -> WalletFormat {
+		WalletFormat::Json
+	}
+}
+
+/// Settings of the lock file guarding the wallet data against concurrent
+/// access.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockConfig {
+	/// Age in seconds after which a lock file is considered left behind by a
+	/// crashed process and can be reclaimed
+	pub stale_after_secs: u64,
+	/// How many times we retry taking the lock before giving up
+	pub max_retries: usize,
+	/// Wait before the first retry, in milliseconds
+	pub backoff_ms: u64,
+	/// Factor applied to the wait after each retry, 1 for a fixed wait and 2
+	/// to double it every time
+	pub backoff_multiplier: u64,
+}
+
+impl Default for LockConfig {
+	fn default() -> LockConfig {
+		LockConfig {
+			stale_after_secs: 600,
+			max_retries: 3,
+			backoff_ms: 500,
+			backoff_multiplier: 1,
 		}
 	}
 }
@@ -127,7 +735,8 @@ impl Default for WalletConfig {
 /// Status of an output that's being tracked by the wallet. Can either be
 /// unconfirmed, spent, unspent, or locked (when it's been used to generate
 /// a transaction but we don't have confirmation that the transaction was
-/// broadcasted or mined).
+/// broadcasted or mined). Abandoned outputs were created by a transaction we
+/// gave up on and will never exist.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum OutputStatus {
 	Unconfirmed,
@@ -135,6 +744,7 @@ pub enum OutputStatus {
 	Immature,
 	Locked,
 	Spent,
+	Abandoned,
 }
 
 impl fmt::Display for OutputStatus {
@@ -145,10 +755,74 @@ impl fmt::Display for OutputStatus {
 			OutputStatus::Immature => write!(f, "Immature"),
 			OutputStatus::Locked => write!(f, "Locked"),
 			OutputStatus::Spent => write!(f, "Spent"),
+			OutputStatus::Abandoned => write!(f, "Abandoned"),
+		}
+	}
+}
+
+impl OutputStatus {
+	/// How far along its lifecycle an output in this status is, used to keep
+	/// the most advanced status when reconciling two copies of an output.
+	fn rank(&self) -> u8 {
+		match *self {
+			OutputStatus::Unconfirmed => 0,
+			OutputStatus::Immature => 1,
+			OutputStatus::Unspent => 2,
+			OutputStatus::Locked => 3,
+			OutputStatus::Abandoned => 4,
+			OutputStatus::Spent => 5,
 		}
 	}
 }
 
+/// Key of an output in the wallet data, the hex of its key_id. Only ever built
+/// from a key_id, so all lookups format it the same way as it was inserted.
+/// Stored as the plain hex string on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutputKey(String);
+
+impl OutputKey {
+	/// Key of the output with the provided key_id
+	pub fn new(key_id: &keychain::Identifier) -> OutputKey {
+		OutputKey(key_id.to_hex())
+	}
+
+	/// The hex of the key_id
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl<'a> From<&'a keychain::Identifier> for OutputKey {
+	fn from(key_id: &'a keychain::Identifier) -> OutputKey {
+		OutputKey::new(key_id)
+	}
+}
+
+impl fmt::Display for OutputKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl Serialize for OutputKey {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		serializer.serialize_str(&self.0)
+	}
+}
+
+impl<'de> Deserialize<'de> for OutputKey {
+	fn deserialize<D>(deserializer: D) -> Result<OutputKey, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		String::deserialize(deserializer).map(OutputKey)
+	}
+}
+
 /// Information about an output that's being tracked by the wallet. Must be
 /// enough to reconstruct the commitment associated with the ouput when the
 /// root private key is known.
@@ -170,13 +844,325 @@ pub struct OutputData {
 	pub lock_height: u64,
 	/// Can we spend with zero confirmations? (Did it originate from us, change output etc.)
 	pub zero_ok: bool,
+	/// Hash of the transaction we built that created this output, if known
+	#[serde(default)]
+	pub tx_hash: Option<String>,
+	/// Hash of the transaction we built that spends this output, if any
+	#[serde(default)]
+	pub spent_in_tx: Option<String>,
+	/// Height after which a lock on this output expires, for locks taken
+	/// while sending
+	#[serde(default)]
+	pub locked_until: Option<u64>,
+	/// Free form note attached to the output for bookkeeping, like where the
+	/// funds came from
+	#[serde(default)]
+	pub label: Option<String>,
+	/// Output we only monitor, without holding the keys to spend it. Never
+	/// selected as a transaction input.
+	#[serde(default)]
+	pub watch_only: bool,
+	/// Whether this is a coinbase output, only spendable once mature
+	#[serde(default)]
+	pub is_coinbase: bool,
 }
 
 impl OutputData {
-	/// Lock a given output to avoid conflicting use
-	fn lock(&mut self) {
+	/// Key of this output in the wallet data
+	pub fn key(&self) -> OutputKey {
+		OutputKey::new(&self.key_id)
+	}
+
+	/// Whether this is a coinbase output we built but haven't seen confirmed
+	/// yet, no transaction of ours created it and it can't be spent with
+	/// zero confirmations.
+	fn is_coinbase_placeholder(&self) -> bool {
+		self.status == OutputStatus::Unconfirmed && self.tx_hash.is_none() && !self.zero_ok
+	}
+
+	/// Number of blocks on top of the one that confirmed this output at the
+	/// provided chain height, so 0 for an output confirmed at the tip. Also 0
+	/// when unconfirmed or recorded at a height past the provided one.
+	pub fn confirmations(&self, current_height: u64) -> u64 {
+		if self.status == OutputStatus::Unconfirmed {
+			return 0;
+		}
+		current_height.saturating_sub(self.height)
+	}
+
+	/// Whether we can spend this output in a transaction at the provided chain
+	/// height. It has to be unspent, past its lock height and have at least
+	/// min_confirmations confirmations, and a coinbase output also needs
+	/// coinbase_maturity confirmations.
+	pub fn is_spendable(
+		&self,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> bool {
+		if self.status != OutputStatus::Unspent || self.watch_only {
+			return false;
+		}
+		if self.lock_height > current_height {
+			return false;
+		}
+		let confirmations = self.confirmations(current_height);
+		if confirmations < min_confirmations {
+			return false;
+		}
+		!self.is_coinbase || confirmations >= coinbase_maturity
+	}
+
+	/// Lock a given output to avoid conflicting use, until the provided
+	/// height if any
+	fn lock(&mut self, locked_until: Option<u64>) {
 		self.status = OutputStatus::Locked;
+		self.locked_until = locked_until;
+	}
+
+	/// Releases the lock on the output, making it spendable again
+	fn unlock(&mut self) {
+		self.status = OutputStatus::Unspent;
+		self.locked_until = None;
+	}
+
+	/// Rebuilds the commitment of this output from its value and derivation
+	/// index.
+	pub fn commit(&self, keychain: &keychain::Keychain) -> Result<pedersen::Commitment, Error> {
+		let key_id = keychain.derive_key_id(self.n_child)?;
+		let commit = keychain.commit(self.value, &key_id)?;
+		Ok(commit)
+	}
+}
+
+/// Kind of transaction recorded in the wallet history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxLogEntryType {
+	Sent,
+	Received,
+	Coinbase,
+}
+
+impl fmt::Display for TxLogEntryType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TxLogEntryType::Sent => write!(f, "Sent"),
+			TxLogEntryType::Received => write!(f, "Received"),
+			TxLogEntryType::Coinbase => write!(f, "Coinbase"),
+		}
+	}
+}
+
+/// Entry in the wallet history, recording a transaction that moved funds in
+/// or out of the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxLogEntry {
+	/// Sequential id of the entry
+	pub id: u32,
+	/// Whether we sent, received or mined the funds
+	pub tx_type: TxLogEntryType,
+	/// Hash of the transaction we built, if any
+	pub tx_hash: Option<String>,
+	/// Total of the outputs the transaction created for us
+	pub amount_credited: u64,
+	/// Total of our outputs the transaction spent
+	pub amount_debited: u64,
+	/// Fee of the transaction
+	pub fee: u64,
+	/// When the entry was created, in seconds since the epoch
+	pub timestamp: u64,
+	/// Height the transaction got confirmed at, once known
+	pub confirmed_height: Option<u64>,
+}
+
+/// Queries against a node the wallet relies on, so they can be served by
+/// something else than the configured nodes.
+pub trait NodeApi {
+	/// Current tip of the node chain
+	fn get_tip(&self) -> Result<api::Tip, Error>;
+	/// The unspent output with the provided commitment, None if the node
+	/// doesn't know about it
+	fn get_output(&self, commit: &pedersen::Commitment) -> Result<Option<api::Output>, Error>;
+	/// The outputs created by the block at the provided height that are still
+	/// unspent
+	fn get_block_outputs(&self, height: u64) -> Result<Vec<api::Output>, Error>;
+}
+
+/// Outcome of merging wallet data into another, see WalletData::merge.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+	/// Outputs we didn't know about
+	pub added: usize,
+	/// Outputs we knew about updated with more advanced data
+	pub updated: usize,
+	/// Outputs known on both sides with different values, left untouched
+	pub conflicts: usize,
+}
+
+/// Outcome of checking our outputs against the node's, see
+/// WalletData::reconcile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+	/// Unspent outputs we checked against the node
+	pub checked: usize,
+	/// Key ids of the outputs the node doesn't have anymore, now marked as
+	/// spent, in derivation order
+	pub spent: Vec<String>,
+}
+
+/// Changes between two snapshots of the wallet data, see WalletData::diff.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalletDiff {
+	/// Key ids of the outputs only in the later snapshot
+	pub added: Vec<OutputKey>,
+	/// Key ids of the outputs only in the earlier snapshot
+	pub removed: Vec<OutputKey>,
+	/// Key ids of the outputs in both with a different status, along with
+	/// the earlier and later status
+	pub status_changed: Vec<(OutputKey, OutputStatus, OutputStatus)>,
+}
+
+/// JSON blob of a single output handed between wallets, see
+/// WalletData::export_output_json. The commitment lets the importing wallet
+/// check the output wasn't altered on the way.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct OutputBlob {
+	output: OutputData,
+	/// Hex of the output commitment
+	commit: String,
+}
+
+/// Output as stored in the wallet data file, under its key. The key_id is the
+/// key already, only wallet files predating version 2 repeat it, in which
+/// case both have to match.
+#[derive(Serialize, Deserialize)]
+struct StoredOutput {
+	root_key_id: keychain::Identifier,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	key_id: Option<keychain::Identifier>,
+	n_child: u32,
+	value: u64,
+	status: OutputStatus,
+	height: u64,
+	lock_height: u64,
+	zero_ok: bool,
+	#[serde(default)]
+	tx_hash: Option<String>,
+	#[serde(default)]
+	spent_in_tx: Option<String>,
+	#[serde(default)]
+	locked_until: Option<u64>,
+	#[serde(default)]
+	label: Option<String>,
+	#[serde(default)]
+	watch_only: bool,
+	#[serde(default)]
+	is_coinbase: bool,
+}
+
+impl StoredOutput {
+	fn from_output(out: &OutputData) -> StoredOutput {
+		StoredOutput {
+			root_key_id: out.root_key_id.clone(),
+			key_id: None,
+			n_child: out.n_child,
+			value: out.value,
+			status: out.status.clone(),
+			height: out.height,
+			lock_height: out.lock_height,
+			zero_ok: out.zero_ok,
+			tx_hash: out.tx_hash.clone(),
+			spent_in_tx: out.spent_in_tx.clone(),
+			locked_until: out.locked_until,
+			label: out.label.clone(),
+			watch_only: out.watch_only,
+			is_coinbase: out.is_coinbase,
+		}
+	}
+
+	fn into_output(self, key_id: keychain::Identifier) -> OutputData {
+		OutputData {
+			root_key_id: self.root_key_id,
+			key_id: key_id,
+			n_child: self.n_child,
+			value: self.value,
+			status: self.status,
+			height: self.height,
+			lock_height: self.lock_height,
+			zero_ok: self.zero_ok,
+			tx_hash: self.tx_hash,
+			spent_in_tx: self.spent_in_tx,
+			locked_until: self.locked_until,
+			label: self.label,
+			watch_only: self.watch_only,
+			is_coinbase: self.is_coinbase,
+		}
+	}
+}
+
+fn serialize_outputs<S>(
+	outputs: &HashMap<OutputKey, OutputData>,
+	serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+	S: Serializer,
+{
+	let stored = outputs
+		.iter()
+		.map(|(key, out)| (key, StoredOutput::from_output(out)))
+		.collect::<HashMap<_, _>>();
+	stored.serialize(serializer)
+}
+
+/// Reads the outputs back, rebuilding their key_id from the key. Refuses keys
+/// that aren't the hex of a key_id, and a repeated key_id that doesn't match
+/// the key.
+fn deserialize_outputs<'de, D>(deserializer: D) -> Result<HashMap<OutputKey, OutputData>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	let stored = HashMap::<String, StoredOutput>::deserialize(deserializer)?;
+	let mut outputs = HashMap::new();
+	for (key, out) in stored {
+		// from_bytes pads or truncates, a key of the wrong length wouldn't
+		// come back the same
+		let key_id = match util::from_hex(key.clone()) {
+			Ok(bytes) => keychain::Identifier::from_bytes(&bytes),
+			Err(_) => return Err(de::Error::custom(format!("output key {} is not hex", key))),
+		};
+		if key_id.to_hex() != key {
+			return Err(de::Error::custom(format!("output key {} is not a key_id", key)));
+		}
+		if let Some(ref stored_key_id) = out.key_id {
+			if *stored_key_id != key_id {
+				return Err(de::Error::custom(format!(
+					"output key {} doesn't match its key_id {}",
+					key,
+					stored_key_id
+				)));
+			}
+		}
+		outputs.insert(OutputKey::new(&key_id), out.into_output(key_id));
 	}
+	Ok(outputs)
+}
+
+/// Summary of the wallet funds by status of the outputs holding them. The
+/// total covers everything that isn't spent yet, except watch-only outputs
+/// that only count toward watch_only.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalletBalance {
+	pub total: u64,
+	pub unspent: u64,
+	pub immature: u64,
+	pub unconfirmed: u64,
+	pub locked: u64,
+	#[serde(default)]
+	pub watch_only: u64,
+	/// Chain height our outputs were last checked against the node at, 0 if
+	/// they never were
+	#[serde(default)]
+	pub last_confirmed_height: u64,
 }
 
 /// Wallet information tracking all our outputs. Based on HD derivation and
@@ -189,7 +1175,40 @@ impl OutputData {
 /// TODO write locks so files don't get overwritten
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WalletData {
-	pub outputs: HashMap<String, OutputData>,
+	/// Version of the layout the data was written with, 0 for wallet files
+	/// predating versioning
+	#[serde(default)]
+	pub version: u32,
+	/// Outputs by key, stored without repeating their key_id, see
+	/// StoredOutput
+	#[serde(serialize_with = "serialize_outputs", deserialize_with = "deserialize_outputs")]
+	pub outputs: HashMap<OutputKey, OutputData>,
+	/// History of the transactions that moved funds in or out of the wallet
+	#[serde(default)]
+	pub tx_log: Vec<TxLogEntry>,
+	/// Highest child index handed out by next_child before indexes were
+	/// tracked by root key, still never reused for any root
+	#[serde(default)]
+	pub last_issued_child: u32,
+	/// Highest child index handed out by next_child for each root key (in
+	/// hex), so indexes never get reused even once the outputs using them are
+	/// pruned
+	#[serde(default)]
+	pub issued_children: HashMap<String, u32>,
+	/// Chain height of the node when our outputs were last checked against
+	/// it, 0 if they never were
+	#[serde(default)]
+	pub last_confirmed_height: u64,
+	/// Whether the history goes back to the creation of the wallet, which
+	/// isn't the case for wallets predating it, see has_tx_log
+	#[serde(default)]
+	pub tx_log_complete: bool,
+}
+
+impl Default for WalletData {
+	fn default() -> WalletData {
+		WalletData::new()
+	}
 }
 
 impl WalletData {
@@ -198,227 +1217,6415 @@ impl WalletData {
 	/// be held for as short a period as possible to avoid contention.
 	/// Note that due to the impossibility to do an actual file lock easily
 	/// across operating systems, this just creates a lock file with a "should
-	/// not exist" option.
-	pub fn with_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
+	/// not exist" option. The lock file records our PID and the time it was
+	/// taken so one left behind by a crashed process can be reclaimed.
+	/// With keep_backups, the wallet data we read gets backed up before being
+	/// overwritten, see backup_data_file. The data is written back in
+	/// data_format, whatever the format it was read in, and synced to disk
+	/// with sync_writes. GRIN_WALLET_DATA_DIR takes precedence over the
+	/// configured data_file_dir, see resolve_data_file_dir.
+	pub fn with_wallet<T, F>(config: &WalletConfig, f: F) -> Result<T, Error>
 		where F: FnOnce(&mut WalletData) -> T
 	{
-		// create directory if it doesn't exist
-		fs::create_dir_all(data_file_dir).unwrap_or_else(|why| {
-			info!(LOGGER, "! {:?}", why.kind());
-		});
+		WalletData::with_backend(FileBackend::from_config(config), f)
+	}
 
-		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
-		let lock_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, LOCK_FILE);
-
-		// create the lock files, if it already exists, will produce an error
-		// sleep and retry a few times if we cannot get it the first time
-		let mut retries = 0;
-		loop {
-			let result = OpenOptions::new()
-				.write(true)
-				.create_new(true)
-				.open(lock_file_path)
-				.map_err(|_| {
-					Error::WalletData(format!(
-						"Could not create wallet lock file. Either \
-					some other process is using the wallet or there's a write access issue."
-					))
-				});
-			match result {
-				Ok(_) => {
-					break;
-				}
-				Err(e) => {
-					if retries >= 3 {
-						return Err(e);
-					}
-					debug!(
-						LOGGER,
-						"failed to obtain wallet.lock, retries - {}, sleeping",
-						retries
-					);
-					retries += 1;
-					thread::sleep(time::Duration::from_millis(500));
-				}
+	/// Same as with_wallet, for wallet data kept in any backend.
+	pub fn with_backend<B, T, F>(backend: B, f: F) -> Result<T, Error>
+	where
+		B: WalletBackend,
+		F: FnOnce(&mut WalletData) -> T,
+	{
+		let mut session = WalletData::open_backend_session(backend)?;
+		let res = session.run(f);
+		session.commit().map(|_| res)
+	}
+
+	/// Takes the wallet lock and reads the wallet data, for several operations
+	/// to run on it with WalletSession::run before it gets written back once
+	/// with WalletSession::commit. Same as with_wallet otherwise, which should
+	/// be preferred for a single operation.
+	pub fn open_session(config: &WalletConfig) -> Result<WalletSession, Error> {
+		WalletData::open_backend_session(FileBackend::from_config(config))
+	}
+
+	/// Same as open_session, for wallet data kept in any backend.
+	pub fn open_backend_session<B: WalletBackend>(
+		mut backend: B,
+	) -> Result<WalletSession<B>, Error> {
+		backend.acquire()?;
+
+		// release the lock if the wallet data couldn't be read (corrupt)
+		match backend.read() {
+			Ok(wallet_data) => Ok(WalletSession {
+				wallet_data: wallet_data,
+				backend: backend,
+				locked: true,
+			}),
+			Err(e) => {
+				backend.release()?;
+				Err(e)
 			}
 		}
+	}
 
+	/// Allows read-only access to the wallet data, for operations that only
+	/// display or inspect it. Takes the same lock as with_wallet when the data
+	/// directory can be written, see WalletBackend::acquire_read, but never
+	/// writes the wallet data back.
+	pub fn read_wallet<T, F>(config: &WalletConfig, f: F) -> Result<T, Error>
+		where F: FnOnce(&WalletData) -> T
+	{
+		WalletData::read_backend(FileBackend::from_config(config), f)
+	}
 
-		// do what needs to be done
-		let mut wdat = WalletData::read_or_create(data_file_path)?;
-		let res = f(&mut wdat);
-		wdat.write(data_file_path)?;
+	/// Same as read_wallet, for wallet data kept in any backend.
+	pub fn read_backend<B, T, F>(mut backend: B, f: F) -> Result<T, Error>
+	where
+		B: WalletBackend,
+		F: FnOnce(&WalletData) -> T,
+	{
+		backend.acquire_read()?;
 
-		// delete the lock file
-		fs::remove_file(lock_file_path).map_err(|_| {
-			Error::WalletData(format!(
-				"Could not remove wallet lock file. Maybe insufficient rights?"
-			))
-		})?;
+		// release the lock even if the wallet data couldn't be read
+		let res = backend.read().map(|wdat| f(&wdat));
+		backend.release()?;
 
-		Ok(res)
+		res
 	}
 
-	/// Read the wallet data or created a brand new one if it doesn't exist yet
+	/// Reads wallet data from the provided file alone, typically a copy of
+	/// wallet.dat taken for backups or monitoring. No lock is taken and
+	/// nothing is ever written, migrations only apply to the data returned.
+	/// A missing file is an Io error of kind NotFound, one that can't be
+	/// parsed is WalletCorrupt.
+	pub fn read_from_path(path: &str) -> Result<WalletData, Error> {
+		let mut wallet_data = WalletData::read(path)?;
+		wallet_data.migrate()?;
+		Ok(wallet_data)
+	}
+
+	/// Rebuilds the wallet data from the chain alone, after wallet.dat was
+	/// lost. Goes through the unspent outputs of every block on the node,
+	/// then derives our keys one after the other and claims the outputs whose
+	/// range proof rewinds with one of them, which also gives their value. It
+	/// stops after max_child_gap derivations in a row with nothing found.
+	pub fn restore(
+		keychain: &keychain::Keychain,
+		node_api: &NodeApi,
+		max_child_gap: u32,
+	) -> Result<WalletData, Error> {
+		let root_key_id = keychain.root_key_id();
+		let tip = node_api.get_tip()?;
+		let mut wallet_data = WalletData::new();
+
+		let mut unclaimed = vec![];
+		for height in 0..(tip.height + 1) {
+			unclaimed.extend(node_api.get_block_outputs(height)?);
+		}
+
+		let mut misses = 0;
+		let mut n_child = 1;
+		while misses < max_child_gap && !unclaimed.is_empty() {
+			let key_id = keychain.derive_key_id(n_child)?;
+			let mut found = None;
+			for (i, api_out) in unclaimed.iter().enumerate() {
+				let info = keychain.rewind_range_proof(&key_id, api_out.commit, api_out.proof)?;
+				// the proof only tells the value, make sure it's ours
+				if info.success && keychain.commit(info.value, &key_id)? == api_out.commit {
+					found = Some((i, info.value));
+					break;
+				}
+			}
+			match found {
+				Some((i, value)) => {
+					let api_out = unclaimed.remove(i);
+					let status = if api_out.lock_height > tip.height {
+						OutputStatus::Immature
+					} else {
+						OutputStatus::Unspent
+					};
+					let is_coinbase = match api_out.output_type {
+						api::OutputType::Coinbase => true,
+						api::OutputType::Transaction => false,
+					};
+					wallet_data.add_output(OutputData {
+						root_key_id: root_key_id.clone(),
+						key_id: key_id.clone(),
+						n_child: n_child,
+						value: value,
+						status: status,
+						height: api_out.height,
+						lock_height: api_out.lock_height,
+						zero_ok: false,
+						tx_hash: None,
+						spent_in_tx: None,
+						locked_until: None,
+						label: None,
+						watch_only: false,
+						is_coinbase: is_coinbase,
+					})?;
+					wallet_data.issued_children.insert(root_key_id.to_hex(), n_child);
+					misses = 0;
+				}
+				None => misses += 1,
+			}
+			n_child += 1;
+		}
+		wallet_data.last_confirmed_height = tip.height;
+		Ok(wallet_data)
+	}
+
+	/// Read the wallet data or created a brand new one if it doesn't exist yet
+	/// Older wallet files get migrated to the current layout.
 	fn read_or_create(data_file_path: &str) -> Result<WalletData, Error> {
 		if Path::new(data_file_path).exists() {
-			WalletData::read(data_file_path)
+			let mut wdat = WalletData::read(data_file_path)?;
+			wdat.migrate()?;
+			Ok(wdat)
 		} else {
 			// just create a new instance, it will get written afterward
-			Ok(WalletData { outputs: HashMap::new() })
+			Ok(WalletData::new())
+		}
+	}
+
+	/// Brand new wallet data, without any output.
+	pub fn new() -> WalletData {
+		WalletData {
+			version: WALLET_DATA_VERSION,
+			outputs: HashMap::new(),
+			tx_log: vec![],
+			last_issued_child: 0,
+			issued_children: HashMap::new(),
+			last_confirmed_height: 0,
+			tx_log_complete: true,
+		}
+	}
+
+	/// Upgrades wallet data read in an older layout to the current one, one
+	/// version at a time. Data written by a more recent version of the wallet
+	/// is rejected rather than risking losing what we don't understand.
+	fn migrate(&mut self) -> Result<(), Error> {
+		if self.version > WALLET_DATA_VERSION {
+			return Err(Error::WalletData(format!(
+				"Wallet data version {} is more recent than the supported version {}",
+				self.version,
+				WALLET_DATA_VERSION
+			)));
 		}
+		let from_version = self.version;
+		// version 1 added the transaction history and the transaction hashes
+		// on outputs, serde already defaults them to empty so there's nothing
+		// to do beyond stamping the version
+		if self.version < 1 {
+			self.version = 1;
+			debug!(LOGGER, "migrated wallet data to version 1");
+		}
+		// version 2 stopped repeating the key_id of the outputs under their
+		// key, it's rebuilt from the key when reading either layout
+		if self.version < 2 {
+			self.version = 2;
+			debug!(LOGGER, "migrated wallet data to version 2");
+		}
+		// version 3 records whether the history is complete, which it is if
+		// it was there from the start. A wallet from before it that was
+		// migrated and written since can't be told apart from one that had
+		// the history all along.
+		if self.version < 3 {
+			self.tx_log_complete = from_version >= 1;
+			self.version = 3;
+			debug!(LOGGER, "migrated wallet data to version 3");
+		}
+		Ok(())
 	}
 
-	/// Read the wallet data from disk.
+	/// Read the wallet data from disk, in any of the formats write_file supports.
+	/// The binary format is recognized by its magic bytes, anything else is
+	/// parsed as JSON, indented or not.
 	fn read(data_file_path: &str) -> Result<WalletData, Error> {
-		let data_file =
-			File::open(data_file_path)
-				.map_err(|e| Error::WalletData(format!("Could not open {}: {}", data_file_path, e)))?;
-		serde_json::from_reader(data_file)
-			.map_err(|e| Error::WalletData(format!("Error reading {}: {}", data_file_path, e)))
-	}
-
-	/// Write the wallet data to disk.
-	fn write(&self, data_file_path: &str) -> Result<(), Error> {
-		let mut data_file =
-			File::create(data_file_path)
-				.map_err(|e| {
-					Error::WalletData(format!("Could not create {}: {}", data_file_path, e))
-				})?;
-		let res_json = serde_json::to_vec_pretty(self)
-			.map_err(|e| Error::WalletData(format!("Error serializing wallet data: {}", e)))?;
+		let mut data_file = File::open(data_file_path)
+			.map_err(|e| path_io_error(data_file_path, e))?;
+		let mut bytes = vec![];
 		data_file
-			.write_all(res_json.as_slice())
-			.map_err(|e| Error::WalletData(format!("Error writing {}: {}", data_file_path, e)))
+			.read_to_end(&mut bytes)
+			.map_err(|e| path_io_error(data_file_path, e))?;
+		if bytes.starts_with(&WALLET_BIN_MAGIC) {
+			WalletData::from_bytes(&bytes).map_err(|e| corrupt_error(data_file_path, e))
+		} else {
+			serde_json::from_slice(&bytes).map_err(|e| corrupt_error(data_file_path, e))
+		}
+	}
+
+	/// Write the wallet data to disk in the provided format. The data is
+	/// first written to a temporary file that then replaces the existing one,
+	/// so a failure midway never leaves a truncated wallet.dat behind, and
+	/// synced to disk if asked, see write_atomic. Always stamps the current
+	/// version.
+	fn write_file(
+		&mut self,
+		data_file_path: &str,
+		format: WalletFormat,
+		sync: bool,
+	) -> Result<(), Error> {
+		self.version = WALLET_DATA_VERSION;
+		let bytes = match format {
+			WalletFormat::Json => serde_json::to_vec_pretty(self)
+				.map_err(|e| Error::WalletData(format!("Error serializing wallet data: {}", e)))?,
+			WalletFormat::JsonCompact => serde_json::to_vec(self)
+				.map_err(|e| Error::WalletData(format!("Error serializing wallet data: {}", e)))?,
+			WalletFormat::Binary => self.to_bytes()?,
+		};
+		write_atomic(data_file_path, sync, |data_file| {
+			data_file
+				.write_all(bytes.as_slice())
+				.map_err(|e| path_io_error(data_file_path, e))
+		})
+	}
+
+	/// Encodes the wallet data in the binary format, see the Writeable
+	/// implementation.
+	fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+		ser::ser_vec(self)
+			.map_err(|e| Error::WalletData(format!("Error serializing wallet data: {:?}", e)))
+	}
+
+	/// Decodes wallet data in the binary format, see to_bytes.
+	fn from_bytes(bytes: &[u8]) -> Result<WalletData, String> {
+		let header_len = WALLET_BIN_MAGIC.len() + 1;
+		if bytes.len() < header_len {
+			return Err("truncated binary wallet data".to_string());
+		}
+		let version = bytes[WALLET_BIN_MAGIC.len()];
+		if version > WALLET_BIN_VERSION {
+			return Err(format!(
+				"unsupported binary format version {}, this wallet supports up to version {}",
+				version,
+				WALLET_BIN_VERSION
+			));
+		}
+		ser::deserialize(&mut &bytes[..])
+			.map_err(|e| format!("invalid binary wallet data: {:?}", e))
+	}
+
+	/// Write the wallet data in the streaming format, meant for very large
+	/// wallets: a first line holding everything but the outputs, then one
+	/// line per output ordered by derivation index, each a JSON object.
+	/// Written atomically like the classic format, and always synced to disk.
+	pub fn write_streaming(&mut self, data_file_path: &str) -> Result<(), Error> {
+		self.version = WALLET_DATA_VERSION;
+		let header = StreamHeader {
+			version: self.version,
+			last_issued_child: self.last_issued_child,
+			issued_children: self.issued_children.clone(),
+			last_confirmed_height: self.last_confirmed_height,
+			tx_log: self.tx_log.clone(),
+			tx_log_complete: self.tx_log_complete,
+		};
+		let outputs = self.sorted_outputs();
+		write_atomic(data_file_path, true, |data_file| {
+			let mut writer = BufWriter::new(data_file);
+			serde_json::to_writer(&mut writer, &header)?;
+			writer.write_all(b"\n").map_err(|e| path_io_error(data_file_path, e))?;
+			for out in outputs {
+				serde_json::to_writer(&mut writer, out)?;
+				writer.write_all(b"\n").map_err(|e| path_io_error(data_file_path, e))?;
+			}
+			writer.flush().map_err(|e| path_io_error(data_file_path, e))
+		})
+	}
+
+	/// Read wallet data written in the streaming format, see write_streaming.
+	/// Older layouts get migrated like with the classic format.
+	pub fn read_streaming(data_file_path: &str) -> Result<WalletData, Error> {
+		let mut outputs = HashMap::new();
+		let header = read_stream(data_file_path, |out| {
+			outputs.insert(out.key(), out);
+			Ok(())
+		})?;
+		let mut wdat = WalletData {
+			version: header.version,
+			outputs: outputs,
+			tx_log: header.tx_log,
+			last_issued_child: header.last_issued_child,
+			issued_children: header.issued_children,
+			last_confirmed_height: header.last_confirmed_height,
+			tx_log_complete: header.tx_log_complete,
+		};
+		wdat.migrate()?;
+		Ok(wdat)
+	}
+
+	/// Go through the outputs of wallet data written in the streaming format
+	/// one at a time, without ever loading all of them in memory. Stops at
+	/// the first error returned by the provided function.
+	pub fn stream_outputs<F>(data_file_path: &str, f: F) -> Result<(), Error>
+	where
+		F: FnMut(OutputData) -> Result<(), Error>,
+	{
+		read_stream(data_file_path, f).map(|_| ())
+	}
+
+	/// Append a new output data to the wallet data. Refuses to overwrite an
+	/// output we already track, the only valid case being an unconfirmed
+	/// coinbase placeholder built again for the same derivation.
+	pub fn add_output(&mut self, out: OutputData) -> Result<(), Error> {
+		let key = out.key();
+		if let Some(existing) = self.outputs.get(&key) {
+			if !existing.is_coinbase_placeholder() || existing.n_child != out.n_child {
+				return Err(Error::WalletData(format!(
+					"output {} is already tracked (n_child {}, value {}, {})",
+					key,
+					existing.n_child,
+					existing.value,
+					existing.status,
+				)));
+			}
+		}
+		self.outputs.insert(key, out);
+		Ok(())
+	}
+
+	/// Lock an output data, until the provided height if any, see
+	/// expire_locks. Fails if we don't track the output or if the one we track
+	/// doesn't hold the same value, the lock didn't take then.
+	pub fn lock_output(
+		&mut self,
+		out: &OutputData,
+		locked_until: Option<u64>,
+	) -> Result<(), Error> {
+		match self.outputs.get_mut(&out.key()) {
+			Some(out_to_lock) => {
+				if out_to_lock.value != out.value {
+					return Err(Error::WalletData(format!(
+						"Could not lock output {}, expected value {} but found {}",
+						out.key_id,
+						out.value,
+						out_to_lock.value
+					)));
+				}
+				out_to_lock.lock(locked_until);
+				Ok(())
+			}
+			None => Err(Error::WalletData(
+				format!("Could not lock output {}, not found in wallet", out.key_id),
+			)),
+		}
+	}
+
+	/// Release an output we locked for a send that never made it out, making
+	/// it spendable again. Only locked outputs can be unlocked, anything
+	/// spent or not confirmed yet is refused.
+	pub fn unlock_output(&mut self, key_id: &keychain::Identifier) -> Result<(), Error> {
+		match self.outputs.get_mut(&OutputKey::new(key_id)) {
+			Some(out) => {
+				if out.status != OutputStatus::Locked {
+					return Err(Error::WalletData(format!(
+						"Could not unlock output {}, it is {} and not locked",
+						key_id,
+						out.status
+					)));
+				}
+				out.unlock();
+				Ok(())
+			}
+			None => Err(Error::WalletData(
+				format!("Could not unlock output {}, not found in wallet", key_id),
+			)),
+		}
+	}
+
+	/// Set the label of an output we track, None clearing it.
+	pub fn set_label(
+		&mut self,
+		key_id: &keychain::Identifier,
+		label: Option<String>,
+	) -> Result<(), Error> {
+		match self.outputs.get_mut(&OutputKey::new(key_id)) {
+			Some(out) => {
+				out.label = label;
+				Ok(())
+			}
+			None => Err(Error::WalletData(
+				format!("Could not label output {}, not found in wallet", key_id),
+			)),
+		}
+	}
+
+	/// Release all the locked outputs, returning how many were unlocked.
+	pub fn unlock_all_locked(&mut self) -> usize {
+		let mut count = 0;
+		for out in self.outputs.values_mut() {
+			if out.status == OutputStatus::Locked {
+				out.unlock();
+				count += 1;
+			}
+		}
+		count
 	}
 
-	/// Append a new output data to the wallet data.
-	/// TODO - we should check for overwriting here - only really valid for
-	/// unconfirmed coinbase
-	pub fn add_output(&mut self, out: OutputData) {
-		self.outputs.insert(out.key_id.to_hex(), out.clone());
+	/// Recovery for a wallet left with locked outputs by a crash: releases
+	/// all of them, see unlock_output, returning how many were unlocked. As
+	/// this also unlocks outputs of sends that may still confirm, it only
+	/// runs when confirm is set and fails otherwise.
+	pub fn force_unlock_all(&mut self, confirm: bool) -> Result<usize, Error> {
+		if !confirm {
+			return Err(Error::WalletData(
+				"refusing to unlock all outputs without confirmation".to_owned(),
+			));
+		}
+		let locked = self.outputs
+			.values()
+			.filter(|out| out.status == OutputStatus::Locked)
+			.map(|out| out.key_id.clone())
+			.collect::<Vec<_>>();
+		for key_id in &locked {
+			self.unlock_output(key_id)?;
+		}
+		warn!(LOGGER, "Force unlocked {} outputs", locked.len());
+		Ok(locked.len())
+	}
+
+	/// Release the locks expiring before the provided height, for sends
+	/// that never confirmed. Locks taken without an expiry are kept. Returns
+	/// how many outputs were unlocked.
+	pub fn expire_locks(&mut self, current_height: u64) -> usize {
+		let mut count = 0;
+		for out in self.outputs.values_mut() {
+			if out.status != OutputStatus::Locked {
+				continue;
+			}
+			if let Some(locked_until) = out.locked_until {
+				if locked_until < current_height {
+					out.unlock();
+					count += 1;
+				}
+			}
+		}
+		count
+	}
+
+	/// Records the hash of a transaction we built on the outputs it spends
+	/// and the ones it creates.
+	pub fn record_tx(
+		&mut self,
+		tx_hash: &str,
+		spent: &Vec<OutputData>,
+		created: &Vec<keychain::Identifier>,
+	) {
+		for out in spent {
+			if let Some(out) = self.outputs.get_mut(&out.key()) {
+				out.spent_in_tx = Some(tx_hash.to_string());
+			}
+		}
+		for key_id in created {
+			if let Some(out) = self.outputs.get_mut(&OutputKey::new(key_id)) {
+				out.tx_hash = Some(tx_hash.to_string());
+			}
+		}
+	}
+
+	/// Gives up on a send that never confirmed, identified by the hash
+	/// recorded with record_tx. The outputs it spends are unlocked, spendable
+	/// again, and the change outputs it creates marked abandoned. Returns the
+	/// number of outputs updated. Refused if any of them already confirmed.
+	pub fn abandon_tx(&mut self, tx_hash: &str) -> Result<usize, Error> {
+		let tx_hash = Some(tx_hash.to_string());
+		let mut inputs = vec![];
+		let mut change = vec![];
+		for (key, out) in &self.outputs {
+			if out.spent_in_tx == tx_hash && out.status == OutputStatus::Locked {
+				inputs.push(key.clone());
+			} else if out.tx_hash == tx_hash && out.status == OutputStatus::Unconfirmed {
+				change.push(key.clone());
+			} else if out.spent_in_tx == tx_hash || out.tx_hash == tx_hash {
+				return Err(Error::WalletData(format!(
+					"Could not abandon transaction {}, output {} is {}",
+					tx_hash.as_ref().unwrap(),
+					out.key_id,
+					out.status
+				)));
+			}
+		}
+		if inputs.is_empty() && change.is_empty() {
+			return Err(Error::WalletData(format!(
+				"Could not abandon transaction {}, no pending output found in wallet",
+				tx_hash.as_ref().unwrap()
+			)));
+		}
+		for key in &inputs {
+			if let Some(out) = self.outputs.get_mut(key) {
+				out.unlock();
+				out.spent_in_tx = None;
+			}
+		}
+		for key in &change {
+			if let Some(out) = self.outputs.get_mut(key) {
+				out.status = OutputStatus::Abandoned;
+			}
+		}
+		Ok(inputs.len() + change.len())
 	}
 
-	/// Lock an output data.
-	/// TODO - we should track identifier on these outputs (not just n_child)
-	pub fn lock_output(&mut self, out: &OutputData) {
-		if let Some(out_to_lock) = self.outputs.get_mut(&out.key_id.to_hex()) {
-			if out_to_lock.value == out.value {
-				out_to_lock.lock()
+	/// The inputs of a send that never confirmed, identified by the hash
+	/// recorded with record_tx, along with the fee of the same transaction at
+	/// a higher base fee, for it to be built again paying more. Refused if
+	/// any of its outputs already confirmed, or when the new fee isn't above
+	/// the one logged for it.
+	pub fn bump_fee_inputs(
+		&self,
+		tx_hash: &str,
+		new_base_fee: u64,
+	) -> Result<(Vec<OutputData>, u64), Error> {
+		let tx_hash = Some(tx_hash.to_string());
+		let mut inputs = vec![];
+		let mut num_change = 0;
+		for out in self.sorted_outputs() {
+			if out.spent_in_tx == tx_hash && out.status == OutputStatus::Locked {
+				inputs.push(out.clone());
+			} else if out.tx_hash == tx_hash && out.status == OutputStatus::Unconfirmed {
+				num_change += 1;
+			} else if out.spent_in_tx == tx_hash || out.tx_hash == tx_hash {
+				return Err(Error::WalletData(format!(
+					"Could not bump the fee of transaction {}, output {} is {}",
+					tx_hash.as_ref().unwrap(),
+					out.key_id,
+					out.status
+				)));
+			}
+		}
+		if inputs.is_empty() {
+			return Err(Error::WalletData(format!(
+				"Could not bump the fee of transaction {}, no pending input found in wallet",
+				tx_hash.as_ref().unwrap()
+			)));
+		}
+
+		// the payment output along with our change
+		let fee = tx_fee(inputs.len(), num_change + 1, Some(new_base_fee));
+		if let Some(entry) = self.tx_log.iter().find(|entry| entry.tx_hash == tx_hash) {
+			if entry.confirmed_height.is_some() {
+				return Err(Error::WalletData(format!(
+					"Could not bump the fee of transaction {}, it's already confirmed",
+					tx_hash.as_ref().unwrap()
+				)));
+			}
+			if fee <= entry.fee {
+				return Err(Error::WalletData(format!(
+					"Could not bump the fee of transaction {}, {} isn't above its fee of {}",
+					tx_hash.as_ref().unwrap(),
+					fee,
+					entry.fee
+				)));
 			}
 		}
+		Ok((inputs, fee))
+	}
+
+	/// Appends an entry to the wallet history, returning its id.
+	pub fn log_tx(
+		&mut self,
+		tx_type: TxLogEntryType,
+		tx_hash: Option<String>,
+		amount_credited: u64,
+		amount_debited: u64,
+		fee: u64,
+	) -> u32 {
+		let id = self.tx_log.last().map(|entry| entry.id + 1).unwrap_or(0);
+		self.tx_log.push(TxLogEntry {
+			id: id,
+			tx_type: tx_type,
+			tx_hash: tx_hash,
+			amount_credited: amount_credited,
+			amount_debited: amount_debited,
+			fee: fee,
+			timestamp: now_secs(),
+			confirmed_height: None,
+		});
+		id
+	}
+
+	/// History of the transactions that moved funds in or out of the wallet,
+	/// oldest first.
+	pub fn tx_log(&self) -> &[TxLogEntry] {
+		&self.tx_log
+	}
+
+	/// Whether we have the whole history of the transactions of this wallet.
+	/// Wallets predating the history only have it from their migration on,
+	/// anything computed from it would be wrong for them.
+	pub fn has_tx_log(&self) -> bool {
+		self.tx_log_complete
+	}
+
+	/// Total of the fees paid by our sends over the life of the wallet. Is 0
+	/// when we don't have the history to tell, see has_tx_log.
+	pub fn total_fees_paid(&self) -> u64 {
+		if !self.has_tx_log() {
+			return 0;
+		}
+		self.tx_log
+			.iter()
+			.filter(|entry| entry.tx_type == TxLogEntryType::Sent)
+			.map(|entry| entry.fee)
+			.sum()
 	}
 
 	pub fn get_output(&self, key_id: &keychain::Identifier) -> Option<&OutputData> {
-		self.outputs.get(&key_id.to_hex())
+		self.outputs.get(&OutputKey::new(key_id))
 	}
 
-	/// Select a subset of unspent outputs to spend in a transaction
-	/// transferring the provided amount.
-	pub fn select(&self, root_key_id: keychain::Identifier, amount: u64) -> (Vec<OutputData>, i64) {
-		let mut to_spend = vec![];
-		let mut input_total = 0;
+	/// All the outputs holding exactly the provided value, ordered as in
+	/// sorted_outputs. Several outputs can share the same value.
+	pub fn find_outputs_by_value(&self, value: u64) -> Vec<&OutputData> {
+		self.sorted_outputs()
+			.into_iter()
+			.filter(|out| out.value == value)
+			.collect()
+	}
 
-		for out in self.outputs.values() {
-			if out.root_key_id == root_key_id
-				&& (out.status == OutputStatus::Unspent)
-					// the following will let us spend zero confirmation change outputs
-					// || (out.status == OutputStatus::Unconfirmed && out.zero_ok))
-			{
-				to_spend.push(out.clone());
-				input_total += out.value;
-				if input_total >= amount {
-					break;
+	/// The outputs confirmed in the block at the provided height, ordered by
+	/// value (and key_id for equal values).
+	pub fn outputs_at_height(&self, height: u64) -> Vec<&OutputData> {
+		self.outputs_in_range(height, height)
+	}
+
+	/// The outputs confirmed in blocks between the provided heights, both
+	/// included, ordered by value (and key_id for equal values).
+	pub fn outputs_in_range(&self, from: u64, to: u64) -> Vec<&OutputData> {
+		let mut outputs = self.outputs
+			.values()
+			.filter(|out| out.height >= from && out.height <= to)
+			.collect::<Vec<_>>();
+		outputs.sort_by(|a, b| {
+			a.value.cmp(&b.value).then_with(|| a.key_id.to_hex().cmp(&b.key_id.to_hex()))
+		});
+		outputs
+	}
+
+	/// All our outputs in a stable order, by derivation index then value
+	/// (and key_id to break any remaining tie), unlike the underlying map.
+	/// Makes selection and the transactions we build reproducible.
+	pub fn sorted_outputs(&self) -> Vec<&OutputData> {
+		let mut outputs = self.outputs.values().collect::<Vec<_>>();
+		outputs.sort_by(|a, b| {
+			a.n_child
+				.cmp(&b.n_child)
+				.then(a.value.cmp(&b.value))
+				.then(a.key_id.to_hex().cmp(&b.key_id.to_hex()))
+		});
+		outputs
+	}
+
+	/// A page of at most limit of our outputs, in the order of sorted_outputs,
+	/// skipping the first offset ones. Only outputs in the provided status are
+	/// considered, if any. Also returns how many outputs there are in total,
+	/// so an offset past the end gives an empty page with the right total.
+	pub fn list_outputs(
+		&self,
+		offset: usize,
+		limit: usize,
+		filter: Option<OutputStatus>,
+	) -> (Vec<OutputData>, usize) {
+		let outputs = self.sorted_outputs()
+			.into_iter()
+			.filter(|out| filter.as_ref().map_or(true, |status| out.status == *status))
+			.collect::<Vec<_>>();
+		let page = outputs
+			.iter()
+			.skip(offset)
+			.take(limit)
+			.map(|&out| out.clone())
+			.collect();
+		(page, outputs.len())
+	}
+
+	/// Short fingerprint of our outputs, the same for the same key_ids,
+	/// values, statuses and heights however the map happens to be ordered.
+	/// Lets a caller polling the wallet cheaply tell whether anything changed.
+	pub fn state_hash(&self) -> String {
+		let mut hasher = Blake2b::new(32);
+		let mut num = [0; 8];
+		for out in self.sorted_outputs() {
+			// length prefix the variable fields so bytes can't move across
+			for field in &[out.key_id.to_hex(), out.status.to_string()] {
+				BigEndian::write_u64(&mut num, field.len() as u64);
+				hasher.update(&num);
+				hasher.update(field.as_bytes());
+			}
+			for n in &[out.value, out.height] {
+				BigEndian::write_u64(&mut num, *n);
+				hasher.update(&num);
+			}
+		}
+		util::to_hex(hasher.finalize().as_bytes().to_vec())
+	}
+
+	/// Stop tracking an output, returning it if it was there.
+	pub fn remove_output(&mut self, key_id: &keychain::Identifier) -> Option<OutputData> {
+		self.outputs.remove(&OutputKey::new(key_id))
+	}
+
+	/// What changed from this wallet data to the other, a later snapshot of
+	/// it like after a refresh. Outputs are designated by key_id, each list
+	/// sorted by key_id.
+	pub fn diff(&self, other: &WalletData) -> WalletDiff {
+		let mut diff = WalletDiff::default();
+		for (key, out) in &self.outputs {
+			match other.outputs.get(key) {
+				None => diff.removed.push(key.clone()),
+				Some(other_out) if other_out.status != out.status => {
+					diff.status_changed.push((
+						key.clone(),
+						out.status.clone(),
+						other_out.status.clone(),
+					))
 				}
+				Some(_) => {}
 			}
 		}
-		// TODO - clean up our handling of i64 vs u64 so we are consistent
-		(to_spend, (input_total as i64) - (amount as i64))
+		for key in other.outputs.keys() {
+			if !self.outputs.contains_key(key) {
+				diff.added.push(key.clone());
+			}
+		}
+		diff.added.sort();
+		diff.removed.sort();
+		diff.status_changed.sort_by(|a, b| a.0.cmp(&b.0));
+		diff
 	}
 
-	/// Next child index when we want to create a new output.
-	pub fn next_child(&self, root_key_id: keychain::Identifier) -> u32 {
-		let mut max_n = 0;
-		for out in self.outputs.values() {
-			if max_n < out.n_child && out.root_key_id == root_key_id {
-				max_n = out.n_child;
+	/// Adds the outputs of another copy of the wallet data, after a restore
+	/// or when the wallet was used from two places. Outputs only known on one
+	/// side are all kept. For outputs on both sides, we keep the most
+	/// advanced status (Spent > Locked > Unspent > Immature > Unconfirmed)
+	/// and the highest heights. Both copies having different values for the
+	/// same key can't be reconciled, those are reported and left as is.
+	pub fn merge(&mut self, other: &WalletData) -> MergeReport {
+		let mut report = MergeReport::default();
+		self.last_issued_child = cmp::max(self.last_issued_child, other.last_issued_child);
+		for (root, &theirs) in &other.issued_children {
+			let ours = self.issued_children.entry(root.clone()).or_insert(0);
+			*ours = cmp::max(*ours, theirs);
+		}
+		for (key, theirs) in &other.outputs {
+			let ours = match self.outputs.get_mut(key) {
+				Some(ours) => ours,
+				None => {
+					self.outputs.insert(key.clone(), theirs.clone());
+					report.added += 1;
+					continue;
+				}
+			};
+			if ours.value != theirs.value {
+				report.conflicts += 1;
+				continue;
+			}
+
+			let mut updated = false;
+			if theirs.status.rank() > ours.status.rank() {
+				ours.status = theirs.status.clone();
+				ours.locked_until = theirs.locked_until;
+				updated = true;
+			}
+			if theirs.height > ours.height {
+				ours.height = theirs.height;
+				updated = true;
+			}
+			if theirs.lock_height > ours.lock_height {
+				ours.lock_height = theirs.lock_height;
+				updated = true;
+			}
+			if ours.tx_hash.is_none() && theirs.tx_hash.is_some() {
+				ours.tx_hash = theirs.tx_hash.clone();
+				updated = true;
+			}
+			if ours.spent_in_tx.is_none() && theirs.spent_in_tx.is_some() {
+				ours.spent_in_tx = theirs.spent_in_tx.clone();
+				updated = true;
+			}
+			if ours.label.is_none() && theirs.label.is_some() {
+				ours.label = theirs.label.clone();
+				updated = true;
+			}
+			if updated {
+				report.updated += 1;
 			}
 		}
-		max_n + 1
+		report
 	}
-}
 
-/// Helper in serializing the information a receiver requires to build a
-/// transaction.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct JSONPartialTx {
-	amount: u64,
-	blind_sum: String,
-	tx: String,
-}
+	/// Drop the spent outputs confirmed below the provided height, returning
+	/// how many were removed. Outputs in any other status are always kept.
+	pub fn prune_spent(&mut self, older_than_height: u64) -> usize {
+		let before = self.outputs.len();
+		self.outputs.retain(|_, out| {
+			!(out.status == OutputStatus::Spent && out.height < older_than_height)
+		});
+		before - self.outputs.len()
+	}
 
-/// Encodes the information for a partial transaction (not yet completed by the
-/// receiver) into JSON.
-pub fn partial_tx_to_json(receive_amount: u64,
-                          blind_sum: keychain::BlindingFactor,
-                          tx: Transaction)
-                          -> String {
-	let partial_tx = JSONPartialTx {
-		amount: receive_amount,
-		blind_sum: util::to_hex(blind_sum.secret_key().as_ref().to_vec()),
-		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
-	};
-	serde_json::to_string_pretty(&partial_tx).unwrap()
-}
+	/// Select a subset of unspent outputs to spend in a transaction
+	/// transferring the provided amount, with an estimated fee on top. Uses
+	/// the Auto strategy, see SelectionStrategy. The returned change is the
+	/// selected total minus amount and fee, negative when the wallet can't
+	/// cover it (in which case all unspent outputs are returned).
+	pub fn select(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		fee: u64,
+	) -> (Vec<OutputData>, i64) {
+		let target = amount.saturating_add(fee);
+		match self.select_checked(root_key_id.clone(), target) {
+			Ok((coins, change)) => (coins, change as i64),
+			Err(_) => {
+				let coins = self.unspent_outputs(&root_key_id);
+				let total: u64 = coins.iter().map(|out| out.value).sum();
+				// TODO - clean up our handling of i64 vs u64 so we are consistent
+				(coins, (total as i64) - (target as i64))
+			}
+		}
+	}
 
-/// Reads a partial transaction encoded as JSON into the amount, sum of blinding
-/// factors and the transaction itself.
-pub fn partial_tx_from_json(keychain: &keychain::Keychain,
-                            json_str: &str)
-                            -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
-	let partial_tx: JSONPartialTx = serde_json::from_str(json_str)?;
+	/// Select a subset of unspent outputs covering the provided amount (fee
+	/// included) with the Auto strategy. Returns the selected outputs and the
+	/// change, or a NotEnoughFunds error with the total available if all our
+	/// unspent outputs can't cover the amount.
+	pub fn select_checked(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+	) -> Result<(Vec<OutputData>, u64), Error> {
+		self.select_excluding(root_key_id, amount, &HashSet::new())
+	}
 
-	let blind_bin = util::from_hex(partial_tx.blind_sum)?;
+	/// Same as select_checked, never selecting the outputs whose key_id (in
+	/// hex) is in exclude, for manual coin control. Excluded outputs still
+	/// count in the balance, but not in the available total of a
+	/// NotEnoughFunds error.
+	pub fn select_excluding(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		exclude: &HashSet<String>,
+	) -> Result<(Vec<OutputData>, u64), Error> {
+		let (coins, change) = self.select_refs(&root_key_id, amount, exclude)?;
+		Ok((coins.into_iter().cloned().collect(), change))
+	}
 
-	// TODO - turn some data into a blinding factor here somehow
-	// let blinding = SecretKey::from_slice(&secp, &blind_bin[..])?;
-	let blinding = keychain::BlindingFactor::from_slice(keychain.secp(), &blind_bin[..])?;
+	/// Same as select_excluding, selecting references to our outputs.
+	fn select_refs(
+		&self,
+		root_key_id: &keychain::Identifier,
+		amount: u64,
+		exclude: &HashSet<String>,
+	) -> Result<(Vec<&OutputData>, u64), Error> {
+		let candidates = self.unspent_refs(root_key_id)
+			.into_iter()
+			.filter(|out| !exclude.contains(&out.key_id.to_hex()))
+			.collect();
+		let (coins, _) = select_from(candidates, amount, SelectionStrategy::Auto);
+		let total: u64 = coins.iter().map(|out| out.value).sum();
+		if total < amount {
+			return Err(Error::NotEnoughFunds {
+				available: total,
+				needed: amount,
+			});
+		}
+		Ok((coins, total - amount))
+	}
 
-	let tx_bin = util::from_hex(partial_tx.tx)?;
-	let tx = ser::deserialize(&mut &tx_bin[..])
-		.map_err(|_| {
-			Error::Format("Could not deserialize transaction, invalid format.".to_string())
+	/// Selects outputs covering the provided amount and the fee of the
+	/// transaction spending them, with the payment output and the requested
+	/// number of change outputs, see select_with_fee. Returns the selected
+	/// outputs, the fee and the change, failing if the change can't be split
+	/// in that many outputs, see split_change. Change at or below dust_limit
+	/// isn't worth an output and goes to the fee instead, the change returned
+	/// is 0 then and the transaction shouldn't have any change output. A zero
+	/// amount, or one that overflows with the fee on top, is refused upfront.
+	pub fn estimate_and_select(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		base_fee: Option<u64>,
+		num_change_outputs: usize,
+		dust_limit: u64,
+	) -> Result<(Vec<OutputData>, u64, i64), Error> {
+		estimate_with_fee(amount, base_fee, num_change_outputs, dust_limit, |needed_total| {
+			self.select_checked(root_key_id.clone(), needed_total).map(|(coins, _)| coins)
+		})
+	}
+
+	/// Same as estimate_and_select, only selecting among the outputs we can
+	/// spend at the provided chain height, see select_spendable. The
+	/// selection a send makes.
+	pub fn estimate_and_select_spendable(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		base_fee: Option<u64>,
+		num_change_outputs: usize,
+		dust_limit: u64,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> Result<(Vec<OutputData>, u64, i64), Error> {
+		estimate_with_fee(amount, base_fee, num_change_outputs, dust_limit, |needed_total| {
+			let (coins, change) = self.select_spendable(
+				root_key_id.clone(),
+				needed_total,
+				0,
+				current_height,
+				coinbase_maturity,
+				min_confirmations,
+			);
+			if change < 0 {
+				return Err(Error::NotEnoughFunds {
+					available: coins.iter().map(|out| out.value).sum(),
+					needed: needed_total,
+				});
+			}
+			Ok(coins)
+		})
+	}
+
+	/// Selects the inputs of a send, with a single change output unless the
+	/// change is at or below dust_limit, and locks them in one go, returning
+	/// the selected outputs, the change and the fee. Either all of the
+	/// selected outputs end up locked or, on any error, none of them.
+	pub fn reserve_for_send(
+		&mut self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		base_fee: Option<u64>,
+		dust_limit: u64,
+	) -> Result<(Vec<OutputData>, u64, u64), Error> {
+		let (coins, fee, change) =
+			self.estimate_and_select(root_key_id, amount, base_fee, 1, dust_limit)?;
+		for (i, coin) in coins.iter().enumerate() {
+			if let Err(e) = self.lock_output(coin, None) {
+				// put back the ones already locked as they were selected
+				for locked in &coins[..i] {
+					self.outputs.insert(locked.key(), locked.clone());
+				}
+				return Err(e);
+			}
+		}
+		Ok((coins, change as u64, fee))
+	}
+
+	/// How many inputs a transaction sending the provided amount, with a
+	/// single change output, would spend and its fee. Runs the same selection
+	/// as estimate_and_select without copying any output, to tell about a
+	/// send before making it.
+	pub fn estimate_inputs(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		base_fee: Option<u64>,
+	) -> Result<(usize, u64), Error> {
+		if amount == 0 {
+			return Err(Error::WalletData("amount must be greater than zero".to_string()));
+		}
+		let no_exclude = HashSet::new();
+		let (coins, fee) = select_with_fee(amount, base_fee, 2, |needed_total| {
+			self.select_refs(&root_key_id, needed_total, &no_exclude).map(|(coins, _)| coins)
 		})?;
+		Ok((coins.len(), fee))
+	}
 
-	Ok((partial_tx.amount, blinding, tx))
-}
+	/// Same as select but only considers outputs that can actually be spent
+	/// at the provided chain height, skipping immature coinbase outputs, those
+	/// with less than min_confirmations confirmations and those with a
+	/// lock_height still in the future (even if our status for them is
+	/// stale), see OutputData::is_spendable.
+	pub fn select_spendable(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		fee: u64,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> (Vec<OutputData>, i64) {
+		self.select_spendable_with_strategy(
+			root_key_id,
+			amount,
+			fee,
+			current_height,
+			coinbase_maturity,
+			min_confirmations,
+			SelectionStrategy::Auto,
+		)
+	}
 
-/// Amount in request to build a coinbase output.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum WalletReceiveRequest {
-	Coinbase(BlockFees),
-	PartialTransaction(String),
-	Finalize(String),
-}
+	/// Same as select_spendable, ordering the spendable outputs according to
+	/// the given strategy.
+	pub fn select_spendable_with_strategy(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		fee: u64,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+		strategy: SelectionStrategy,
+	) -> (Vec<OutputData>, i64) {
+		let candidates = self.unspent_outputs(&root_key_id)
+			.into_iter()
+			.filter(|out| out.is_spendable(current_height, coinbase_maturity, min_confirmations))
+			.collect();
+		select_from(candidates, amount.saturating_add(fee), strategy)
+	}
 
-/// Fees in block to use for coinbase amount calculation
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct BlockFees {
-	pub fees: u64,
-	pub height: u64,
-	pub key_id: Option<keychain::Identifier>,
-}
+	/// All the outputs derived from the provided root key that can be spent
+	/// at the provided chain height and their total, to consolidate them
+	/// into a single new output. Outputs are in derivation order.
+	pub fn select_all_spendable(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> (Vec<OutputData>, u64) {
+		let coins = self.unspent_outputs(&root_key_id)
+			.into_iter()
+			.filter(|out| out.is_spendable(current_height, coinbase_maturity, min_confirmations))
+			.collect::<Vec<_>>();
+		let total = coins.iter().map(|out| out.value).sum();
+		(coins, total)
+	}
 
-impl BlockFees {
-	pub fn key_id(&self) -> Option<keychain::Identifier> {
-		self.key_id.clone()
+	/// Everything we can send at the provided chain height, to empty the
+	/// wallet: all the spendable outputs (see select_all_spendable), the
+	/// amount left once the fee of a transaction spending them to a single
+	/// output is taken out of their total, and that fee. Fails when the fee
+	/// would take it all.
+	pub fn select_max(
+		&self,
+		root_key_id: keychain::Identifier,
+		base_fee: Option<u64>,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> Result<(Vec<OutputData>, u64, u64), Error> {
+		let (coins, total) = self.select_all_spendable(
+			root_key_id,
+			current_height,
+			coinbase_maturity,
+			min_confirmations,
+		);
+		let fee = tx_fee(coins.len(), 1, base_fee);
+		if total <= fee {
+			// sending anything takes at least one more than the fee
+			return Err(Error::NotEnoughFunds {
+				available: total,
+				needed: fee.saturating_add(1),
+			});
+		}
+		Ok((coins, total - fee, fee))
 	}
-}
 
-/// Response to build a coinbase output.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct CbData {
-	pub output: String,
-	pub kernel: String,
-	pub key_id: String,
+	/// Count and total value of the outputs of the provided root key we can
+	/// spend at the provided chain height, see is_spendable, by age. Outputs
+	/// are grouped by the height they were confirmed at, in buckets of
+	/// bucket_size blocks each given by the height it starts at. Buckets come
+	/// oldest first and the empty ones are left out.
+	pub fn age_histogram(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		bucket_size: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> Vec<(u64, u64, u64)> {
+		let bucket_size = cmp::max(bucket_size, 1);
+		let mut histogram: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+		let outputs = self.outputs.values().filter(|out| {
+			out.root_key_id == root_key_id
+				&& out.is_spendable(current_height, coinbase_maturity, min_confirmations)
+		});
+		for out in outputs {
+			let start = out.height / bucket_size * bucket_size;
+			let bucket = histogram.entry(start).or_insert((0, 0));
+			bucket.0 += 1;
+			bucket.1 += out.value;
+		}
+		histogram
+			.into_iter()
+			.map(|(start, (count, value))| (start, count, value))
+			.collect()
+	}
+
+	/// Total we'll be able to spend at the provided future chain height,
+	/// counting the outputs we can spend now along with the immature ones
+	/// that will have matured by then. Outputs still unconfirmed or locked
+	/// aren't counted, we can't tell when they'll be spendable.
+	pub fn projected_spendable(
+		&self,
+		root_key_id: keychain::Identifier,
+		at_height: u64,
+		coinbase_maturity: u64,
+	) -> u64 {
+		self.outputs
+			.values()
+			.filter(|out| out.root_key_id == root_key_id && out.height <= at_height)
+			.filter(|out| match out.status {
+				OutputStatus::Unspent => out.is_spendable(at_height, coinbase_maturity, 0),
+				OutputStatus::Immature => {
+					let mut matured = (*out).clone();
+					matured.status = OutputStatus::Unspent;
+					matured.is_spendable(at_height, coinbase_maturity, 0)
+				}
+				_ => false,
+			})
+			.map(|out| out.value)
+			.sum()
+	}
+
+	/// Select a subset of unspent outputs covering the provided amount,
+	/// ordering the candidate outputs according to the given strategy before
+	/// accumulating them.
+	pub fn select_with_strategy(
+		&self,
+		root_key_id: keychain::Identifier,
+		amount: u64,
+		strategy: SelectionStrategy,
+	) -> (Vec<OutputData>, i64) {
+		select_from(self.unspent_outputs(&root_key_id), amount, strategy)
+	}
+
+	/// All unspent outputs derived from the provided root key that we can
+	/// spend, watch-only ones are left out.
+	fn unspent_outputs(&self, root_key_id: &keychain::Identifier) -> Vec<OutputData> {
+		self.unspent_refs(root_key_id).into_iter().cloned().collect()
+	}
+
+	/// Same as unspent_outputs, without copying them.
+	fn unspent_refs(&self, root_key_id: &keychain::Identifier) -> Vec<&OutputData> {
+		self.sorted_outputs()
+			.into_iter()
+			.filter(|out| {
+				out.root_key_id == *root_key_id
+					&& !out.watch_only
+					&& (out.status == OutputStatus::Unspent)
+				// the following will let us spend zero confirmation change outputs
+				// || (out.status == OutputStatus::Unconfirmed && out.zero_ok))
+			})
+			.collect()
+	}
+
+	/// Rebuilds the commitments of all our outputs once, mapping each of them
+	/// to the key_id of its output, so outputs reported by a node can be
+	/// matched without rebuilding anything.
+	pub fn commitment_index(
+		&self,
+		keychain: &keychain::Keychain,
+	) -> Result<HashMap<pedersen::Commitment, String>, Error> {
+		let mut index = HashMap::with_capacity(self.outputs.len());
+		for (key, out) in &self.outputs {
+			index.insert(out.commit(keychain)?, key.to_string());
+		}
+		Ok(index)
+	}
+
+	/// Pairs of outputs rebuilding to the same commitment, by key_id, the
+	/// first of each pair being the first output with that commitment in
+	/// derivation order. Only one of them could ever be spent, so any pair
+	/// is a wallet inconsistency to repair. Outputs from other roots can't be
+	/// rebuilt with this keychain and are left out.
+	pub fn find_duplicate_commitments(
+		&self,
+		keychain: &keychain::Keychain,
+	) -> Result<Vec<(String, String)>, Error> {
+		let root_key_id = keychain.root_key_id();
+		let mut seen: HashMap<pedersen::Commitment, String> = HashMap::new();
+		let mut duplicates = vec![];
+		for out in self.sorted_outputs() {
+			if out.root_key_id != root_key_id {
+				continue;
+			}
+			let key = out.key().to_string();
+			match seen.entry(out.commit(keychain)?) {
+				hash_map::Entry::Occupied(first) => duplicates.push((first.get().clone(), key)),
+				hash_map::Entry::Vacant(slot) => {
+					slot.insert(key);
+				}
+			}
+		}
+		Ok(duplicates)
+	}
+
+	/// Rebuilds the commitments of all the outputs of the provided root we
+	/// still expect to see on chain, to query the node for them in one go.
+	/// Spent and abandoned outputs are left out. Fails if any of the
+	/// commitments can't be rebuilt, rather than returning a partial list.
+	pub fn tracked_commitments(
+		&self,
+		keychain: &keychain::Keychain,
+		root_key_id: &keychain::Identifier,
+	) -> Result<Vec<pedersen::Commitment>, Error> {
+		self.sorted_outputs()
+			.into_iter()
+			.filter(|out| &out.root_key_id == root_key_id)
+			.filter(|out| match out.status {
+				OutputStatus::Spent | OutputStatus::Abandoned => false,
+				_ => true,
+			})
+			.map(|out| out.commit(keychain))
+			.collect()
+	}
+
+	/// Checks our unspent outputs against the set of unspent commitments
+	/// reported by the node at the provided tip height, marking the ones the
+	/// node doesn't have as spent. They're gone from the chain, whether spent
+	/// or orphaned by a reorg, so can't be used in a transaction anymore.
+	/// Outputs from other roots can't be rebuilt with this keychain and are
+	/// left alone.
+	pub fn reconcile(
+		&mut self,
+		node_utxos: &HashSet<pedersen::Commitment>,
+		tip_height: u64,
+		keychain: &keychain::Keychain,
+	) -> Result<ReconcileReport, Error> {
+		let root_key_id = keychain.root_key_id();
+		let mut report = ReconcileReport::default();
+		let mut gone = vec![];
+		for out in self.sorted_outputs() {
+			if out.status != OutputStatus::Unspent || out.root_key_id != root_key_id {
+				continue;
+			}
+			report.checked += 1;
+			if !node_utxos.contains(&out.commit(keychain)?) {
+				gone.push(out.key());
+			}
+		}
+		for key in &gone {
+			if let Some(out) = self.outputs.get_mut(key) {
+				out.status = OutputStatus::Spent;
+			}
+		}
+		report.spent = gone.iter().map(|key| key.to_string()).collect();
+		self.last_confirmed_height = tip_height;
+		Ok(report)
+	}
+
+	/// Health check of the outputs derived from the keychain root, making sure
+	/// each of them still rebuilds a commitment from its recorded value and
+	/// derivation index, with the key_id we recorded. Returns the key_ids of
+	/// the outputs that don't, in derivation order, empty when the wallet is
+	/// consistent. Outputs from other roots can't be checked with this
+	/// keychain and are skipped.
+	pub fn verify_commitments(
+		&self,
+		keychain: &keychain::Keychain,
+	) -> Result<Vec<String>, Error> {
+		let root_key_id = keychain.root_key_id();
+		let mut inconsistent = vec![];
+		for out in self.sorted_outputs() {
+			if out.root_key_id != root_key_id {
+				continue;
+			}
+			let key_id = keychain.derive_key_id(out.n_child)?;
+			if key_id != out.key_id || keychain.commit(out.value, &key_id).is_err() {
+				inconsistent.push(out.key_id.to_hex());
+			}
+		}
+		Ok(inconsistent)
+	}
+
+	/// JSON blob of one of our outputs, along with its commitment, so another
+	/// wallet on the same keychain can start tracking it with
+	/// import_output_json without a full merge.
+	pub fn export_output_json(
+		&self,
+		key_id: &keychain::Identifier,
+		keychain: &keychain::Keychain,
+	) -> Result<String, Error> {
+		let out = self.get_output(key_id).ok_or_else(|| {
+			Error::WalletData(format!("output {} is not tracked", key_id.to_hex()))
+		})?;
+		let blob = OutputBlob {
+			output: out.clone(),
+			commit: util::to_hex(out.commit(keychain)?.as_ref().to_vec()),
+		};
+		serde_json::to_string(&blob)
+			.map_err(|e| Error::Format(format!("Could not serialize output: {}", e)))
+	}
+
+	/// Starts tracking an output exported by another wallet, see
+	/// export_output_json. The output is only added if it derives from our
+	/// keychain root and its commitment rebuilds from its value and derivation
+	/// index, so a blob tampered with is refused. Returns the key_id of the
+	/// imported output.
+	pub fn import_output_json(
+		&mut self,
+		json: &str,
+		keychain: &keychain::Keychain,
+	) -> Result<keychain::Identifier, Error> {
+		let blob: OutputBlob = serde_json::from_str(json)
+			.map_err(|e| Error::Format(format!("invalid output: {}", e)))?;
+		let out = blob.output;
+		let key = out.key_id.to_hex();
+		if out.root_key_id != keychain.root_key_id() {
+			return Err(Error::WalletData(format!(
+				"output {} doesn't derive from our root key {}",
+				key,
+				keychain.root_key_id().to_hex(),
+			)));
+		}
+		if keychain.derive_key_id(out.n_child)? != out.key_id {
+			return Err(Error::WalletData(format!(
+				"output {} doesn't match its derivation index {}",
+				key, out.n_child,
+			)));
+		}
+		let commit = util::to_hex(out.commit(keychain)?.as_ref().to_vec());
+		if commit != blob.commit.to_lowercase() {
+			return Err(Error::WalletData(format!(
+				"output {} doesn't rebuild its commitment {} from value {} and \
+				 derivation index {}",
+				key, blob.commit, out.value, out.n_child,
+			)));
+		}
+		let key_id = out.key_id.clone();
+		self.add_output(out)?;
+		Ok(key_id)
+	}
+
+	/// Writes all our outputs as CSV, one row per output ordered by derivation
+	/// index, with a header row even when there are no outputs. Values are
+	/// given both in grins and nanogrins, labels are quoted when needed.
+	pub fn export_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+		writeln!(
+			writer,
+			"key_id,n_child,value_grin,value_nanogrin,status,height,lock_height,label"
+		)?;
+
+		let mut outputs = self.outputs.values().collect::<Vec<_>>();
+		outputs.sort_by_key(|out| (out.n_child, out.key_id.to_hex()));
+		for out in outputs {
+			writeln!(
+				writer,
+				"{},{},{},{},{},{},{},{}",
+				out.key_id,
+				out.n_child,
+				amount_to_str(out.value),
+				out.value,
+				out.status,
+				out.height,
+				out.lock_height,
+				csv_field(out.label.as_ref().map(|l| l.as_str()).unwrap_or(""))
+			)?;
+		}
+		Ok(())
+	}
+
+	/// Chain height of the node when our outputs were last checked against
+	/// it, 0 when they never were, so an empty balance can't be told from
+	/// an empty wallet.
+	pub fn last_confirmed_height(&self) -> u64 {
+		self.last_confirmed_height
+	}
+
+	/// Summary of the funds derived from the provided root key, by output
+	/// status.
+	pub fn balance(&self, root_key_id: keychain::Identifier) -> WalletBalance {
+		self.for_account(root_key_id).balance()
+	}
+
+	/// View of the outputs derived from the provided root key only, when the
+	/// wallet holds several accounts.
+	pub fn for_account(&self, root_key_id: keychain::Identifier) -> AccountView {
+		AccountView {
+			wallet_data: self,
+			root_key_id: root_key_id,
+		}
+	}
+
+	/// Next child index under the provided root key when we want to create a
+	/// new output. Always above any index of that root we know of or handed
+	/// out before, the index gets recorded as issued. Each root has its own
+	/// sequence, starting from 1.
+	pub fn next_child(&mut self, root_key_id: keychain::Identifier) -> u32 {
+		let root = root_key_id.to_hex();
+		let mut max_n = cmp::max(
+			self.last_issued_child,
+			self.issued_children.get(&root).cloned().unwrap_or(0),
+		);
+		for out in self.outputs.values() {
+			if max_n < out.n_child && out.root_key_id == root_key_id {
+				max_n = out.n_child;
+			}
+		}
+		self.issued_children.insert(root, max_n + 1);
+		max_n + 1
+	}
+}
+
+/// Read-only view of the wallet data restricted to a single account, the
+/// outputs derived from one root key, see WalletData::for_account.
+pub struct AccountView<'a> {
+	wallet_data: &'a WalletData,
+	root_key_id: keychain::Identifier,
+}
+
+impl<'a> AccountView<'a> {
+	/// Root key of the account
+	pub fn root_key_id(&self) -> &keychain::Identifier {
+		&self.root_key_id
+	}
+
+	/// All the outputs of the account, in derivation order.
+	pub fn outputs(&self) -> Vec<&'a OutputData> {
+		let root_key_id = &self.root_key_id;
+		self.wallet_data
+			.sorted_outputs()
+			.into_iter()
+			.filter(|out| out.root_key_id == *root_key_id)
+			.collect()
+	}
+
+	/// The output with the provided key_id, if it belongs to the account.
+	pub fn get_output(&self, key_id: &keychain::Identifier) -> Option<&'a OutputData> {
+		match self.wallet_data.get_output(key_id) {
+			Some(out) if out.root_key_id == self.root_key_id => Some(out),
+			_ => None,
+		}
+	}
+
+	/// Summary of the funds of the account, by output status.
+	pub fn balance(&self) -> WalletBalance {
+		let mut balance = WalletBalance::default();
+		balance.last_confirmed_height = self.wallet_data.last_confirmed_height;
+		let outputs = self.wallet_data.outputs.values();
+		for out in outputs.filter(|out| out.root_key_id == self.root_key_id) {
+			if out.watch_only {
+				if out.status != OutputStatus::Spent && out.status != OutputStatus::Abandoned {
+					balance.watch_only += out.value;
+				}
+				continue;
+			}
+			match out.status {
+				OutputStatus::Unspent => balance.unspent += out.value,
+				OutputStatus::Immature => balance.immature += out.value,
+				OutputStatus::Unconfirmed => balance.unconfirmed += out.value,
+				OutputStatus::Locked => balance.locked += out.value,
+				OutputStatus::Spent | OutputStatus::Abandoned => continue,
+			}
+			balance.total += out.value;
+		}
+		balance
+	}
+
+	/// Same as WalletData::select, among the outputs of the account.
+	pub fn select(&self, amount: u64, fee: u64) -> (Vec<OutputData>, i64) {
+		self.wallet_data.select(self.root_key_id.clone(), amount, fee)
+	}
+
+	/// Same as WalletData::select_checked, among the outputs of the account.
+	pub fn select_checked(&self, amount: u64) -> Result<(Vec<OutputData>, u64), Error> {
+		self.wallet_data.select_checked(self.root_key_id.clone(), amount)
+	}
+
+	/// Same as WalletData::select_spendable, among the outputs of the account.
+	pub fn select_spendable(
+		&self,
+		amount: u64,
+		fee: u64,
+		current_height: u64,
+		coinbase_maturity: u64,
+		min_confirmations: u64,
+	) -> (Vec<OutputData>, i64) {
+		self.wallet_data.select_spendable(
+			self.root_key_id.clone(),
+			amount,
+			fee,
+			current_height,
+			coinbase_maturity,
+			min_confirmations,
+		)
+	}
+}
+
+/// Where the wallet data is kept and how access to it is guarded, see
+/// FileBackend for the wallet.dat file and MemoryBackend.
+pub trait WalletBackend {
+	/// Takes exclusive access to the wallet data, until released.
+	fn acquire(&mut self) -> Result<(), Error>;
+
+	/// Takes access to the wallet data to only read it, until released. The
+	/// same exclusive access as acquire unless the backend can do better.
+	fn acquire_read(&mut self) -> Result<(), Error> {
+		self.acquire()
+	}
+
+	/// Gives up the access taken with acquire.
+	fn release(&mut self) -> Result<(), Error>;
+
+	/// The wallet data, a brand new one if there's none yet.
+	fn read(&self) -> Result<WalletData, Error>;
+
+	/// Replaces the wallet data.
+	fn write(&mut self, wallet_data: &mut WalletData) -> Result<(), Error>;
+}
+
+impl<'a, B: WalletBackend> WalletBackend for &'a mut B {
+	fn acquire(&mut self) -> Result<(), Error> {
+		(**self).acquire()
+	}
+
+	fn acquire_read(&mut self) -> Result<(), Error> {
+		(**self).acquire_read()
+	}
+
+	fn release(&mut self) -> Result<(), Error> {
+		(**self).release()
+	}
+
+	fn read(&self) -> Result<WalletData, Error> {
+		(**self).read()
+	}
+
+	fn write(&mut self, wallet_data: &mut WalletData) -> Result<(), Error> {
+		(**self).write(wallet_data)
+	}
+}
+
+/// Wallet data kept in the wallet.dat file of a directory, guarded by a lock
+/// file next to it, see WalletData::with_wallet.
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+	pub data_file_dir: String,
+	pub lock_config: LockConfig,
+	/// Back up the wallet data before overwriting it, see backup_data_file
+	pub keep_backups: bool,
+	/// Format the wallet data gets written in
+	pub data_format: WalletFormat,
+	/// Sync the wallet data to disk after writing it
+	pub sync_writes: bool,
+	/// Whether we created the lock file, to remove it on release
+	holds_lock_file: bool,
+	/// Key of the in-process lock we hold on the data directory, see
+	/// acquire_dir_lock
+	dir_lock_key: Option<String>,
+}
+
+impl FileBackend {
+	/// Backend for the wallet data in the provided directory, with the
+	/// default settings of WalletConfig.
+	pub fn new(data_file_dir: &str, lock_config: &LockConfig) -> FileBackend {
+		FileBackend {
+			data_file_dir: data_file_dir.to_string(),
+			lock_config: lock_config.clone(),
+			keep_backups: false,
+			data_format: WalletFormat::default(),
+			sync_writes: true,
+			holds_lock_file: false,
+			dir_lock_key: None,
+		}
+	}
+
+	/// Backend for the wallet data as configured, in the directory given by
+	/// resolve_data_file_dir.
+	pub fn from_config(config: &WalletConfig) -> FileBackend {
+		FileBackend {
+			data_file_dir: resolve_data_file_dir(&config.data_file_dir),
+			lock_config: config.lock.clone(),
+			keep_backups: config.keep_backups,
+			data_format: config.data_format,
+			sync_writes: config.sync_writes,
+			holds_lock_file: false,
+			dir_lock_key: None,
+		}
+	}
+
+	fn data_file_path(&self) -> String {
+		wallet_file_path(&self.data_file_dir, DAT_FILE)
+	}
+
+	fn lock_file_path(&self) -> String {
+		wallet_file_path(&self.data_file_dir, LOCK_FILE)
+	}
+}
+
+impl WalletBackend for FileBackend {
+	fn acquire(&mut self) -> Result<(), Error> {
+		// the lock file and the wallet data need to be written
+		check_writable_dir(&self.data_file_dir)?;
+		// threads of this process wait on each other before trying the lock
+		// file, which can't tell them apart
+		let key = acquire_dir_lock(&self.data_file_dir, &self.lock_config)?;
+		let res = acquire_lock(&self.lock_file_path(), &self.lock_config);
+		match res {
+			Ok(()) => self.dir_lock_key = Some(key),
+			Err(_) => release_dir_lock(&key),
+		}
+		self.holds_lock_file = res.is_ok();
+		res
+	}
+
+	/// Takes the lock file when the data directory can be written, without
+	/// probing it first. Otherwise nobody can write the wallet data there
+	/// and, as it's always replaced atomically, it's read without the lock.
+	fn acquire_read(&mut self) -> Result<(), Error> {
+		let key = acquire_dir_lock(&self.data_file_dir, &self.lock_config)?;
+		self.holds_lock_file = false;
+		self.dir_lock_key = Some(key);
+		if !Path::new(&self.data_file_dir).exists() {
+			return Ok(());
+		}
+		match acquire_lock(&self.lock_file_path(), &self.lock_config) {
+			Ok(()) => {
+				self.holds_lock_file = true;
+				Ok(())
+			}
+			Err(Error::Io(ref e)) if e.kind() != io::ErrorKind::AlreadyExists => {
+				debug!(LOGGER, "Reading the wallet without a lock file: {}", e);
+				Ok(())
+			}
+			Err(e) => {
+				if let Some(key) = self.dir_lock_key.take() {
+					release_dir_lock(&key);
+				}
+				Err(e)
+			}
+		}
+	}
+
+	fn release(&mut self) -> Result<(), Error> {
+		let res = if self.holds_lock_file {
+			release_lock(&self.lock_file_path())
+		} else {
+			Ok(())
+		};
+		self.holds_lock_file = false;
+		if let Some(key) = self.dir_lock_key.take() {
+			release_dir_lock(&key);
+		}
+		res
+	}
+
+	fn read(&self) -> Result<WalletData, Error> {
+		WalletData::read_or_create(&self.data_file_path())
+	}
+
+	fn write(&mut self, wallet_data: &mut WalletData) -> Result<(), Error> {
+		let data_file_path = self.data_file_path();
+		if self.keep_backups {
+			backup_data_file(&data_file_path)?;
+		}
+		wallet_data.write_file(&data_file_path, self.data_format, self.sync_writes)
+	}
+}
+
+/// Wallet data only kept in memory, for throwaway wallets and tests. Counts
+/// how many times it was written.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+	wallet_data: Option<WalletData>,
+	writes: usize,
+}
+
+impl MemoryBackend {
+	/// Backend starting with no wallet data.
+	pub fn new() -> MemoryBackend {
+		MemoryBackend::default()
+	}
+
+	/// Backend starting with the provided wallet data.
+	pub fn with_data(wallet_data: WalletData) -> MemoryBackend {
+		MemoryBackend {
+			wallet_data: Some(wallet_data),
+			writes: 0,
+		}
+	}
+
+	/// The wallet data last written, if any.
+	pub fn wallet_data(&self) -> Option<&WalletData> {
+		self.wallet_data.as_ref()
+	}
+
+	/// How many times the wallet data was written.
+	pub fn writes(&self) -> usize {
+		self.writes
+	}
+}
+
+impl WalletBackend for MemoryBackend {
+	fn acquire(&mut self) -> Result<(), Error> {
+		// borrowing rules already make our access exclusive
+		Ok(())
+	}
+
+	fn release(&mut self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn read(&self) -> Result<WalletData, Error> {
+		match self.wallet_data {
+			Some(ref wallet_data) => Ok(wallet_data.clone()),
+			None => Ok(WalletData::new()),
+		}
+	}
+
+	fn write(&mut self, wallet_data: &mut WalletData) -> Result<(), Error> {
+		wallet_data.version = WALLET_DATA_VERSION;
+		self.wallet_data = Some(wallet_data.clone());
+		self.writes += 1;
+		Ok(())
+	}
+}
+
+/// Wallet data read from a backend with exclusive access to it, see
+/// WalletData::open_session. The access is held until the session is
+/// committed, or dropped in which case the changes made to the wallet data
+/// are lost.
+pub struct WalletSession<B: WalletBackend = FileBackend> {
+	wallet_data: WalletData,
+	backend: B,
+	locked: bool,
+}
+
+impl<B: WalletBackend> WalletSession<B> {
+	/// Runs an operation on the wallet data, nothing gets written until
+	/// commit.
+	pub fn run<T, F>(&mut self, f: F) -> T
+		where F: FnOnce(&mut WalletData) -> T
+	{
+		f(&mut self.wallet_data)
+	}
+
+	/// Writes the wallet data back, then releases the lock, even if the write
+	/// failed.
+	pub fn commit(mut self) -> Result<(), Error> {
+		let res = self.backend.write(&mut self.wallet_data);
+		self.locked = false;
+		self.backend.release()?;
+		res
+	}
+}
+
+impl<B: WalletBackend> Drop for WalletSession<B> {
+	fn drop(&mut self) {
+		if self.locked {
+			if let Err(e) = self.backend.release() {
+				warn!(LOGGER, "Could not release the wallet lock: {}", e);
+			}
+		}
+	}
+}
+
+/// How candidate outputs get ordered when selecting which ones to spend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+	/// Spend the smallest outputs first, sweeping dust
+	SmallestFirst,
+	/// Spend the largest outputs first, minimizing the number of inputs
+	LargestFirst,
+	/// Spend the outputs confirmed the longest ago first, the first to have
+	/// matured among coinbase outputs
+	OldestFirst,
+	/// Look for an exact match to avoid creating change, falling back to
+	/// spending outputs in derivation order
+	Auto,
+}
+
+/// Fixed-point search for the fee of the transaction spending the outputs
+/// the provided selection picks to cover an amount plus fee, with the given
+/// number of outputs. As the fee depends on the number of inputs selected, we
+/// iterate until the fee of the selected inputs is covered. Returns the
+/// selected outputs and their fee.
+fn select_with_fee<T, F>(
+	amount: u64,
+	base_fee: Option<u64>,
+	num_outputs: usize,
+	mut select: F,
+) -> Result<(Vec<T>, u64), Error>
+where
+	F: FnMut(u64) -> Result<Vec<T>, Error>,
+{
+	let mut fee = tx_fee(1, num_outputs, base_fee);
+	loop {
+		let needed_total = amount.checked_add(fee).ok_or_else(|| {
+			Error::WalletData(format!("amount of {} plus fee of {} overflows", amount, fee))
+		})?;
+		let coins = select(needed_total)?;
+		let needed = tx_fee(coins.len(), num_outputs, base_fee);
+		if needed <= fee {
+			return Ok((coins, needed));
+		}
+		// the fee only ever grows here and can only take so many values, so
+		// this converges
+		fee = needed;
+	}
+}
+
+/// Selection of estimate_and_select, with the provided selection covering a
+/// total or failing with NotEnoughFunds.
+fn estimate_with_fee<F>(
+	amount: u64,
+	base_fee: Option<u64>,
+	num_change_outputs: usize,
+	dust_limit: u64,
+	select: F,
+) -> Result<(Vec<OutputData>, u64, i64), Error>
+where
+	F: FnMut(u64) -> Result<Vec<OutputData>, Error>,
+{
+	if amount == 0 {
+		return Err(Error::WalletData("amount must be greater than zero".to_string()));
+	}
+	let num_outputs = num_change_outputs.saturating_add(1);
+	let (coins, fee) = select_with_fee(amount, base_fee, num_outputs, select)?;
+	let total: u64 = coins.iter().map(|out| out.value).sum();
+	let change = total - amount - fee;
+	// without change outputs the transaction gets lighter, what's left
+	// over once the amount is paid has to cover its own fee
+	if change <= dust_limit && total - amount >= tx_fee(coins.len(), 1, base_fee) {
+		return Ok((coins, total - amount, 0));
+	}
+	split_change(change, num_change_outputs)?;
+	Ok((coins, fee, change as i64))
+}
+
+/// Selects among the candidate outputs to cover the provided amount, ordering
+/// them according to the strategy. Returns the selected outputs and the
+/// change, negative if the candidates can't cover the amount. Candidates and
+/// outcome get logged at debug level, to explain surprising selections. Works
+/// on our outputs or references to them alike.
+fn select_from<T: Borrow<OutputData> + Clone>(
+	mut candidates: Vec<T>,
+	amount: u64,
+	strategy: SelectionStrategy,
+) -> (Vec<T>, i64) {
+	debug!(
+		LOGGER,
+		"selecting outputs for {} with {:?} among {} candidates: {}",
+		amount,
+		strategy,
+		candidates.len(),
+		describe_outputs(&candidates)
+	);
+	let to_spend = match strategy {
+		SelectionStrategy::SmallestFirst => {
+			candidates.sort_by_key(|out| (out.borrow().value, out.borrow().n_child));
+			accumulate(candidates, amount)
+		}
+		SelectionStrategy::LargestFirst => {
+			candidates.sort_by(|a, b| {
+				let (a, b) = (a.borrow(), b.borrow());
+				b.value.cmp(&a.value).then(a.n_child.cmp(&b.n_child))
+			});
+			accumulate(candidates, amount)
+		}
+		SelectionStrategy::OldestFirst => {
+			candidates.sort_by_key(|out| (out.borrow().height, out.borrow().n_child));
+			accumulate(candidates, amount)
+		}
+		SelectionStrategy::Auto => {
+			match branch_and_bound(&candidates, amount) {
+				Some(exact) => exact,
+				None => {
+					candidates.sort_by_key(|out| (out.borrow().n_child, out.borrow().value));
+					accumulate(candidates, amount)
+				}
+			}
+		}
+	};
+	let input_total: u64 = to_spend.iter().map(|out| out.borrow().value).sum();
+
+	// TODO - clean up our handling of i64 vs u64 so we are consistent
+	let change = (input_total as i64) - (amount as i64);
+	debug!(LOGGER, "{}", selection_outcome(amount, &to_spend, change));
+	(to_spend, change)
+}
+
+/// Lists outputs for the logs as key_id:value, nothing that could help
+/// rebuild their blinding factors.
+fn describe_outputs<T: Borrow<OutputData>>(outputs: &[T]) -> String {
+	outputs
+		.iter()
+		.map(|out| format!("{}:{}", out.borrow().key_id, out.borrow().value))
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Log line reporting the outputs selected for an amount and the change left.
+fn selection_outcome<T: Borrow<OutputData>>(amount: u64, selected: &[T], change: i64) -> String {
+	format!(
+		"selected {} outputs for {}, change {}: {}",
+		selected.len(),
+		amount,
+		change,
+		describe_outputs(selected)
+	)
+}
+
+/// Accumulates the provided outputs in order until their total covers the
+/// target. Returns all of them if it can't.
+fn accumulate<T: Borrow<OutputData>>(candidates: Vec<T>, target: u64) -> Vec<T> {
+	let mut to_spend = vec![];
+	let mut input_total = 0;
+	for out in candidates {
+		input_total += out.borrow().value;
+		to_spend.push(out);
+		if input_total >= target {
+			break;
+		}
+	}
+	to_spend
+}
+
+/// Maximum number of branches explored by the branch-and-bound search before
+/// giving up on finding an exact match.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Depth-first search for a subset of the provided outputs whose values sum
+/// exactly to the target. Outputs are explored largest first, pruning any
+/// branch that overshoots the target or can't reach it anymore with what's
+/// left. Returns None if no exact match was found within BNB_MAX_TRIES.
+fn branch_and_bound<T: Borrow<OutputData> + Clone>(
+	candidates: &Vec<T>,
+	target: u64,
+) -> Option<Vec<T>> {
+	let mut sorted = candidates.clone();
+	sorted.sort_by(|a, b| {
+		let (a, b) = (a.borrow(), b.borrow());
+		b.value.cmp(&a.value).then(a.n_child.cmp(&b.n_child))
+	});
+	let values = sorted.iter().map(|out| out.borrow().value).collect::<Vec<_>>();
+
+	// remaining[i] is the total value still available from index i onward
+	let mut remaining = vec![0u64; values.len() + 1];
+	for i in (0..values.len()).rev() {
+		remaining[i] = remaining[i + 1].saturating_add(values[i]);
+	}
+
+	if target == 0 {
+		return None;
+	}
+
+	// indices of the outputs included in the current branch, in increasing
+	// order, going depth-first and trying inclusion before exclusion
+	let mut selected: Vec<usize> = vec![];
+	let mut current = 0;
+	let mut index = 0;
+	for _ in 0..BNB_MAX_TRIES {
+		if current == target {
+			return Some(selected.into_iter().map(|i| sorted[i].clone()).collect());
+		}
+		if index < values.len() && current.saturating_add(remaining[index]) >= target {
+			if values[index] <= target - current {
+				selected.push(index);
+				current += values[index];
+			}
+			index += 1;
+			continue;
+		}
+		// dead end, drop the last included output and explore the branch
+		// without it
+		match selected.pop() {
+			Some(last) => {
+				current -= values[last];
+				index = last + 1;
+			}
+			None => return None,
+		}
+	}
+	None
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break,
+/// doubling any quote inside.
+fn csv_field(field: &str) -> String {
+	if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_owned()
+	}
+}
+
+/// Directory the wallet data is kept in, in order of precedence:
+/// 1. the GRIN_WALLET_DATA_DIR environment variable, when set and not empty
+/// 2. the configured data_file_dir
+pub fn resolve_data_file_dir(data_file_dir: &str) -> String {
+	pick_data_file_dir(data_file_dir, env::var(DATA_DIR_ENV_VAR).ok())
+}
+
+/// Same as resolve_data_file_dir, with the value of the environment variable
+/// provided.
+fn pick_data_file_dir(data_file_dir: &str, env_dir: Option<String>) -> String {
+	match env_dir {
+		Some(ref dir) if !dir.is_empty() => dir.clone(),
+		_ => data_file_dir.to_string(),
+	}
+}
+
+/// Creates the wallet data directory if it doesn't exist and makes sure we can
+/// write in it, by creating and removing a probe file. Catches read-only
+/// mounts before we get to the lock file.
+fn check_writable_dir(data_file_dir: &str) -> Result<(), Error> {
+	let not_writable = || {
+		Error::WalletData(format!("data directory is not writable: {}", data_file_dir))
+	};
+
+	fs::create_dir_all(data_file_dir).map_err(|why| {
+		info!(LOGGER, "! {:?}", why.kind());
+		not_writable()
+	})?;
+
+	let probe_path = wallet_file_path(
+		data_file_dir,
+		&format!(
+			".wallet_probe.{}.{}",
+			process::id(),
+			TMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+		),
+	);
+	OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&probe_path)
+		.map_err(|_| not_writable())?;
+	fs::remove_file(&probe_path).map_err(|_| not_writable())
+}
+
+/// Path of a file in the wallet data directory. Joined as paths rather than
+/// strings, so a directory given with a trailing separator or with the other
+/// separator on Windows still gives a well formed path.
+fn wallet_file_path(data_file_dir: &str, file_name: &str) -> String {
+	Path::new(data_file_dir).join(file_name).to_string_lossy().into_owned()
+}
+
+lazy_static! {
+	/// Data directories whose wallet lock is held by a thread of this process,
+	/// along with the condition the others wait on until it gets released.
+	static ref DIR_LOCKS: (Mutex<HashSet<String>>, Condvar) =
+		(Mutex::new(HashSet::new()), Condvar::new());
+}
+
+/// Key of a data directory in DIR_LOCKS, its canonical path when it can be
+/// resolved so different spellings of the same directory share a lock.
+fn dir_lock_key(data_file_dir: &str) -> String {
+	match fs::canonicalize(data_file_dir) {
+		Ok(path) => path.to_string_lossy().into_owned(),
+		Err(_) => data_file_dir.to_string(),
+	}
+}
+
+/// Takes the in-process lock of a data directory, waiting for another thread
+/// holding it to release it with the same retries and backoff as the lock
+/// file. Being woken up only to see another waiter take the lock first
+/// doesn't count as a retry. Returns the key the lock is held under, to
+/// release it by even if the directory resolves differently by then.
+fn acquire_dir_lock(data_file_dir: &str, lock_config: &LockConfig) -> Result<String, Error> {
+	let key = dir_lock_key(data_file_dir);
+	let &(ref held, ref released) = &*DIR_LOCKS;
+	let mut held = held.lock().unwrap();
+	let mut retries = 0;
+	let mut backoff = lock_config.backoff_ms;
+	while held.contains(&key) {
+		if retries >= lock_config.max_retries {
+			return Err(Error::WalletData(format!(
+				"Could not lock the wallet in {}, another thread is using it.",
+				data_file_dir
+			)));
+		}
+		let (guard, wait) = released
+			.wait_timeout(held, time::Duration::from_millis(backoff))
+			.unwrap();
+		held = guard;
+		if wait.timed_out() {
+			retries += 1;
+			backoff = backoff.saturating_mul(lock_config.backoff_multiplier);
+		}
+	}
+	held.insert(key.clone());
+	Ok(key)
+}
+
+/// Releases the in-process lock held under the provided key, waking up the
+/// threads waiting for it.
+fn release_dir_lock(key: &str) {
+	let &(ref held, ref released) = &*DIR_LOCKS;
+	held.lock().unwrap().remove(key);
+	released.notify_all();
+}
+
+/// Deletes the wallet lock file taken by acquire_lock.
+fn release_lock(lock_file_path: &str) -> Result<(), Error> {
+	fs::remove_file(lock_file_path).map_err(|e| path_io_error(lock_file_path, e))
+}
+
+/// Creates the wallet lock file, recording our PID and the current time in
+/// it. If the lock file already exists, we sleep and retry as configured,
+/// unless the existing lock is stale in which case it gets removed and we take
+/// over. A lock file that can't be created at all, in a directory we can't
+/// write in, fails right away with an Io error.
+fn acquire_lock(lock_file_path: &str, lock_config: &LockConfig) -> Result<(), Error> {
+	let mut retries = 0;
+	let mut backoff = lock_config.backoff_ms;
+	loop {
+		let result = OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(lock_file_path)
+			.and_then(|mut lock_file| {
+				write!(lock_file, "{} {}", process::id(), now_secs())
+			});
+		match result {
+			Ok(_) => {
+				return Ok(());
+			}
+			Err(e) => {
+				// no point waiting for a lock we can't ever create
+				let exists = Path::new(lock_file_path).exists();
+				if e.kind() != io::ErrorKind::AlreadyExists && !exists {
+					return Err(path_io_error(lock_file_path, e));
+				}
+				let e = Error::WalletData(format!(
+					"Could not create wallet lock file. Either \
+				some other process is using the wallet or there's a write access issue."
+				));
+				if let Some(stale_content) = stale_lock_content(lock_file_path, lock_config) {
+					warn!(LOGGER, "Removing stale wallet lock file {}", lock_file_path);
+					if remove_stale_lock(lock_file_path, &stale_content) {
+						continue;
+					}
+				}
+				if retries >= lock_config.max_retries {
+					return Err(e);
+				}
+				debug!(
+					LOGGER,
+					"failed to obtain wallet.lock, retries - {}, sleeping {}ms",
+					retries,
+					backoff
+				);
+				retries += 1;
+				thread::sleep(time::Duration::from_millis(backoff));
+				backoff = backoff.saturating_mul(lock_config.backoff_multiplier);
+			}
+		}
+	}
+}
+
+/// Whether an existing lock file was left behind, either because the process
+/// that created it is gone or because it's older than the configured
+/// staleness threshold. Lock files we can't parse (still being written or
+/// created by an older version) are only judged on their modification time.
+/// Returns the content of the lock when stale, to check it's still the same
+/// lock when removing it.
+fn stale_lock_content(lock_file_path: &str, lock_config: &LockConfig) -> Option<String> {
+	let content = fs::read_to_string(lock_file_path).unwrap_or(String::new());
+	let mut parts = content.split_whitespace();
+	let pid = parts.next().and_then(|p| p.parse::<u32>().ok());
+	let timestamp = parts.next().and_then(|t| t.parse::<u64>().ok());
+
+	if let Some(pid) = pid {
+		if !process_alive(pid) {
+			return Some(content);
+		}
+	}
+	let timestamp = match timestamp {
+		Some(t) => t,
+		None => {
+			match fs::metadata(lock_file_path).and_then(|m| m.modified()) {
+				Ok(modified) => secs_since_epoch(modified),
+				Err(_) => return None,
+			}
+		}
+	};
+	if now_secs().saturating_sub(timestamp) > lock_config.stale_after_secs {
+		Some(content)
+	} else {
+		None
+	}
+}
+
+/// Removes a lock file we found stale with the provided content. Another
+/// process may have reclaimed it and created its own lock since we looked,
+/// so the lock is first moved aside and only removed if it's still the one
+/// we inspected, a fresh lock is put back instead. Returns whether the lock
+/// is gone and creating ours can be tried again right away.
+fn remove_stale_lock(lock_file_path: &str, stale_content: &str) -> bool {
+	let aside_path = format!("{}.stale.{}", lock_file_path, process::id());
+	if let Err(e) = fs::rename(lock_file_path, &aside_path) {
+		// already gone if another process removed it before us
+		return e.kind() == io::ErrorKind::NotFound;
+	}
+	let content = fs::read_to_string(&aside_path).unwrap_or(String::new());
+	if content != stale_content {
+		// hard linking fails rather than overwrite a lock created meanwhile
+		if let Err(e) = fs::hard_link(&aside_path, lock_file_path) {
+			warn!(LOGGER, "Could not restore wallet lock file {}: {}", lock_file_path, e);
+		}
+		let _ = fs::remove_file(&aside_path);
+		return false;
+	}
+	fs::remove_file(&aside_path).is_ok()
+}
+
+/// Whether a process with the given PID is still running. Only checked on
+/// Linux through procfs, elsewhere we assume it is and only go by the age of
+/// the lock.
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+	Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_alive(_pid: u32) -> bool {
+	true
+}
+
+fn now_secs() -> u64 {
+	secs_since_epoch(time::SystemTime::now())
+}
+
+fn secs_since_epoch(t: time::SystemTime) -> u64 {
+	t.duration_since(time::UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+/// Writes a file through the provided closure into a uniquely named
+/// temporary file next to it, then renames it over the target path, which is
+/// atomic on the same filesystem. On failure the temporary file is removed
+/// and the original file left untouched. With sync, the temporary file is
+/// synced to disk before the rename and the directory after it, so once we
+/// return the new content survives a crash. Otherwise the OS may keep the
+/// write in its cache for a while.
+fn write_atomic<F>(file_path: &str, sync: bool, f: F) -> Result<(), Error>
+where
+	F: FnOnce(&mut File) -> Result<(), Error>,
+{
+	let tmp_path = format!(
+		"{}.{}.{}.tmp",
+		file_path,
+		process::id(),
+		TMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+	);
+	let res = File::create(&tmp_path)
+		.map_err(|e| path_io_error(&tmp_path, e))
+		.and_then(|mut tmp_file| {
+			f(&mut tmp_file)?;
+			if sync {
+				tmp_file.sync_all().map_err(|e| path_io_error(&tmp_path, e))?;
+			}
+			Ok(())
+		})
+		.and_then(|_| fs::rename(&tmp_path, file_path).map_err(|e| path_io_error(file_path, e)));
+	if res.is_err() {
+		let _ = fs::remove_file(&tmp_path);
+		return res;
+	}
+	if sync {
+		sync_parent_dir(file_path)?;
+	}
+	Ok(())
+}
+
+/// Syncs the directory holding the provided file, making a rename into it
+/// durable.
+#[cfg(unix)]
+fn sync_parent_dir(file_path: &str) -> Result<(), Error> {
+	let dir = match Path::new(file_path).parent() {
+		Some(dir) if !dir.as_os_str().is_empty() => dir,
+		_ => Path::new("."),
+	};
+	File::open(dir)
+		.and_then(|dir_file| dir_file.sync_all())
+		.map_err(|e| path_io_error(&dir.to_string_lossy(), e))
+}
+
+/// Directories can't be opened, let alone synced, on other platforms where
+/// the rename gets committed along with the file.
+#[cfg(not(unix))]
+fn sync_parent_dir(_file_path: &str) -> Result<(), Error> {
+	Ok(())
+}
+
+/// First line of the streaming wallet data format, everything but the
+/// outputs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StreamHeader {
+	version: u32,
+	#[serde(default)]
+	last_issued_child: u32,
+	#[serde(default)]
+	issued_children: HashMap<String, u32>,
+	#[serde(default)]
+	last_confirmed_height: u64,
+	#[serde(default)]
+	tx_log: Vec<TxLogEntry>,
+	#[serde(default)]
+	tx_log_complete: bool,
+}
+
+/// Reads a streaming format wallet data file line by line, passing each
+/// output to the provided function as it gets parsed. Blank lines are
+/// skipped. Returns the header.
+fn read_stream<F>(data_file_path: &str, mut f: F) -> Result<StreamHeader, Error>
+where
+	F: FnMut(OutputData) -> Result<(), Error>,
+{
+	let data_file = File::open(data_file_path).map_err(|e| path_io_error(data_file_path, e))?;
+	let mut lines = BufReader::new(data_file).lines();
+	let line_error = |n: usize, e: serde_json::Error| {
+		corrupt_error(&format!("{} line {}", data_file_path, n), e)
+	};
+
+	let header: StreamHeader = match lines.next() {
+		Some(line) => {
+			let line = line.map_err(|e| path_io_error(data_file_path, e))?;
+			serde_json::from_str(&line).map_err(|e| line_error(1, e))?
+		}
+		None => {
+			return Err(Error::WalletData(
+				format!("Error reading {}: missing header line", data_file_path),
+			))
+		}
+	};
+	if header.version > WALLET_DATA_VERSION {
+		return Err(Error::WalletData(format!(
+			"Wallet data version {} is more recent than the supported version {}",
+			header.version,
+			WALLET_DATA_VERSION
+		)));
+	}
+
+	for (n, line) in lines.enumerate() {
+		let line = line.map_err(|e| path_io_error(data_file_path, e))?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		let out: OutputData = serde_json::from_str(&line).map_err(|e| line_error(n + 2, e))?;
+		f(out)?;
+	}
+	Ok(header)
+}
+
+/// Number of previous versions of the wallet data kept around when backups
+/// are enabled
+const BACKUP_GENERATIONS: usize = 2;
+
+/// Path of a wallet data backup, generation 0 being the most recent.
+fn backup_path(data_file_path: &str, generation: usize) -> String {
+	if generation == 0 {
+		format!("{}.bak", data_file_path)
+	} else {
+		format!("{}.bak.{}", data_file_path, generation)
+	}
+}
+
+/// Copies the current wallet data file to wallet.dat.bak before it gets
+/// overwritten, shifting the previous backups by one generation and dropping
+/// the oldest. Nothing to do if there's no wallet data file yet.
+fn backup_data_file(data_file_path: &str) -> Result<(), Error> {
+	if !Path::new(data_file_path).exists() {
+		return Ok(());
+	}
+	for generation in (1..BACKUP_GENERATIONS).rev() {
+		let older = backup_path(data_file_path, generation - 1);
+		if Path::new(&older).exists() {
+			let newer = backup_path(data_file_path, generation);
+			fs::rename(&older, &newer).map_err(|e| path_io_error(&newer, e))?;
+		}
+	}
+	let backup = backup_path(data_file_path, 0);
+	fs::copy(data_file_path, &backup).map_err(|e| path_io_error(&backup, e))?;
+	Ok(())
+}
+
+/// Writes an optional string as a presence flag followed by the string.
+fn write_opt_string<W: ser::Writer>(writer: &mut W, s: &Option<String>) -> Result<(), ser::Error> {
+	match *s {
+		Some(ref s) => {
+			writer.write_u8(1)?;
+			writer.write_bytes(s)
+		}
+		None => writer.write_u8(0),
+	}
+}
+
+/// Reads an optional string written by write_opt_string.
+fn read_opt_string(reader: &mut ser::Reader) -> Result<Option<String>, ser::Error> {
+	match reader.read_u8()? {
+		0 => Ok(None),
+		1 => {
+			let bytes = reader.read_vec()?;
+			String::from_utf8(bytes).map(Some).map_err(|_| ser::Error::CorruptedData)
+		}
+		_ => Err(ser::Error::CorruptedData),
+	}
+}
+
+/// Writes an optional u64 as a presence flag followed by the value.
+fn write_opt_u64<W: ser::Writer>(writer: &mut W, n: Option<u64>) -> Result<(), ser::Error> {
+	match n {
+		Some(n) => {
+			writer.write_u8(1)?;
+			writer.write_u64(n)
+		}
+		None => writer.write_u8(0),
+	}
+}
+
+/// Reads an optional u64 written by write_opt_u64.
+fn read_opt_u64(reader: &mut ser::Reader) -> Result<Option<u64>, ser::Error> {
+	match reader.read_u8()? {
+		0 => Ok(None),
+		1 => reader.read_u64().map(Some),
+		_ => Err(ser::Error::CorruptedData),
+	}
+}
+
+fn read_bool(reader: &mut ser::Reader) -> Result<bool, ser::Error> {
+	match reader.read_u8()? {
+		0 => Ok(false),
+		1 => Ok(true),
+		_ => Err(ser::Error::CorruptedData),
+	}
+}
+
+impl Writeable for OutputData {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.root_key_id.write(writer)?;
+		self.key_id.write(writer)?;
+		writer.write_u32(self.n_child)?;
+		writer.write_u64(self.value)?;
+		writer.write_u8(match self.status {
+			OutputStatus::Unconfirmed => 0,
+			OutputStatus::Unspent => 1,
+			OutputStatus::Immature => 2,
+			OutputStatus::Locked => 3,
+			OutputStatus::Spent => 4,
+			OutputStatus::Abandoned => 5,
+		})?;
+		writer.write_u64(self.height)?;
+		writer.write_u64(self.lock_height)?;
+		writer.write_u8(self.zero_ok as u8)?;
+		write_opt_string(writer, &self.tx_hash)?;
+		write_opt_string(writer, &self.spent_in_tx)?;
+		write_opt_u64(writer, self.locked_until)?;
+		write_opt_string(writer, &self.label)?;
+		writer.write_u8(self.watch_only as u8)?;
+		writer.write_u8(self.is_coinbase as u8)
+	}
+}
+
+impl Readable for OutputData {
+	fn read(reader: &mut ser::Reader) -> Result<OutputData, ser::Error> {
+		let root_key_id = keychain::Identifier::read(reader)?;
+		let key_id = keychain::Identifier::read(reader)?;
+		let n_child = reader.read_u32()?;
+		let value = reader.read_u64()?;
+		let status = match reader.read_u8()? {
+			0 => OutputStatus::Unconfirmed,
+			1 => OutputStatus::Unspent,
+			2 => OutputStatus::Immature,
+			3 => OutputStatus::Locked,
+			4 => OutputStatus::Spent,
+			5 => OutputStatus::Abandoned,
+			_ => return Err(ser::Error::CorruptedData),
+		};
+		Ok(OutputData {
+			root_key_id: root_key_id,
+			key_id: key_id,
+			n_child: n_child,
+			value: value,
+			status: status,
+			height: reader.read_u64()?,
+			lock_height: reader.read_u64()?,
+			zero_ok: read_bool(reader)?,
+			tx_hash: read_opt_string(reader)?,
+			spent_in_tx: read_opt_string(reader)?,
+			locked_until: read_opt_u64(reader)?,
+			label: read_opt_string(reader)?,
+			watch_only: read_bool(reader)?,
+			is_coinbase: read_bool(reader)?,
+		})
+	}
+}
+
+impl Writeable for TxLogEntry {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.id)?;
+		writer.write_u8(match self.tx_type {
+			TxLogEntryType::Sent => 0,
+			TxLogEntryType::Received => 1,
+			TxLogEntryType::Coinbase => 2,
+		})?;
+		write_opt_string(writer, &self.tx_hash)?;
+		writer.write_u64(self.amount_credited)?;
+		writer.write_u64(self.amount_debited)?;
+		writer.write_u64(self.fee)?;
+		writer.write_u64(self.timestamp)?;
+		write_opt_u64(writer, self.confirmed_height)
+	}
+}
+
+impl Readable for TxLogEntry {
+	fn read(reader: &mut ser::Reader) -> Result<TxLogEntry, ser::Error> {
+		let id = reader.read_u32()?;
+		let tx_type = match reader.read_u8()? {
+			0 => TxLogEntryType::Sent,
+			1 => TxLogEntryType::Received,
+			2 => TxLogEntryType::Coinbase,
+			_ => return Err(ser::Error::CorruptedData),
+		};
+		Ok(TxLogEntry {
+			id: id,
+			tx_type: tx_type,
+			tx_hash: read_opt_string(reader)?,
+			amount_credited: reader.read_u64()?,
+			amount_debited: reader.read_u64()?,
+			fee: reader.read_u64()?,
+			timestamp: reader.read_u64()?,
+			confirmed_height: read_opt_u64(reader)?,
+		})
+	}
+}
+
+/// Child indexes issued by root key, following the body of the binary wallet
+/// data format since its version 2. Written ordered by root key.
+struct BinIssuedChildren(HashMap<String, u32>);
+
+impl Writeable for BinIssuedChildren {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let mut roots = self.0.iter().collect::<Vec<_>>();
+		roots.sort();
+		writer.write_u64(roots.len() as u64)?;
+		for (root, &n_child) in roots {
+			writer.write_bytes(root)?;
+			writer.write_u32(n_child)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BinIssuedChildren {
+	fn read(reader: &mut ser::Reader) -> Result<BinIssuedChildren, ser::Error> {
+		let mut issued_children = HashMap::new();
+		for _ in 0..reader.read_u64()? {
+			let root = reader.read_vec()?;
+			let root = String::from_utf8(root).map_err(|_| ser::Error::CorruptedData)?;
+			issued_children.insert(root, reader.read_u32()?);
+		}
+		Ok(BinIssuedChildren(issued_children))
+	}
+}
+
+/// Binary wallet data format: magic bytes and format version, the fields,
+/// then the child indexes issued by root key since version 2, the last
+/// confirmed height since version 3 and whether the history is complete
+/// since version 4. Outputs are written ordered by
+/// derivation index and keyed back by their key id when read.
+impl Writeable for WalletData {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&WALLET_BIN_MAGIC)?;
+		writer.write_u8(WALLET_BIN_VERSION)?;
+		writer.write_u32(self.version)?;
+		writer.write_u32(self.last_issued_child)?;
+		let outputs = self.sorted_outputs();
+		writer.write_u64(outputs.len() as u64)?;
+		for out in outputs {
+			out.write(writer)?;
+		}
+		writer.write_u64(self.tx_log.len() as u64)?;
+		for entry in &self.tx_log {
+			entry.write(writer)?;
+		}
+		BinIssuedChildren(self.issued_children.clone()).write(writer)?;
+		writer.write_u64(self.last_confirmed_height)?;
+		writer.write_u8(self.tx_log_complete as u8)
+	}
+}
+
+impl Readable for WalletData {
+	fn read(reader: &mut ser::Reader) -> Result<WalletData, ser::Error> {
+		if reader.read_fixed_bytes(WALLET_BIN_MAGIC.len())? != WALLET_BIN_MAGIC {
+			return Err(ser::Error::CorruptedData);
+		}
+		let bin_version = reader.read_u8()?;
+		if bin_version > WALLET_BIN_VERSION {
+			return Err(ser::Error::CorruptedData);
+		}
+		let version = reader.read_u32()?;
+		let last_issued_child = reader.read_u32()?;
+		let mut outputs = HashMap::new();
+		for _ in 0..reader.read_u64()? {
+			let out = OutputData::read(reader)?;
+			outputs.insert(out.key(), out);
+		}
+		let mut tx_log = vec![];
+		for _ in 0..reader.read_u64()? {
+			tx_log.push(TxLogEntry::read(reader)?);
+		}
+		// version 1 stopped there, without the indexes issued by root key
+		let issued_children = if bin_version >= 2 {
+			BinIssuedChildren::read(reader)?.0
+		} else {
+			HashMap::new()
+		};
+		// and version 2 without the last confirmed height
+		let last_confirmed_height = if bin_version >= 3 { reader.read_u64()? } else { 0 };
+		// and version 3 without the flag, the data version tells for those
+		let tx_log_complete = if bin_version >= 4 { reader.read_u8()? != 0 } else { false };
+		Ok(WalletData {
+			version: version,
+			outputs: outputs,
+			tx_log: tx_log,
+			last_issued_child: last_issued_child,
+			issued_children: issued_children,
+			last_confirmed_height: last_confirmed_height,
+			tx_log_complete: tx_log_complete,
+		})
+	}
+}
+
+/// Version of the partial transaction format we produce. Payloads without a
+/// version are read as version 0, which has the same fields.
+const PARTIAL_TX_VERSION: u16 = 1;
+
+/// Leading bytes identifying a partial transaction in the binary format
+const PARTIAL_TX_MAGIC: [u8; 4] = *b"GRPT";
+
+/// Version of the binary partial transaction format, written right after the
+/// magic bytes.
+const PARTIAL_TX_BIN_VERSION: u8 = 1;
+
+/// Helper in serializing the information a receiver requires to build a
+/// transaction. Unknown fields are refused rather than dropped, a sender
+/// adding some likely expects us to use them, see parse_partial_tx.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct JSONPartialTx {
+	/// Version of the format, 0 for legacy payloads predating versioning
+	#[serde(default)]
+	version: u16,
+	amount: u64,
+	blind_sum: String,
+	tx: String,
+}
+
+/// Just the version of a partial transaction, whatever else it holds.
+#[derive(Deserialize)]
+struct JSONPartialTxVersion {
+	#[serde(default)]
+	version: u16,
+}
+
+/// Parses the JSON encoding of a partial transaction. When it has fields we
+/// don't know about, a newer version reports as unsupported, an error naming
+/// the unexpected field otherwise.
+fn parse_partial_tx(json_str: &str) -> Result<JSONPartialTx, Error> {
+	serde_json::from_str(json_str).map_err(|e| {
+		if let Ok(partial_tx) = serde_json::from_str::<JSONPartialTxVersion>(json_str) {
+			if let Err(e) = check_partial_tx_version(partial_tx.version) {
+				return e;
+			}
+		}
+		Error::Format(format!("invalid partial transaction: {}", e))
+	})
+}
+
+fn check_partial_tx_version(version: u16) -> Result<(), Error> {
+	if version > PARTIAL_TX_VERSION {
+		return Err(Error::Format(format!(
+			"Unsupported partial transaction version {}, this wallet supports up to version {}",
+			version,
+			PARTIAL_TX_VERSION
+		)));
+	}
+	Ok(())
+}
+
+/// Encodes the information for a partial transaction (not yet completed by the
+/// receiver) into JSON.
+pub fn partial_tx_to_json(receive_amount: u64,
+                          blind_sum: keychain::BlindingFactor,
+                          tx: Transaction)
+                          -> Result<String, Error> {
+	PartialTx::new(receive_amount, blind_sum, tx)?.to_json()
+}
+
+/// Reads a partial transaction encoded as JSON into the amount, sum of blinding
+/// factors and the transaction itself.
+pub fn partial_tx_from_json(keychain: &keychain::Keychain,
+                            json_str: &str)
+                            -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
+	decode_partial_tx(keychain, parse_partial_tx(json_str)?)
+}
+
+/// A partial transaction as sent to a receiver, built by a sender. Travels
+/// as a string holding its JSON encoding, see partial_tx_to_json, and gets
+/// checked for a well formed structure when parsed (see check) so malformed
+/// payloads are rejected early. Decoding the blinding factor and the
+/// transaction, which needs a keychain, is left to decode.
+#[derive(Debug, Clone)]
+pub struct PartialTx(JSONPartialTx);
+
+impl PartialTx {
+	/// Builds the partial transaction for the amount to receive, the sum of
+	/// blinding factors and the transaction.
+	pub fn new(
+		receive_amount: u64,
+		blind_sum: keychain::BlindingFactor,
+		tx: Transaction,
+	) -> Result<PartialTx, Error> {
+		let tx_bin = ser::ser_vec(&tx)
+			.map_err(|e| Error::Format(format!("Could not serialize transaction: {:?}", e)))?;
+		Ok(PartialTx(JSONPartialTx {
+			version: PARTIAL_TX_VERSION,
+			amount: receive_amount,
+			blind_sum: util::to_hex(blind_sum.secret_key().as_ref().to_vec()),
+			tx: util::to_hex(tx_bin),
+		}))
+	}
+
+	/// Parses the JSON encoding of a partial transaction, checking its
+	/// structure.
+	pub fn from_json(json_str: &str) -> Result<PartialTx, Error> {
+		let partial_tx = PartialTx(parse_partial_tx(json_str)?);
+		partial_tx.check()?;
+		Ok(partial_tx)
+	}
+
+	/// JSON encoding of the partial transaction.
+	pub fn to_json(&self) -> Result<String, Error> {
+		Ok(serde_json::to_string_pretty(&self.0)?)
+	}
+
+	/// The amount the receiver gets, fee included.
+	pub fn amount(&self) -> u64 {
+		self.0.amount
+	}
+
+	/// Checks the version is supported, the amount isn't zero and the hex
+	/// fields are well formed, without deserializing the transaction itself.
+	pub fn check(&self) -> Result<(), Error> {
+		let partial_tx = &self.0;
+		check_partial_tx_version(partial_tx.version)?;
+		if partial_tx.amount == 0 {
+			return Err(Error::Format(
+				"invalid partial transaction: amount must not be zero".to_owned(),
+			));
+		}
+		check_hex_field(
+			"partial transaction",
+			"blind_sum",
+			&partial_tx.blind_sum,
+			Some(secp::constants::SECRET_KEY_SIZE),
+		)?;
+		check_hex_field("partial transaction", "tx", &partial_tx.tx, None)
+	}
+
+	/// Decodes the partial transaction into the amount, sum of blinding
+	/// factors and the transaction itself.
+	pub fn decode(
+		&self,
+		keychain: &keychain::Keychain,
+	) -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
+		decode_partial_tx(keychain, self.0.clone())
+	}
+}
+
+impl Serialize for PartialTx {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		let json_str = serde_json::to_string(&self.0).map_err(serde::ser::Error::custom)?;
+		serializer.serialize_str(&json_str)
+	}
+}
+
+impl<'de> Deserialize<'de> for PartialTx {
+	fn deserialize<D>(deserializer: D) -> Result<PartialTx, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let json_str = String::deserialize(deserializer)?;
+		PartialTx::from_json(&json_str).map_err(de::Error::custom)
+	}
+}
+
+/// Decodes the fields of a partial transaction, leniently: only what's needed
+/// to decode them gets checked.
+fn decode_partial_tx(
+	keychain: &keychain::Keychain,
+	partial_tx: JSONPartialTx,
+) -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
+	check_partial_tx_version(partial_tx.version)?;
+
+	let blind_bin = util::from_hex(partial_tx.blind_sum)?;
+	if blind_bin.len() != secp::constants::SECRET_KEY_SIZE {
+		return Err(Error::Format(format!(
+			"blinding factor must be {} bytes",
+			secp::constants::SECRET_KEY_SIZE
+		)));
+	}
+
+	// TODO - turn some data into a blinding factor here somehow
+	// let blinding = SecretKey::from_slice(&secp, &blind_bin[..])?;
+	let blinding = keychain::BlindingFactor::from_slice(keychain.secp(), &blind_bin[..])?;
+
+	let tx_bin = util::from_hex(partial_tx.tx)?;
+	let tx = ser::deserialize(&mut &tx_bin[..])
+		.map_err(|_| {
+			Error::Format(format!(
+				"Could not deserialize transaction, invalid format ({} bytes).",
+				tx_bin.len()
+			))
+		})?;
+
+	Ok((partial_tx.amount, blinding, tx))
+}
+
+/// Body of the binary partial transaction format, the same fields as the JSON
+/// one without the hex encoding.
+struct BinPartialTx {
+	amount: u64,
+	blind_sum: Vec<u8>,
+	tx: Transaction,
+}
+
+impl Writeable for BinPartialTx {
+	fn write<W: ser::Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.amount)?;
+		writer.write_fixed_bytes(&self.blind_sum)?;
+		self.tx.write(writer)
+	}
+}
+
+impl Readable for BinPartialTx {
+	fn read(reader: &mut ser::Reader) -> Result<BinPartialTx, ser::Error> {
+		let amount = reader.read_u64()?;
+		let blind_sum = reader.read_fixed_bytes(secp::constants::SECRET_KEY_SIZE)?;
+		let tx = Transaction::read(reader)?;
+		Ok(BinPartialTx {
+			amount: amount,
+			blind_sum: blind_sum,
+			tx: tx,
+		})
+	}
+}
+
+/// Encodes the amount, sum of blinding factors and transaction as a compact
+/// binary partial transaction, about half the size of the JSON encoding.
+pub fn partial_tx_to_bytes(receive_amount: u64,
+                           blind_sum: keychain::BlindingFactor,
+                           tx: Transaction)
+                           -> Result<Vec<u8>, Error> {
+	let partial_tx = BinPartialTx {
+		amount: receive_amount,
+		blind_sum: blind_sum.secret_key().as_ref().to_vec(),
+		tx: tx,
+	};
+	let body = ser::ser_vec(&partial_tx)
+		.map_err(|e| Error::Format(format!("Could not serialize transaction: {:?}", e)))?;
+
+	let mut bytes = PARTIAL_TX_MAGIC.to_vec();
+	bytes.push(PARTIAL_TX_BIN_VERSION);
+	bytes.extend(body);
+	Ok(bytes)
+}
+
+/// Reads a binary partial transaction into the amount, sum of blinding
+/// factors and transaction.
+pub fn partial_tx_from_bytes(keychain: &keychain::Keychain,
+                             bytes: &[u8])
+                             -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
+	let header_len = PARTIAL_TX_MAGIC.len() + 1;
+	if bytes.len() < header_len || bytes[..PARTIAL_TX_MAGIC.len()] != PARTIAL_TX_MAGIC[..] {
+		return Err(Error::Format("Not a binary partial transaction.".to_string()));
+	}
+	let version = bytes[PARTIAL_TX_MAGIC.len()];
+	if version > PARTIAL_TX_BIN_VERSION {
+		return Err(Error::Format(format!(
+			"Unsupported partial transaction version {}, this wallet supports up to version {}",
+			version,
+			PARTIAL_TX_BIN_VERSION
+		)));
+	}
+
+	let partial_tx: BinPartialTx = ser::deserialize(&mut &bytes[header_len..])
+		.map_err(|_| {
+			Error::Format(format!(
+				"Could not deserialize transaction, invalid format ({} bytes).",
+				bytes.len() - header_len
+			))
+		})?;
+	let blinding = keychain::BlindingFactor::from_slice(keychain.secp(), &partial_tx.blind_sum)?;
+
+	Ok((partial_tx.amount, blinding, partial_tx.tx))
+}
+
+/// Amount in request to build a coinbase output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WalletReceiveRequest {
+	Coinbase(BlockFees),
+	PartialTransaction(PartialTx),
+	Finalize(String),
+}
+
+impl WalletReceiveRequest {
+	/// Checks the structure of the request when we accept it, before doing any
+	/// work with it. Partial transactions already get checked when parsed but
+	/// could have been built programmatically, see PartialTx::check.
+	pub fn validate(&self) -> Result<(), Error> {
+		match *self {
+			WalletReceiveRequest::PartialTransaction(ref partial_tx) => partial_tx.check(),
+			WalletReceiveRequest::Coinbase(_) | WalletReceiveRequest::Finalize(_) => Ok(()),
+		}
+	}
+}
+
+/// Checks a field of a partial transaction, or whatever else we're parsing,
+/// is non empty hex, decoding to exactly the expected number of bytes if
+/// provided.
+fn check_hex_field(
+	what: &str,
+	name: &str,
+	value: &str,
+	expected_len: Option<usize>,
+) -> Result<(), Error> {
+	if value.is_empty() || value.len() % 2 != 0 || !value.chars().all(|c| c.is_digit(16)) {
+		return Err(Error::Format(
+			format!("invalid {}: field {} is not valid hex", what, name),
+		));
+	}
+	match expected_len {
+		Some(len) if value.len() != len * 2 => Err(Error::Format(format!(
+			"invalid {}: field {} must be {} bytes, got {}",
+			what,
+			name,
+			len,
+			value.len() / 2
+		))),
+		_ => Ok(()),
+	}
+}
+
+/// Fees in block to use for coinbase amount calculation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockFees {
+	pub fees: u64,
+	pub height: u64,
+	pub key_id: Option<keychain::Identifier>,
+	/// Optional lower bound on the fees claimed by the coinbase
+	#[serde(default)]
+	pub min_fees: Option<u64>,
+	/// Optional upper bound on the fees claimed by the coinbase
+	#[serde(default)]
+	pub max_fees: Option<u64>,
+}
+
+impl BlockFees {
+	pub fn key_id(&self) -> Option<keychain::Identifier> {
+		self.key_id.clone()
+	}
+
+	/// Checks the height of the block is at most max_drift blocks away from
+	/// the one following the provided chain tip, the next block to be mined.
+	pub fn check_height(&self, tip_height: u64, max_drift: u64) -> Result<(), Error> {
+		let next_height = tip_height.saturating_add(1);
+		let drift = cmp::max(self.height, next_height) - cmp::min(self.height, next_height);
+		if drift > max_drift {
+			return Err(Error::WalletData(format!(
+				"block height {} is too far from the next block height {} (max drift {})",
+				self.height,
+				next_height,
+				max_drift
+			)));
+		}
+		Ok(())
+	}
+
+	/// Fees the coinbase claims, the block fees clamped to the min_fees and
+	/// max_fees range when either is set.
+	pub fn coinbase_fees(&self) -> u64 {
+		let mut fees = self.fees;
+		if let Some(min_fees) = self.min_fees {
+			fees = cmp::max(fees, min_fees);
+		}
+		if let Some(max_fees) = self.max_fees {
+			fees = cmp::min(fees, max_fees);
+		}
+		fees
+	}
+
+	/// Total coinbase amount for the provided block reward, reward plus fees
+	/// within the allowed range.
+	pub fn coinbase_amount(&self, reward: u64) -> u64 {
+		reward.saturating_add(self.coinbase_fees())
+	}
+}
+
+/// Response to build a coinbase output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CbData {
+	pub output: String,
+	pub kernel: String,
+	pub key_id: String,
+	/// Hex encoded hash of the three fields above, see CbData::verify. Empty
+	/// when coming from a wallet predating it.
+	#[serde(default)]
+	pub checksum: String,
+}
+
+impl CbData {
+	/// Builds the coinbase response from its hex encoded parts, computing the
+	/// checksum over them.
+	pub fn new(output: String, kernel: String, key_id: String) -> CbData {
+		let checksum = CbData::compute_checksum(&output, &kernel, &key_id);
+		CbData {
+			output: output,
+			kernel: kernel,
+			key_id: key_id,
+			checksum: checksum,
+		}
+	}
+
+	/// Checks the output, kernel and key_id haven't been altered since the
+	/// response was built. Should be called before deserializing them. A
+	/// response without checksum, from an older wallet, can't be checked and
+	/// is let through.
+	pub fn verify(&self) -> Result<(), Error> {
+		if self.checksum.is_empty() {
+			return Ok(());
+		}
+		let expected = CbData::compute_checksum(&self.output, &self.kernel, &self.key_id);
+		if self.checksum != expected {
+			return Err(Error::Format(format!(
+				"Coinbase data checksum mismatch, expected {}, got {}",
+				expected,
+				self.checksum
+			)));
+		}
+		Ok(())
+	}
+
+	/// JSON encoding of the coinbase response, as sent to miners.
+	pub fn to_json(&self) -> Result<String, Error> {
+		serde_json::to_string(self)
+			.map_err(|e| Error::Format(format!("Could not serialize coinbase data: {}", e)))
+	}
+
+	/// Parses the JSON encoding of a coinbase response, checking its fields
+	/// are hex of the right length. The checksum is left to verify.
+	pub fn from_json(json_str: &str) -> Result<CbData, Error> {
+		let cb_data: CbData = serde_json::from_str(json_str)
+			.map_err(|e| Error::Format(format!("invalid coinbase data: {}", e)))?;
+		check_hex_field("coinbase data", "output", &cb_data.output, None)?;
+		check_hex_field("coinbase data", "kernel", &cb_data.kernel, None)?;
+		check_hex_field(
+			"coinbase data",
+			"key_id",
+			&cb_data.key_id,
+			Some(keychain::IDENTIFIER_SIZE),
+		)?;
+		if !cb_data.checksum.is_empty() {
+			check_hex_field("coinbase data", "checksum", &cb_data.checksum, Some(32))?;
+		}
+		Ok(cb_data)
+	}
+
+	fn compute_checksum(output: &str, kernel: &str, key_id: &str) -> String {
+		let mut hasher = Blake2b::new(32);
+		for field in &[output, kernel, key_id] {
+			// length prefix each field so bytes can't move from one to another
+			let mut len = [0; 8];
+			BigEndian::write_u64(&mut len, field.len() as u64);
+			hasher.update(&len);
+			hasher.update(field.as_bytes());
+		}
+		util::to_hex(hasher.finalize().as_bytes().to_vec())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+	use std::{env, fs, path, process};
+	use core::core::build;
+	use core::core::hash::Hashed;
+	use keychain::{BlindingFactor, Identifier, Keychain};
+	use test_util::{keyed_output, output, test_config, test_dir, MockNode};
+	use super::*;
+
+	/// with_wallet with the test_config settings and the provided lock
+	/// settings
+	fn with_test_wallet<T, F>(dir: &str, lock_config: &LockConfig, f: F) -> Result<T, Error>
+		where F: FnOnce(&mut WalletData) -> T
+	{
+		let config = WalletConfig {
+			lock: lock_config.clone(),
+			..test_config(dir)
+		};
+		WalletData::with_wallet(&config, f)
+	}
+
+	/// Partial transaction as a sender would build it
+	fn partial_tx(keychain: &Keychain) -> (Transaction, BlindingFactor) {
+		build::transaction(
+			vec![
+				build::input(1000, keychain.derive_key_id(1).unwrap()),
+				build::output(700, keychain.derive_key_id(2).unwrap()),
+				build::with_fee(tx_fee(1, 2, None)),
+			],
+			keychain,
+		).unwrap()
+	}
+
+	fn wallet(outputs: Vec<OutputData>) -> WalletData {
+		let mut wallet_data = WalletData::new();
+		for out in outputs {
+			wallet_data.add_output(out).unwrap();
+		}
+		wallet_data
+	}
+
+	fn values(outputs: &Vec<OutputData>) -> Vec<u64> {
+		let mut values = outputs.iter().map(|out| out.value).collect::<Vec<_>>();
+		values.sort();
+		values
+	}
+
+	#[test]
+	fn fee_policy_floor() {
+		let policy = FeePolicy {
+			base_fee: 10,
+			min_fee: 100,
+			per_kernel_fee: 5,
+			max_fee: None,
+			fee_tolerance: 0,
+		};
+		// weight 4 for 1 input and 1 output, below the floor
+		assert_eq!(tx_fee_with_policy(1, 1, 1, &policy), 100);
+		// weight 8 for 1 input and 2 outputs, above the floor
+		assert_eq!(tx_fee_with_policy(1, 2, 1, &policy), 85);
+		assert_eq!(tx_fee_with_policy(1, 2, 2, &policy), 90);
+
+		// the default policy matches the plain fee calculation
+		assert_eq!(tx_fee(1, 2, None), 80);
+		assert_eq!(tx_fee_with_policy(1, 2, 1, &FeePolicy::default()), 80);
+		assert_eq!(tx_fee(1, 2, Some(3)), 24);
+	}
+
+	#[test]
+	fn fee_with_huge_input_count() {
+		assert_eq!(tx_fee(1_000_000, 1, None), DEFAULT_BASE_FEE);
+		assert_eq!(tx_fee(5_000_000, 2, Some(7)), 7);
+		// would have wrapped around with 32 bits arithmetic
+		assert_eq!(tx_fee(3_000_000_000, 1, None), DEFAULT_BASE_FEE);
+	}
+
+	#[test]
+	fn balance_by_status() {
+		let mut other_account = output(7, 1000, OutputStatus::Unspent);
+		other_account.root_key_id = Identifier::from_bytes(&[9]);
+		let wallet_data = wallet(vec![
+			output(1, 1, OutputStatus::Unspent),
+			output(2, 2, OutputStatus::Unspent),
+			output(3, 4, OutputStatus::Immature),
+			output(4, 8, OutputStatus::Unconfirmed),
+			output(5, 16, OutputStatus::Locked),
+			output(6, 32, OutputStatus::Spent),
+			other_account,
+		]);
+
+		let balance = wallet_data.balance(Identifier::zero());
+		assert_eq!(
+			balance,
+			WalletBalance {
+				total: 31,
+				unspent: 3,
+				immature: 4,
+				unconfirmed: 8,
+				locked: 16,
+				watch_only: 0,
+				last_confirmed_height: 0,
+			}
+		);
+	}
+
+	#[test]
+	fn watch_only_outputs() {
+		let mut watched = vec![];
+		for (n_child, value, status) in vec![
+			(3, 100, OutputStatus::Unspent),
+			(4, 200, OutputStatus::Immature),
+			(5, 400, OutputStatus::Spent),
+		] {
+			let mut out = output(n_child, value, status);
+			out.watch_only = true;
+			watched.push(out);
+		}
+		let mut outputs = vec![
+			output(1, 5, OutputStatus::Unspent),
+			output(2, 10, OutputStatus::Unspent),
+		];
+		outputs.extend(watched);
+		let wallet_data = wallet(outputs);
+
+		let balance = wallet_data.balance(Identifier::zero());
+		assert_eq!(balance.watch_only, 300);
+		assert_eq!(balance.unspent, 15);
+		assert_eq!(balance.immature, 0);
+		assert_eq!(balance.total, 15);
+
+		let (coins, change) = wallet_data.select(Identifier::zero(), 12, 0);
+		assert_eq!(values(&coins), vec![5, 10]);
+		assert_eq!(change, 3);
+
+		// even when only the watch-only outputs could cover the amount
+		let (coins, change) = wallet_data.select(Identifier::zero(), 90, 0);
+		assert_eq!(values(&coins), vec![5, 10]);
+		assert_eq!(change, -75);
+		match wallet_data.select_checked(Identifier::zero(), 90) {
+			Err(Error::NotEnoughFunds { available, needed }) => {
+				assert_eq!(available, 15);
+				assert_eq!(needed, 90);
+			}
+			res => panic!("expected not enough funds, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn prune_spent_outputs() {
+		let mut outputs = vec![];
+		for (n, status) in vec![
+			OutputStatus::Spent,
+			OutputStatus::Spent,
+			OutputStatus::Locked,
+			OutputStatus::Unconfirmed,
+			OutputStatus::Unspent,
+		].into_iter()
+			.enumerate()
+		{
+			let mut out = output(n as u32 + 1, 10, status);
+			out.height = 5;
+			outputs.push(out);
+		}
+		let mut recent = output(6, 10, OutputStatus::Spent);
+		recent.height = 20;
+		outputs.push(recent);
+		let mut wallet_data = wallet(outputs);
+
+		assert_eq!(wallet_data.prune_spent(10), 2);
+		assert_eq!(wallet_data.outputs.len(), 4);
+		assert!(wallet_data.outputs.values().all(|out| out.n_child > 2));
+		assert_eq!(wallet_data.prune_spent(10), 0);
+
+		let removed = wallet_data.remove_output(&output(3, 0, OutputStatus::Locked).key_id);
+		assert_eq!(removed.map(|out| out.n_child), Some(3));
+		assert!(wallet_data.remove_output(&output(3, 0, OutputStatus::Locked).key_id).is_none());
+		assert_eq!(wallet_data.outputs.len(), 3);
+	}
+
+	#[test]
+	fn commitment_index_distinct() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let out1 = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		let out2 = keyed_output(&keychain, 2, 20, OutputStatus::Unspent);
+		let commit1 = keychain.commit(10, &out1.key_id).unwrap();
+		let commit2 = keychain.commit(20, &out2.key_id).unwrap();
+		let wallet_data = wallet(vec![out1.clone(), out2.clone()]);
+
+		let index = wallet_data.commitment_index(&keychain).unwrap();
+		assert!(commit1 != commit2);
+		assert_eq!(index.len(), 2);
+		assert_eq!(index.get(&commit1), Some(&out1.key_id.to_hex()));
+		assert_eq!(index.get(&commit2), Some(&out2.key_id.to_hex()));
+	}
+
+	#[test]
+	fn duplicate_commitments() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let out1 = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		let out2 = keyed_output(&keychain, 2, 20, OutputStatus::Unspent);
+		let mut wallet_data = wallet(vec![out1.clone(), out2.clone()]);
+		assert!(wallet_data.find_duplicate_commitments(&keychain).unwrap().is_empty());
+
+		// same derivation and value under another key, as a bad import would
+		let mut copy = out1.clone();
+		copy.key_id = Identifier::from_bytes(&[7, 7, 7]);
+		wallet_data.add_output(copy.clone()).unwrap();
+		// same derivation, another value, is a different commitment
+		let mut other_value = out2.clone();
+		other_value.key_id = Identifier::from_bytes(&[8, 8, 8]);
+		other_value.value = 25;
+		wallet_data.add_output(other_value).unwrap();
+
+		let duplicates = wallet_data.find_duplicate_commitments(&keychain).unwrap();
+		assert_eq!(duplicates.len(), 1);
+		let (first, second) = duplicates[0].clone();
+		let mut pair = vec![first, second];
+		pair.sort();
+		let mut expected = vec![out1.key_id.to_hex(), copy.key_id.to_hex()];
+		expected.sort();
+		assert_eq!(pair, expected);
+	}
+
+	#[test]
+	fn tracked_commitments() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let unspent = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		let locked = keyed_output(&keychain, 2, 20, OutputStatus::Locked);
+		let unconfirmed = keyed_output(&keychain, 3, 30, OutputStatus::Unconfirmed);
+		let spent = keyed_output(&keychain, 4, 40, OutputStatus::Spent);
+		let mut other_root = keyed_output(&keychain, 5, 50, OutputStatus::Unspent);
+		other_root.root_key_id = Identifier::from_bytes(&[9, 9, 9]);
+		let wallet_data = wallet(vec![
+			unspent.clone(),
+			locked.clone(),
+			unconfirmed.clone(),
+			spent.clone(),
+			other_root,
+		]);
+
+		let commits = wallet_data
+			.tracked_commitments(&keychain, &keychain.root_key_id())
+			.unwrap();
+		assert_eq!(commits.len(), 3);
+		for out in &[unspent, locked, unconfirmed] {
+			assert!(commits.contains(&out.commit(&keychain).unwrap()));
+		}
+		assert!(!commits.contains(&spent.commit(&keychain).unwrap()));
+	}
+
+	#[test]
+	fn abandon_pending_send() {
+		let input = output(1, 50, OutputStatus::Locked);
+		let other = output(2, 20, OutputStatus::Locked);
+		let change = output(3, 10, OutputStatus::Unconfirmed);
+		let mut wallet_data = wallet(vec![input.clone(), other.clone(), change.clone()]);
+		wallet_data.record_tx("abcd", &vec![input.clone()], &vec![change.key_id.clone()]);
+		wallet_data.record_tx("ef01", &vec![other.clone()], &vec![]);
+
+		assert_eq!(wallet_data.abandon_tx("abcd").unwrap(), 2);
+		let input = &wallet_data.outputs[&input.key()];
+		assert_eq!(input.status, OutputStatus::Unspent);
+		assert_eq!(input.spent_in_tx, None);
+		assert!(input.is_spendable(100, 10, 1));
+		let change = &wallet_data.outputs[&change.key()];
+		assert_eq!(change.status, OutputStatus::Abandoned);
+		assert_eq!(format!("{}", change.status), "Abandoned");
+		// other sends are left alone
+		assert_eq!(wallet_data.outputs[&other.key()].status, OutputStatus::Locked);
+
+		let balance = wallet_data.balance(Identifier::zero());
+		assert_eq!(balance.unspent, 50);
+		assert_eq!(balance.total, 70);
+
+		// nothing left to abandon, or unknown
+		assert!(wallet_data.abandon_tx("abcd").is_err());
+		assert!(wallet_data.abandon_tx("0000").is_err());
+	}
+
+	#[test]
+	fn abandon_confirmed_send() {
+		let input = output(1, 50, OutputStatus::Spent);
+		let change = output(2, 10, OutputStatus::Unspent);
+		let mut wallet_data = wallet(vec![input.clone(), change.clone()]);
+		wallet_data.record_tx("abcd", &vec![input.clone()], &vec![change.key_id.clone()]);
+
+		assert!(wallet_data.abandon_tx("abcd").is_err());
+		assert_eq!(wallet_data.outputs[&input.key()].status, OutputStatus::Spent);
+	}
+
+	#[test]
+	fn bump_fee_pending_send() {
+		let input = output(1, 5000, OutputStatus::Locked);
+		let other = output(2, 3000, OutputStatus::Locked);
+		let change = output(3, 1000, OutputStatus::Unconfirmed);
+		let mut wallet_data = wallet(vec![input.clone(), other.clone(), change.clone()]);
+		let spent = vec![input.clone(), other.clone()];
+		wallet_data.record_tx("abcd", &spent, &vec![change.key_id.clone()]);
+		let fee = tx_fee(2, 2, Some(10));
+		wallet_data.log_tx(TxLogEntryType::Sent, Some("abcd".to_string()), 1000, 8000, fee);
+
+		let (inputs, new_fee) = wallet_data.bump_fee_inputs("abcd", 20).unwrap();
+		assert_eq!(values(&inputs), vec![5000, 3000]);
+		assert_eq!(new_fee, tx_fee(2, 2, Some(20)));
+		assert!(new_fee > fee);
+		// nothing changed until the caller rebuilds it
+		assert_eq!(wallet_data.outputs[&input.key()].status, OutputStatus::Locked);
+
+		// not raising the fee, or an unknown transaction
+		assert!(wallet_data.bump_fee_inputs("abcd", 10).is_err());
+		assert!(wallet_data.bump_fee_inputs("0000", 20).is_err());
+	}
+
+	#[test]
+	fn bump_fee_confirmed_send() {
+		let input = output(1, 5000, OutputStatus::Spent);
+		let change = output(2, 1000, OutputStatus::Unspent);
+		let mut wallet_data = wallet(vec![input.clone(), change.clone()]);
+		wallet_data.record_tx("abcd", &vec![input.clone()], &vec![change.key_id.clone()]);
+
+		match wallet_data.bump_fee_inputs("abcd", 20) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("is Spent")),
+			res => panic!("expected a wallet data error, got {:?}", res.map(|r| r.1)),
+		}
+	}
+
+	#[test]
+	fn reconcile_with_node() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let present = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		let missing = keyed_output(&keychain, 2, 20, OutputStatus::Unspent);
+		let locked = keyed_output(&keychain, 3, 30, OutputStatus::Locked);
+		let mut other_root = output(4, 40, OutputStatus::Unspent);
+		other_root.root_key_id = Identifier::from_bytes(&[9]);
+		let mut wallet_data = wallet(vec![
+			present.clone(),
+			missing.clone(),
+			locked.clone(),
+			other_root.clone(),
+		]);
+
+		let mut node_utxos = HashSet::new();
+		node_utxos.insert(present.commit(&keychain).unwrap());
+		let report = wallet_data.reconcile(&node_utxos, 42, &keychain).unwrap();
+		assert_eq!(report.checked, 2);
+		assert_eq!(report.spent, vec![missing.key_id.to_hex()]);
+
+		let status = |out: &OutputData| wallet_data.outputs[&out.key()].status.clone();
+		assert_eq!(status(&missing), OutputStatus::Spent);
+		assert_eq!(status(&present), OutputStatus::Unspent);
+		assert_eq!(status(&locked), OutputStatus::Locked);
+		assert_eq!(status(&other_root), OutputStatus::Unspent);
+	}
+
+	#[test]
+	fn output_data_tx_hash_roundtrip() {
+		let mut out = output(1, 10, OutputStatus::Spent);
+		out.tx_hash = Some("aa01".to_string());
+		out.spent_in_tx = Some("bb02".to_string());
+		let json = serde_json::to_string(&out).unwrap();
+		let read_back: OutputData = serde_json::from_str(&json).unwrap();
+		assert_eq!(read_back.tx_hash, Some("aa01".to_string()));
+		assert_eq!(read_back.spent_in_tx, Some("bb02".to_string()));
+
+		// as found in wallet files written before these fields existed
+		let legacy = "{\"root_key_id\":\"00000000000000000000\",\
+			\"key_id\":\"01000100000000000000\",\"n_child\":1,\"value\":10,\
+			\"status\":\"Unspent\",\"height\":0,\"lock_height\":0,\"zero_ok\":false}";
+		let read_back: OutputData = serde_json::from_str(legacy).unwrap();
+		assert_eq!(read_back.key_id, output(1, 10, OutputStatus::Unspent).key_id);
+		assert_eq!(read_back.tx_hash, None);
+		assert_eq!(read_back.spent_in_tx, None);
+	}
+
+	#[test]
+	fn record_tx_on_outputs() {
+		let spent = output(1, 10, OutputStatus::Locked);
+		let change = output(2, 4, OutputStatus::Unconfirmed);
+		let mut wallet_data = wallet(vec![spent.clone(), change.clone()]);
+
+		wallet_data.record_tx("abcd", &vec![spent.clone()], &vec![change.key_id.clone()]);
+		let spent = wallet_data.get_output(&spent.key_id).unwrap();
+		assert_eq!(spent.spent_in_tx, Some("abcd".to_string()));
+		assert_eq!(spent.tx_hash, None);
+		let change = wallet_data.get_output(&change.key_id).unwrap();
+		assert_eq!(change.tx_hash, Some("abcd".to_string()));
+		assert_eq!(change.spent_in_tx, None);
+	}
+
+	#[test]
+	fn tx_log_entries() {
+		let mut wallet_data = wallet(vec![]);
+		assert_eq!(wallet_data.log_tx(TxLogEntryType::Coinbase, None, 50, 0, 0), 0);
+		assert_eq!(
+			wallet_data.log_tx(TxLogEntryType::Sent, Some("ab".to_string()), 30, 50, 10),
+			1
+		);
+
+		let log = wallet_data.tx_log();
+		assert_eq!(log.len(), 2);
+		assert_eq!(log[1].tx_type, TxLogEntryType::Sent);
+		assert_eq!(log[1].amount_debited - log[1].amount_credited, 20);
+		assert_eq!(log[1].tx_hash, Some("ab".to_string()));
+
+		// wallets written before the history existed
+		let legacy: WalletData = serde_json::from_str("{\"outputs\":{}}").unwrap();
+		assert!(legacy.tx_log().is_empty());
+	}
+
+	#[test]
+	fn fees_paid() {
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent)]);
+		wallet_data.log_tx(TxLogEntryType::Sent, Some("ab".to_string()), 30, 50, 10);
+		wallet_data.log_tx(TxLogEntryType::Received, None, 25, 0, 3);
+		wallet_data.log_tx(TxLogEntryType::Sent, Some("cd".to_string()), 10, 30, 8);
+		assert!(wallet_data.has_tx_log());
+		assert_eq!(wallet_data.total_fees_paid(), 18);
+
+		// nothing sent yet
+		assert!(wallet(vec![]).has_tx_log());
+		assert_eq!(wallet(vec![]).total_fees_paid(), 0);
+
+		// outputs but no history, the wallet predates it
+		let legacy_json = "{\"outputs\":{\"01000100000000000000\":{\
+			\"root_key_id\":\"00000000000000000000\",\"key_id\":\"01000100000000000000\",\
+			\"n_child\":1,\"value\":10,\"status\":\"Unspent\",\"height\":3,\
+			\"lock_height\":0,\"zero_ok\":false}}}";
+		let mut legacy: WalletData = serde_json::from_str(legacy_json).unwrap();
+		legacy.migrate().unwrap();
+		assert!(!legacy.has_tx_log());
+		assert_eq!(legacy.total_fees_paid(), 0);
+
+		// and it stays incomplete once something got logged and written
+		legacy.log_tx(TxLogEntryType::Sent, Some("ab".to_string()), 0, 10, 2);
+		assert!(!legacy.has_tx_log());
+		assert_eq!(legacy.total_fees_paid(), 0);
+		let dir = test_dir("fees_paid");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		for &format in [WalletFormat::Json, WalletFormat::Binary].iter() {
+			legacy.write_file(&data_file_path, format, false).unwrap();
+			assert!(!WalletData::read_or_create(&data_file_path).unwrap().has_tx_log());
+		}
+
+		// whereas a wallet written with the history had it from the start
+		let tracked_json = "{\"version\":2,\"outputs\":{}}";
+		let mut tracked: WalletData = serde_json::from_str(tracked_json).unwrap();
+		tracked.migrate().unwrap();
+		assert!(tracked.has_tx_log());
+	}
+
+	#[test]
+	fn export_outputs_csv() {
+		let mut csv = vec![];
+		wallet(vec![]).export_csv(&mut csv).unwrap();
+		assert_eq!(
+			String::from_utf8(csv).unwrap(),
+			"key_id,n_child,value_grin,value_nanogrin,status,height,lock_height,label\n"
+		);
+
+		let mut csv = vec![];
+		let mut labeled = output(3, 30, OutputStatus::Unspent);
+		labeled.label = Some("from \"Alice\", thanks".to_owned());
+		let wallet_data = wallet(vec![
+			output(2, 2_500_000_000, OutputStatus::Unspent),
+			output(1, 10, OutputStatus::Spent),
+			labeled,
+		]);
+		wallet_data.export_csv(&mut csv).unwrap();
+		let csv = String::from_utf8(csv).unwrap();
+		let lines = csv.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 4);
+		assert_eq!(lines[1], "01000100000000000000,1,0.000000010,10,Spent,0,0,");
+		assert_eq!(lines[2], "01000200000000000000,2,2.500000000,2500000000,Unspent,0,0,");
+		assert_eq!(
+			lines[3],
+			"01000300000000000000,3,0.000000030,30,Unspent,0,0,\"from \"\"Alice\"\", thanks\""
+		);
+	}
+
+	#[test]
+	fn set_output_label() {
+		let key_id = output(1, 0, OutputStatus::Spent).key_id;
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent)]);
+		assert_eq!(wallet_data.get_output(&key_id).unwrap().label, None);
+
+		wallet_data.set_label(&key_id, Some("mining reward".to_owned())).unwrap();
+		assert_eq!(
+			wallet_data.get_output(&key_id).unwrap().label,
+			Some("mining reward".to_owned())
+		);
+
+		wallet_data.set_label(&key_id, Some("payment from Alice".to_owned())).unwrap();
+		assert_eq!(
+			wallet_data.get_output(&key_id).unwrap().label,
+			Some("payment from Alice".to_owned())
+		);
+
+		wallet_data.set_label(&key_id, None).unwrap();
+		assert_eq!(wallet_data.get_output(&key_id).unwrap().label, None);
+
+		let unknown = output(2, 0, OutputStatus::Spent).key_id;
+		match wallet_data.set_label(&unknown, Some("nope".to_owned())) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("not found")),
+			res => panic!("expected unknown output error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn output_label_serde_roundtrip() {
+		let mut out = output(1, 10, OutputStatus::Unspent);
+		out.label = Some("payment from Alice".to_owned());
+		let json = serde_json::to_string(&out).unwrap();
+		let back: OutputData = serde_json::from_str(&json).unwrap();
+		assert_eq!(back.label, Some("payment from Alice".to_owned()));
+		assert_eq!(back.key_id, out.key_id);
+		assert_eq!(back.value, out.value);
+
+		// outputs written before labels existed
+		let legacy = json.replace(",\"label\":\"payment from Alice\"", "");
+		assert!(!legacy.contains("label"));
+		let back: OutputData = serde_json::from_str(&legacy).unwrap();
+		assert_eq!(back.label, None);
+	}
+
+	#[test]
+	fn select_exact_match_without_change() {
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 7, OutputStatus::Unspent),
+			output(3, 5, OutputStatus::Unspent),
+			output(4, 3, OutputStatus::Unspent),
+		]);
+
+		let (coins, change) = wallet_data.select(Identifier::zero(), 8, 0);
+		assert_eq!(values(&coins), vec![3, 5]);
+		assert_eq!(change, 0);
+
+		// the fee is part of the target
+		let (coins, change) = wallet_data.select(Identifier::zero(), 15, 2);
+		assert_eq!(values(&coins), vec![7, 10]);
+		assert_eq!(change, 0);
+	}
+
+	#[test]
+	fn select_falls_back_to_greedy() {
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 7, OutputStatus::Unspent),
+			output(3, 5, OutputStatus::Unspent),
+			output(4, 1, OutputStatus::Spent),
+		]);
+
+		let (coins, change) = wallet_data.select(Identifier::zero(), 4, 0);
+		assert_eq!(values(&coins), vec![10]);
+		assert_eq!(change, 6);
+
+		let (coins, change) = wallet_data.select(Identifier::zero(), 30, 0);
+		assert_eq!(values(&coins), vec![5, 7, 10]);
+		assert_eq!(change, -8);
+	}
+
+	#[test]
+	fn select_is_deterministic() {
+		let wallet_data = wallet(
+			(1..20)
+				.map(|n| output(n, n as u64 * 3, OutputStatus::Unspent))
+				.collect(),
+		);
+
+		let (coins1, change1) = wallet_data.select(Identifier::zero(), 100, 0);
+		let (coins2, change2) = wallet_data.select(Identifier::zero(), 100, 0);
+		let keys1 = coins1.iter().map(|out| out.n_child).collect::<Vec<_>>();
+		let keys2 = coins2.iter().map(|out| out.n_child).collect::<Vec<_>>();
+		assert_eq!(keys1, keys2);
+		assert_eq!(change1, change2);
+	}
+
+	#[test]
+	fn select_with_strategies() {
+		let sizes = vec![1, 2, 3, 5, 8, 13, 21, 34, 55, 89];
+		let wallet_data = wallet(
+			sizes
+				.iter()
+				.enumerate()
+				.map(|(n, v)| output(n as u32 + 1, *v, OutputStatus::Unspent))
+				.collect(),
+		);
+		let root_key_id = Identifier::zero();
+
+		let (largest, change) = wallet_data.select_with_strategy(
+			root_key_id.clone(),
+			60,
+			SelectionStrategy::LargestFirst,
+		);
+		assert_eq!(values(&largest), vec![89]);
+		assert_eq!(change, 29);
+
+		let (smallest, change) = wallet_data.select_with_strategy(
+			root_key_id.clone(),
+			60,
+			SelectionStrategy::SmallestFirst,
+		);
+		assert_eq!(values(&smallest), vec![1, 2, 3, 5, 8, 13, 21, 34]);
+		assert_eq!(change, 27);
+
+		let (auto, change) =
+			wallet_data.select_with_strategy(root_key_id.clone(), 60, SelectionStrategy::Auto);
+		assert_eq!(auto.iter().map(|out| out.value).sum::<u64>(), 60);
+		assert_eq!(change, 0);
+
+		assert!(largest.len() < smallest.len());
+	}
+
+	#[test]
+	fn selection_logged() {
+		let outputs = vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Unspent),
+		];
+		let line = selection_outcome(25, &outputs, 5);
+		assert!(line.starts_with("selected 2 outputs for 25, change 5: "));
+		for out in &outputs {
+			assert!(line.contains(&format!("{}:{}", out.key_id, out.value)));
+		}
+		assert_eq!(
+			selection_outcome::<OutputData>(25, &[], -25),
+			"selected 0 outputs for 25, change -25: "
+		);
+	}
+
+	#[test]
+	fn select_excluding_reserved() {
+		let largest = output(3, 100, OutputStatus::Unspent);
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Unspent),
+			largest.clone(),
+		]);
+		let root_key_id = Identifier::zero();
+		let mut reserved = HashSet::new();
+		reserved.insert(largest.key_id.to_hex());
+
+		// would be picked otherwise
+		let (coins, _) = wallet_data.select_checked(root_key_id.clone(), 120).unwrap();
+		assert_eq!(values(&coins), vec![20, 100]);
+
+		// worked around when the rest covers it
+		let (coins, change) =
+			wallet_data.select_excluding(root_key_id.clone(), 25, &reserved).unwrap();
+		assert_eq!(values(&coins), vec![10, 20]);
+		assert_eq!(change, 5);
+
+		// and never touched when it doesn't
+		match wallet_data.select_excluding(root_key_id.clone(), 90, &reserved) {
+			Err(Error::NotEnoughFunds { available, needed }) => {
+				assert_eq!((available, needed), (30, 90));
+			}
+			res => panic!("expected not enough funds, got {:?}", res),
+		}
+
+		// still ours though
+		assert_eq!(wallet_data.balance(root_key_id).unspent, 130);
+	}
+
+	#[test]
+	fn select_checked_amounts() {
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Unspent),
+			output(3, 40, OutputStatus::Locked),
+		]);
+		let root_key_id = Identifier::zero();
+
+		let (coins, change) = wallet_data.select_checked(root_key_id.clone(), 30).unwrap();
+		assert_eq!(values(&coins), vec![10, 20]);
+		assert_eq!(change, 0);
+
+		let (coins, change) = wallet_data.select_checked(root_key_id.clone(), 15).unwrap();
+		assert_eq!(values(&coins), vec![10, 20]);
+		assert_eq!(change, 15);
+
+		match wallet_data.select_checked(root_key_id.clone(), 31) {
+			Err(Error::NotEnoughFunds { available, needed }) => {
+				assert_eq!(available, 30);
+				assert_eq!(needed, 31);
+			}
+			res => panic!("expected NotEnoughFunds, got {:?}", res),
+		}
+
+		// the locked output doesn't count as available
+		match wallet_data.select_checked(root_key_id.clone(), 65) {
+			Err(e) => assert_eq!(e.to_string(), "not enough funds: have 30, need 65, short by 35"),
+			res => panic!("expected NotEnoughFunds, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn output_confirmations() {
+		let mut out = output(1, 10, OutputStatus::Unspent);
+		out.height = 100;
+		// at the tip
+		assert_eq!(out.confirmations(100), 0);
+		// well below the tip
+		assert_eq!(out.confirmations(1_100), 1_000);
+		// recorded past the tip, after a reorg for example
+		assert_eq!(out.confirmations(90), 0);
+
+		out.status = OutputStatus::Unconfirmed;
+		assert_eq!(out.confirmations(1_100), 0);
+	}
+
+	#[test]
+	fn coinbase_spendable_once_mature() {
+		let mut coinbase = output(1, 60, OutputStatus::Unspent);
+		coinbase.is_coinbase = true;
+		coinbase.height = 100;
+		let mut regular = output(2, 10, OutputStatus::Unspent);
+		regular.height = 108;
+
+		// coinbase one block short of maturity, regular with 1 confirmation
+		assert!(!coinbase.is_spendable(109, 10, 1));
+		assert!(regular.is_spendable(109, 10, 1));
+		// coinbase just mature
+		assert!(coinbase.is_spendable(110, 10, 1));
+		assert!(coinbase.is_spendable(111, 10, 1));
+
+		// status still rules
+		let mut spent = regular.clone();
+		spent.status = OutputStatus::Spent;
+		assert!(!spent.is_spendable(200, 10, 1));
+
+		let wallet_data = wallet(vec![coinbase, regular]);
+		let root_key_id = Identifier::zero();
+		let (coins, change) = wallet_data.select_spendable(root_key_id.clone(), 50, 0, 109, 10, 1);
+		assert_eq!(values(&coins), vec![10]);
+		assert_eq!(change, -40);
+		let (coins, change) = wallet_data.select_spendable(root_key_id.clone(), 50, 0, 110, 10, 1);
+		assert_eq!(values(&coins), vec![60]);
+		assert_eq!(change, 10);
+	}
+
+	#[test]
+	fn select_oldest_first() {
+		let mut outputs = vec![];
+		// (n_child, value, height), coinbase outputs at the last 2 heights are immature
+		let seeds = vec![(1, 10, 50), (2, 20, 30), (3, 30, 40), (4, 40, 95), (5, 50, 100)];
+		for (n_child, value, height) in seeds {
+			let mut out = output(n_child, value, OutputStatus::Unspent);
+			out.height = height;
+			out.is_coinbase = true;
+			outputs.push(out);
+		}
+		let wallet_data = wallet(outputs);
+		let root_key_id = Identifier::zero();
+		let select = |amount| {
+			wallet_data.select_spendable_with_strategy(
+				root_key_id.clone(),
+				amount,
+				0,
+				100,
+				10,
+				1,
+				SelectionStrategy::OldestFirst,
+			)
+		};
+		let children = |outs: Vec<OutputData>| {
+			outs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+
+		let (coins, change) = select(15);
+		assert_eq!((children(coins), change), (vec![2], 5));
+		let (coins, change) = select(45);
+		assert_eq!((children(coins), change), (vec![2, 3], 5));
+		let (coins, change) = select(60);
+		assert_eq!((children(coins), change), (vec![2, 3, 1], 0));
+		// immature ones stay out, even though they would cover it
+		let (coins, change) = select(100);
+		assert_eq!((children(coins), change), (vec![2, 3, 1], -40));
+	}
+
+	#[test]
+	fn force_unlock_all_outputs() {
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Locked),
+			output(2, 20, OutputStatus::Locked),
+			output(3, 30, OutputStatus::Unspent),
+			output(4, 40, OutputStatus::Spent),
+		]);
+		let statuses = |wallet_data: &WalletData| {
+			wallet_data
+				.sorted_outputs()
+				.iter()
+				.map(|out| out.status.clone())
+				.collect::<Vec<_>>()
+		};
+
+		match wallet_data.force_unlock_all(false) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("without confirmation")),
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+		assert_eq!(
+			statuses(&wallet_data),
+			vec![
+				OutputStatus::Locked,
+				OutputStatus::Locked,
+				OutputStatus::Unspent,
+				OutputStatus::Spent,
+			]
+		);
+
+		assert_eq!(wallet_data.force_unlock_all(true).unwrap(), 2);
+		assert_eq!(
+			statuses(&wallet_data),
+			vec![
+				OutputStatus::Unspent,
+				OutputStatus::Unspent,
+				OutputStatus::Unspent,
+				OutputStatus::Spent,
+			]
+		);
+		assert_eq!(wallet_data.force_unlock_all(true).unwrap(), 0);
+	}
+
+	#[test]
+	fn wallet_diff() {
+		let before = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Unconfirmed),
+			output(3, 30, OutputStatus::Unspent),
+			output(4, 40, OutputStatus::Locked),
+		]);
+		let mut after = before.clone();
+		after.remove_output(&output(3, 30, OutputStatus::Spent).key_id);
+		after.add_output(output(6, 60, OutputStatus::Unconfirmed)).unwrap();
+		after.add_output(output(5, 50, OutputStatus::Unconfirmed)).unwrap();
+		let key = |n_child| output(n_child, 0, OutputStatus::Unspent).key();
+		after.outputs.get_mut(&key(2)).unwrap().status = OutputStatus::Unspent;
+
+		let diff = before.diff(&after);
+		assert_eq!(diff.added, vec![key(5), key(6)]);
+		assert_eq!(diff.removed, vec![key(3)]);
+		assert_eq!(
+			diff.status_changed,
+			vec![(key(2), OutputStatus::Unconfirmed, OutputStatus::Unspent)]
+		);
+
+		// the other way around
+		let diff = after.diff(&before);
+		assert_eq!(diff.added, vec![key(3)]);
+		assert_eq!(diff.removed, vec![key(5), key(6)]);
+		assert_eq!(
+			diff.status_changed,
+			vec![(key(2), OutputStatus::Unspent, OutputStatus::Unconfirmed)]
+		);
+		assert_eq!(before.diff(&before), WalletDiff::default());
+	}
+
+	#[test]
+	fn account_views() {
+		let root_a = Identifier::zero();
+		let root_b = Identifier::from_bytes(&[9]);
+		let in_b = |mut out: OutputData| {
+			out.root_key_id = root_b.clone();
+			out
+		};
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Locked),
+			in_b(output(3, 30, OutputStatus::Unspent)),
+			in_b(output(4, 40, OutputStatus::Unspent)),
+			in_b(output(5, 50, OutputStatus::Spent)),
+		]);
+		let children = |outs: Vec<&OutputData>| {
+			outs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+
+		let a = wallet_data.for_account(root_a.clone());
+		let b = wallet_data.for_account(root_b.clone());
+		assert_eq!(children(a.outputs()), vec![1, 2]);
+		assert_eq!(children(b.outputs()), vec![3, 4, 5]);
+
+		assert_eq!(a.balance().total, 30);
+		assert_eq!(a.balance().unspent, 10);
+		assert_eq!(b.balance().total, 70);
+		assert_eq!(b.balance(), wallet_data.balance(root_b.clone()));
+
+		let key_3 = output(3, 30, OutputStatus::Unspent).key_id;
+		assert!(a.get_output(&key_3).is_none());
+		assert_eq!(b.get_output(&key_3).unwrap().value, 30);
+
+		let (coins, change) = a.select(15, 0);
+		assert_eq!((values(&coins), change), (vec![10], -5));
+		let (coins, change) = b.select_checked(70).unwrap();
+		assert_eq!((values(&coins), change), (vec![30, 40], 0));
+	}
+
+	#[test]
+	fn select_max_amount() {
+		let mut immature = output(3, 1000, OutputStatus::Unspent);
+		immature.is_coinbase = true;
+		immature.height = 95;
+		let wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Unspent),
+			output(2, 40, OutputStatus::Unspent),
+			immature,
+			output(4, 500, OutputStatus::Locked),
+		]);
+
+		let (coins, amount, fee) =
+			wallet_data.select_max(Identifier::zero(), Some(1), 100, 10, 1).unwrap();
+		assert_eq!(values(&coins), vec![100, 40]);
+		assert_eq!(fee, tx_fee(2, 1, Some(1)));
+		assert_eq!(amount + fee, 140);
+
+		// once the coinbase matures it goes too
+		let (coins, amount, fee) =
+			wallet_data.select_max(Identifier::zero(), Some(1), 105, 10, 1).unwrap();
+		assert_eq!(coins.len(), 3);
+		assert_eq!(amount + fee, 1140);
+
+		// dust the fee eats entirely
+		let dust = wallet(vec![output(1, tx_fee(1, 1, Some(10)), OutputStatus::Unspent)]);
+		match dust.select_max(Identifier::zero(), Some(10), 100, 10, 1) {
+			Err(Error::NotEnoughFunds { available, .. }) => {
+				assert_eq!(available, tx_fee(1, 1, Some(10)))
+			}
+			res => panic!("expected not enough funds, got {:?}", res.map(|r| r.1)),
+		}
+		assert!(WalletData::new().select_max(Identifier::zero(), Some(1), 100, 10, 1).is_err());
+	}
+
+	#[test]
+	fn output_age_histogram() {
+		let aged = |n_child, value, height| {
+			let mut out = output(n_child, value, OutputStatus::Unspent);
+			out.height = height;
+			out
+		};
+		let mut coinbase = aged(4, 1000, 95);
+		coinbase.status = OutputStatus::Immature;
+		let mut other_root = aged(9, 90, 92);
+		other_root.root_key_id = Identifier::from_bytes(&[7]);
+		let wallet_data = wallet(vec![
+			aged(1, 10, 100),
+			aged(2, 20, 92),
+			coinbase,
+			aged(5, 50, 45),
+			aged(6, 60, 41),
+			output(7, 70, OutputStatus::Locked),
+			output(8, 80, OutputStatus::Spent),
+			other_root,
+		]);
+		let root = Identifier::zero();
+
+		// confirmed at 41 and 45, then 92, then 100, nothing in between
+		let histogram = wallet_data.age_histogram(root.clone(), 100, 10, 3, 0);
+		assert_eq!(histogram, vec![(40, 2, 110), (90, 1, 20), (100, 1, 10)]);
+
+		// not confirmed enough to be spent yet
+		let histogram = wallet_data.age_histogram(root.clone(), 100, 100, 3, 1);
+		assert_eq!(histogram, vec![(0, 3, 130)]);
+		assert!(WalletData::new().age_histogram(root, 100, 10, 3, 0).is_empty());
+	}
+
+	#[test]
+	fn projected_spendable_balance() {
+		let coinbase = |n_child, status, height| {
+			let mut out = output(n_child, 1000, status);
+			out.is_coinbase = true;
+			out.height = height;
+			out
+		};
+		let mut future = output(5, 7, OutputStatus::Unspent);
+		future.height = 150;
+		let wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Unspent),
+			coinbase(2, OutputStatus::Immature, 95),
+			coinbase(3, OutputStatus::Unspent, 120),
+			output(4, 500, OutputStatus::Locked),
+			future,
+			output(6, 60, OutputStatus::Unconfirmed),
+		]);
+		let root_key_id = Identifier::zero();
+
+		// both coinbase outputs are still immature now
+		assert_eq!(wallet_data.projected_spendable(root_key_id.clone(), 100, 10), 100);
+		// the first one matured
+		assert_eq!(wallet_data.projected_spendable(root_key_id.clone(), 105, 10), 1100);
+		// both matured, along with the confirmed output past the current height
+		assert_eq!(wallet_data.projected_spendable(root_key_id.clone(), 200, 10), 2107);
+
+		let other_root = Identifier::from_bytes(&[9]);
+		assert_eq!(wallet_data.projected_spendable(other_root, 200, 10), 0);
+	}
+
+	#[test]
+	fn select_spendable_min_confirmations() {
+		let mut recent = output(2, 50, OutputStatus::Unspent);
+		recent.height = 100;
+		let wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent), recent]);
+		let root_key_id = Identifier::zero();
+		let config = WalletConfig {
+			min_confirmations: 3,
+			..WalletConfig::default()
+		};
+		let select = |current_height| {
+			let (coins, _) = wallet_data.select_spendable(
+				root_key_id.clone(),
+				40,
+				0,
+				current_height,
+				10,
+				config.min_confirmations,
+			);
+			values(&coins)
+		};
+
+		// 2 confirmations only, left out
+		assert_eq!(select(102), vec![10]);
+		assert_eq!(select(103), vec![10, 50]);
+		assert_eq!(WalletConfig::default().min_confirmations, 1);
+	}
+
+	#[test]
+	fn estimate_and_select_spendable_outputs() {
+		let mut recent = output(2, 500, OutputStatus::Unspent);
+		recent.height = 100;
+		let wallet_data = wallet(vec![output(1, 100, OutputStatus::Unspent), recent]);
+		let estimate = |amount, current_height| {
+			wallet_data.estimate_and_select_spendable(
+				Identifier::zero(),
+				amount,
+				Some(1),
+				1,
+				0,
+				current_height,
+				10,
+				3,
+			)
+		};
+
+		// the recent output isn't confirmed enough to count yet
+		match estimate(200, 102) {
+			Err(Error::NotEnoughFunds { available, .. }) => assert_eq!(available, 100),
+			res => panic!("expected not enough funds, got {:?}", res.map(|r| r.1)),
+		}
+		let (coins, fee, change) = estimate(200, 103).unwrap();
+		assert_eq!(values(&coins), vec![100, 500]);
+		assert_eq!(fee, tx_fee(2, 2, Some(1)));
+		assert_eq!(change, 400 - fee as i64);
+	}
+
+	#[test]
+	fn select_spendable_respects_lock_height() {
+		let mut locked = output(2, 50, OutputStatus::Unspent);
+		locked.lock_height = 100;
+		let wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent), locked]);
+		let root_key_id = Identifier::zero();
+
+		let (coins, change) = wallet_data.select_spendable(root_key_id.clone(), 40, 0, 99, 10, 1);
+		assert_eq!(values(&coins), vec![10]);
+		assert_eq!(change, -30);
+
+		let (coins, change) = wallet_data.select_spendable(root_key_id.clone(), 40, 0, 100, 10, 1);
+		assert_eq!(values(&coins), vec![10, 50]);
+		assert_eq!(change, 20);
+
+		let (coins, _) = wallet_data.select_spendable(root_key_id.clone(), 40, 0, 150, 10, 1);
+		assert!(coins.iter().any(|out| out.n_child == 2));
+	}
+
+	#[test]
+	fn synced_writes() {
+		let dir = test_dir("synced_writes");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent)]);
+		wallet_data.write_file(&data_file_path, WalletFormat::Json, true).unwrap();
+		let synced = fs::read(&data_file_path).unwrap();
+		wallet_data.write_file(&data_file_path, WalletFormat::Json, false).unwrap();
+		assert_eq!(fs::read(&data_file_path).unwrap(), synced);
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+		assert!(WalletConfig::default().sync_writes);
+		let config = WalletConfig {
+			sync_writes: true,
+			..test_config(&dir)
+		};
+		WalletData::with_wallet(&config, |w| {
+			w.add_output(output(2, 20, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		assert_eq!(WalletData::read(&data_file_path).unwrap().outputs.len(), 2);
+
+		// a file name alone is in the current directory
+		assert!(sync_parent_dir(DAT_FILE).is_ok());
+	}
+
+	#[test]
+	fn failed_write_leaves_wallet_intact() {
+		let dir = test_dir("failed_write");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent)]);
+		wallet_data.write_file(&data_file_path, WalletFormat::Json, false).unwrap();
+		let before = fs::read(&data_file_path).unwrap();
+
+		// partially write something else then fail, as a full disk would
+		let res = write_atomic(&data_file_path, false, |file| {
+			file.write_all(b"{\"outputs\": {").unwrap();
+			Err(Error::WalletData("disk full".to_string()))
+		});
+		assert!(res.is_err());
+		assert_eq!(fs::read(&data_file_path).unwrap(), before);
+
+		// the temporary file got cleaned up and the wallet still reads fine
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+		let read_back = WalletData::read(&data_file_path).unwrap();
+		assert_eq!(read_back.outputs.len(), 1);
+	}
+
+	#[test]
+	fn wallet_lock_fresh() {
+		let dir = test_dir("lock_fresh");
+		let lock_config = LockConfig::default();
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+
+		with_test_wallet(&dir, &lock_config, |wallet_data| {
+			// we hold the lock, with our PID in it
+			let content = fs::read_to_string(&lock_file_path).unwrap();
+			assert!(content.starts_with(&format!("{} ", process::id())));
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		assert!(!Path::new(&lock_file_path).exists());
+	}
+
+	#[test]
+	fn wallet_lock_stale_reclaim() {
+		let dir = test_dir("lock_stale");
+		let lock_config = LockConfig::default();
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+
+		// lock left behind a long time ago by some process
+		fs::write(&lock_file_path, format!("{} {}", process::id(), 1000)).unwrap();
+		with_test_wallet(&dir, &lock_config, |_| ()).unwrap();
+		assert!(!Path::new(&lock_file_path).exists());
+
+		// recent lock, but from a process that isn't running anymore
+		if cfg!(target_os = "linux") {
+			fs::write(&lock_file_path, format!("{} {}", u32::max_value(), now_secs())).unwrap();
+			with_test_wallet(&dir, &lock_config, |_| ()).unwrap();
+			assert!(!Path::new(&lock_file_path).exists());
+		}
+	}
+
+	#[test]
+	fn wallet_lock_stale_reclaim_race() {
+		let dir = test_dir("lock_stale_race");
+		let lock_config = LockConfig::default();
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+
+		fs::write(&lock_file_path, format!("{} {}", process::id(), 1000)).unwrap();
+		let stale_content = stale_lock_content(&lock_file_path, &lock_config).unwrap();
+
+		// another process reclaimed the lock and holds a fresh one by now
+		let fresh_content = format!("{} {}", process::id(), now_secs());
+		fs::write(&lock_file_path, &fresh_content).unwrap();
+		assert!(stale_lock_content(&lock_file_path, &lock_config).is_none());
+		assert!(!remove_stale_lock(&lock_file_path, &stale_content));
+		assert_eq!(fs::read_to_string(&lock_file_path).unwrap(), fresh_content);
+
+		// and once released, there's nothing left to remove
+		fs::remove_file(&lock_file_path).unwrap();
+		assert!(remove_stale_lock(&lock_file_path, &stale_content));
+		let leftovers = fs::read_dir(&dir).unwrap().count();
+		assert_eq!(leftovers, 0);
+	}
+
+	#[test]
+	fn wallet_lock_live_contention() {
+		let dir = test_dir("lock_live");
+		let lock_config = LockConfig::default();
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+
+		// lock currently held by a live process (us)
+		fs::write(&lock_file_path, format!("{} {}", process::id(), now_secs())).unwrap();
+		match with_test_wallet(&dir, &lock_config, |_| ()) {
+			Err(Error::WalletData(_)) => {}
+			res => panic!("expected a lock error, got {:?}", res),
+		}
+		assert!(Path::new(&lock_file_path).exists());
+	}
+
+	#[test]
+	fn wallet_lock_configured_retries() {
+		let dir = test_dir("lock_retries");
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+		fs::write(&lock_file_path, format!("{} {}", process::id(), now_secs())).unwrap();
+
+		// waits of 10, 20, 40, 80 and 160ms before giving up
+		let lock_config = LockConfig {
+			max_retries: 5,
+			backoff_ms: 10,
+			backoff_multiplier: 2,
+			..LockConfig::default()
+		};
+		let start = time::Instant::now();
+		match with_test_wallet(&dir, &lock_config, |_| ()) {
+			Err(Error::WalletData(_)) => {}
+			res => panic!("expected a lock error, got {:?}", res),
+		}
+		assert!(start.elapsed() >= time::Duration::from_millis(310));
+
+		// no retry at all
+		let lock_config = LockConfig {
+			max_retries: 0,
+			backoff_ms: 10_000,
+			..LockConfig::default()
+		};
+		let start = time::Instant::now();
+		let res = with_test_wallet(&dir, &lock_config, |_| ());
+		assert!(res.is_err());
+		assert!(start.elapsed() < time::Duration::from_millis(10_000));
+	}
+
+	#[test]
+	fn dir_lock_released_by_acquired_key() {
+		// the directory only appears while the lock is held, so it resolves
+		// differently on release than on acquire
+		let dir = wallet_file_path(&test_dir("dir_lock_key"), "wallet");
+		let lock_config = LockConfig {
+			max_retries: 0,
+			..LockConfig::default()
+		};
+		let mut backend = FileBackend::new(&dir, &lock_config);
+		backend.acquire_read().unwrap();
+		fs::create_dir(&dir).unwrap();
+		backend.release().unwrap();
+		{
+			let held = DIR_LOCKS.0.lock().unwrap();
+			assert!(!held.contains(&dir) && !held.contains(&dir_lock_key(&dir)));
+		}
+
+		// and can be taken again right away
+		fs::remove_dir(&dir).unwrap();
+		backend.acquire_read().unwrap();
+		backend.release().unwrap();
+	}
+
+	#[test]
+	fn wallet_lock_threads() {
+		let dir = test_dir("lock_threads");
+		let lock_config = LockConfig {
+			max_retries: 100,
+			backoff_ms: 10,
+			..LockConfig::default()
+		};
+
+		// each thread reads the wallet data, adds an output and writes it
+		// back, an update would get lost if two of them overlapped
+		let threads = (1..9)
+			.map(|n_child| {
+				let dir = dir.clone();
+				let lock_config = lock_config.clone();
+				thread::spawn(move || {
+					with_test_wallet(&dir, &lock_config, |wallet_data| {
+						let out = output(n_child, n_child as u64 * 10, OutputStatus::Unspent);
+						wallet_data.add_output(out).unwrap();
+						thread::sleep(time::Duration::from_millis(5));
+					})
+				})
+			})
+			.collect::<Vec<_>>();
+		for handle in threads {
+			handle.join().unwrap().unwrap();
+		}
+
+		let config = WalletConfig {
+			lock: lock_config,
+			..test_config(&dir)
+		};
+		let count = WalletData::read_wallet(&config, |w| w.outputs.len()).unwrap();
+		assert_eq!(count, 8);
+		assert!(!Path::new(&wallet_file_path(&dir, LOCK_FILE)).exists());
+
+		// the directory is free again for this thread
+		let res = WalletData::read_wallet(&test_config(&dir), |_| ());
+		assert!(res.is_ok());
+	}
+
+	#[test]
+	fn read_snapshot_from_path() {
+		let dir = test_dir("read_from_path");
+		let snapshot_path = wallet_file_path(&dir, "snapshot.dat");
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Locked),
+		]);
+		wallet_data.write_file(&snapshot_path, WalletFormat::Json, false).unwrap();
+		let before = fs::read(&snapshot_path).unwrap();
+
+		let read = WalletData::read_from_path(&snapshot_path).unwrap();
+		assert_eq!(read.diff(&wallet_data), WalletDiff::default());
+		assert_eq!(read.outputs.len(), 2);
+		assert_eq!(fs::read(&snapshot_path).unwrap(), before);
+
+		match WalletData::read_from_path(&wallet_file_path(&dir, "missing.dat")) {
+			Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+			res => panic!("expected a not found error, got {:?}", res.map(|w| w.version)),
+		}
+
+		let corrupt_path = wallet_file_path(&dir, "corrupt.dat");
+		fs::write(&corrupt_path, "{\"outputs\": {").unwrap();
+		match WalletData::read_from_path(&corrupt_path) {
+			Err(Error::WalletCorrupt(_)) => {}
+			res => panic!("expected a corrupt error, got {:?}", res.map(|w| w.version)),
+		}
+
+		// no lock file was ever taken, only our own files are there
+		assert!(!Path::new(&wallet_file_path(&dir, LOCK_FILE)).exists());
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+	}
+
+	#[test]
+	fn outputs_stored_without_key_id() {
+		let legacy = "{\"version\":1,\"outputs\":{\"01000100000000000000\":{\
+			\"root_key_id\":\"00000000000000000000\",\"key_id\":\"01000100000000000000\",\
+			\"n_child\":1,\"value\":10,\"status\":\"Unspent\",\"height\":3,\
+			\"lock_height\":0,\"zero_ok\":false,\"label\":\"mined\"}}}";
+		let current = "{\"version\":3,\"outputs\":{\"01000100000000000000\":{\
+			\"root_key_id\":\"00000000000000000000\",\
+			\"n_child\":1,\"value\":10,\"status\":\"Unspent\",\"height\":3,\
+			\"lock_height\":0,\"zero_ok\":false,\"label\":\"mined\"}}}";
+
+		let mut from_legacy: WalletData = serde_json::from_str(legacy).unwrap();
+		from_legacy.migrate().unwrap();
+		let from_current: WalletData = serde_json::from_str(current).unwrap();
+		assert_eq!(from_legacy.version, from_current.version);
+		assert_eq!(
+			format!("{:?}", from_legacy.sorted_outputs()),
+			format!("{:?}", from_current.sorted_outputs())
+		);
+		let out = from_current.get_output(&output(1, 10, OutputStatus::Unspent).key_id).unwrap();
+		assert_eq!(out.key(), output(1, 10, OutputStatus::Unspent).key());
+		assert_eq!(out.label, Some("mined".to_string()));
+
+		// written back without the key_id, reading the same
+		let written = serde_json::to_string(&from_legacy).unwrap();
+		assert!(!written.contains("\"key_id\""));
+		let read_back: WalletData = serde_json::from_str(&written).unwrap();
+		assert_eq!(
+			format!("{:?}", read_back.sorted_outputs()),
+			format!("{:?}", from_current.sorted_outputs())
+		);
+
+		// a repeated key_id disagreeing with the key, or a key that isn't one
+		let mismatched = legacy.replace("\"key_id\":\"0100010", "\"key_id\":\"0100020");
+		assert!(serde_json::from_str::<WalletData>(&mismatched).is_err());
+		let bad_key = current.replace("01000100000000000000", "not a key");
+		assert!(serde_json::from_str::<WalletData>(&bad_key).is_err());
+	}
+
+	#[test]
+	fn migrate_unversioned_wallet() {
+		let dir = test_dir("migrate");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+
+		// wallet.dat as written before versioning
+		fs::write(
+			&data_file_path,
+			"{\"outputs\":{\"01000100000000000000\":{\
+				\"root_key_id\":\"00000000000000000000\",\"key_id\":\"01000100000000000000\",\
+				\"n_child\":1,\"value\":10,\"status\":\"Unspent\",\"height\":3,\
+				\"lock_height\":0,\"zero_ok\":false}}}",
+		).unwrap();
+		assert_eq!(WalletData::read(&data_file_path).unwrap().version, 0);
+
+		let wallet_data = WalletData::read_or_create(&data_file_path).unwrap();
+		assert_eq!(wallet_data.version, WALLET_DATA_VERSION);
+		assert_eq!(wallet_data.outputs.len(), 1);
+		assert!(wallet_data.tx_log.is_empty());
+
+		// but we don't pretend to understand future versions
+		fs::write(&data_file_path, "{\"version\":1000,\"outputs\":{}}").unwrap();
+		match WalletData::read_or_create(&data_file_path) {
+			Err(Error::WalletData(_)) => {}
+			res => panic!("expected a version error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn partial_tx_versions() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+		let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+		let blind_hex = util::to_hex(blind_sum.secret_key().as_ref().to_vec());
+
+		// what we produce we can read back
+		let json = partial_tx_to_json(300, blind_sum.clone(), tx.clone()).unwrap();
+		let (amount, _, read_tx) = partial_tx_from_json(&keychain, &json).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(read_tx.hash(), tx.hash());
+
+		// legacy payloads without a version
+		let legacy = format!(
+			"{{\"amount\":300,\"blind_sum\":\"{}\",\"tx\":\"{}\"}}",
+			blind_hex,
+			tx_hex
+		);
+		let (amount, _, read_tx) = partial_tx_from_json(&keychain, &legacy).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(read_tx.hash(), tx.hash());
+
+		// newer versions we don't know about
+		let newer = serde_json::to_string(&JSONPartialTx {
+			version: PARTIAL_TX_VERSION + 1,
+			amount: 300,
+			blind_sum: blind_hex,
+			tx: tx_hex,
+		}).unwrap();
+		match partial_tx_from_json(&keychain, &newer) {
+			Err(Error::Format(msg)) => {
+				assert!(msg.contains(&format!("version {}", PARTIAL_TX_VERSION + 1)));
+				assert!(msg.contains(&format!("version {}", PARTIAL_TX_VERSION)));
+			}
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		}
+		// checked the same way without decoding
+		let expected = check_partial_tx_version(PARTIAL_TX_VERSION + 1);
+		match (PartialTx::from_json(&newer), expected) {
+			(Err(Error::Format(msg)), Err(Error::Format(expected))) => assert_eq!(msg, expected),
+			res => panic!("expected format errors, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn partial_tx_unknown_fields() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+		let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+		let blind_hex = util::to_hex(blind_sum.secret_key().as_ref().to_vec());
+		let payload = |version: u16, extra: &str| {
+			format!(
+				"{{\"version\":{},\"amount\":300,\"blind_sum\":\"{}\",\"tx\":\"{}\"{}}}",
+				version,
+				blind_hex,
+				tx_hex,
+				extra
+			)
+		};
+		let format_error = |json: String| match partial_tx_from_json(&keychain, &json) {
+			Err(Error::Format(msg)) => msg,
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		};
+
+		// exactly the fields we know
+		let exact = payload(PARTIAL_TX_VERSION, "");
+		let (amount, _, read_tx) = partial_tx_from_json(&keychain, &exact).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(read_tx.hash(), tx.hash());
+		PartialTx::from_json(&exact).unwrap();
+
+		// an extra field at our version gets named
+		let extra = payload(PARTIAL_TX_VERSION, ",\"lock_height\":5");
+		assert!(format_error(extra.clone()).contains("lock_height"));
+		assert!(PartialTx::from_json(&extra).is_err());
+
+		// from a newer version, the version is what we report
+		let newer = payload(PARTIAL_TX_VERSION + 1, ",\"lock_height\":5");
+		let msg = format_error(newer);
+		assert!(msg.contains(&format!("version {}", PARTIAL_TX_VERSION + 1)));
+	}
+
+	#[test]
+	fn partial_tx_to_json_fields() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+
+		let json = partial_tx_to_json(300, blind_sum.clone(), tx.clone()).unwrap();
+		let partial: JSONPartialTx = serde_json::from_str(&json).unwrap();
+		assert_eq!(partial.version, PARTIAL_TX_VERSION);
+		assert_eq!(partial.amount, 300);
+		assert_eq!(
+			partial.blind_sum,
+			util::to_hex(blind_sum.secret_key().as_ref().to_vec())
+		);
+		assert_eq!(partial.tx, util::to_hex(ser::ser_vec(&tx).unwrap()));
+	}
+
+	#[test]
+	fn partial_tx_blind_length() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+		let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+		let blind_bin = blind_sum.secret_key().as_ref().to_vec();
+		let json = |blind: Vec<u8>| {
+			serde_json::to_string(&JSONPartialTx {
+				version: PARTIAL_TX_VERSION,
+				amount: 300,
+				blind_sum: util::to_hex(blind),
+				tx: tx_hex.clone(),
+			}).unwrap()
+		};
+		let expected = format!(
+			"blinding factor must be {} bytes",
+			secp::constants::SECRET_KEY_SIZE
+		);
+
+		let short = blind_bin[..31].to_vec();
+		match partial_tx_from_json(&keychain, &json(short)) {
+			Err(Error::Format(msg)) => assert_eq!(msg, expected),
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		}
+
+		let mut long = blind_bin.clone();
+		long.push(0);
+		match partial_tx_from_json(&keychain, &json(long)) {
+			Err(Error::Format(msg)) => assert_eq!(msg, expected),
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		}
+
+		let (amount, _, read_tx) = partial_tx_from_json(&keychain, &json(blind_bin)).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(read_tx.hash(), tx.hash());
+	}
+
+	#[test]
+	fn partial_tx_bad_tx_reports_length() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (_, blind_sum) = partial_tx(&keychain);
+		let json = serde_json::to_string(&JSONPartialTx {
+			version: PARTIAL_TX_VERSION,
+			amount: 300,
+			blind_sum: util::to_hex(blind_sum.secret_key().as_ref().to_vec()),
+			tx: util::to_hex(vec![1, 2, 3]),
+		}).unwrap();
+		match partial_tx_from_json(&keychain, &json) {
+			Err(Error::Format(msg)) => assert!(msg.contains("(3 bytes)")),
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		}
+	}
+
+	#[test]
+	fn partial_tx_bytes_roundtrip() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+
+		let bytes = partial_tx_to_bytes(300, blind_sum.clone(), tx.clone()).unwrap();
+		assert_eq!(&bytes[..4], b"GRPT");
+		assert_eq!(bytes[4], PARTIAL_TX_BIN_VERSION);
+
+		let (amount, blind, read_tx) = partial_tx_from_bytes(&keychain, &bytes).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(
+			blind.secret_key().as_ref().to_vec(),
+			blind_sum.secret_key().as_ref().to_vec()
+		);
+		assert_eq!(read_tx.hash(), tx.hash());
+
+		// hex alone doubles the payload, the binary form should be well under
+		// two thirds of the JSON one
+		let json = partial_tx_to_json(300, blind_sum, tx).unwrap();
+		assert!(bytes.len() * 3 < json.len() * 2);
+
+		// JSON is not mistaken for the binary format
+		match partial_tx_from_bytes(&keychain, json.as_bytes()) {
+			Err(Error::Format(_)) => {}
+			res => panic!("expected a format error, got {:?}", res.map(|r| r.0)),
+		}
+	}
+
+	#[test]
+	fn cb_data_checksum() {
+		let cb_data = CbData::new(
+			"8a90bc".to_string(),
+			"f083de".to_string(),
+			"0100010000".to_string(),
+		);
+		assert!(cb_data.verify().is_ok());
+
+		let mut tampered = cb_data.clone();
+		tampered.kernel = "f083df".to_string();
+		match tampered.verify() {
+			Err(Error::Format(msg)) => assert!(msg.contains("checksum mismatch")),
+			res => panic!("expected a format error, got {:?}", res),
+		}
+
+		// moving bytes across fields changes the checksum too
+		let mut shifted = cb_data.clone();
+		shifted.output = "8a90bcf0".to_string();
+		shifted.kernel = "83de".to_string();
+		assert!(shifted.verify().is_err());
+	}
+
+	#[test]
+	fn cb_data_json_roundtrip() {
+		let cb_data = CbData::new(
+			"8a90bc".to_string(),
+			"f083de".to_string(),
+			"01000100000000000000".to_string(),
+		);
+		let json = cb_data.to_json().unwrap();
+		let parsed = CbData::from_json(&json).unwrap();
+		assert_eq!(parsed.output, cb_data.output);
+		assert_eq!(parsed.kernel, cb_data.kernel);
+		assert_eq!(parsed.key_id, cb_data.key_id);
+		assert_eq!(parsed.checksum, cb_data.checksum);
+		assert!(parsed.verify().is_ok());
+
+		// not json, or missing fields
+		assert!(CbData::from_json("coinbase").is_err());
+		assert!(CbData::from_json("{\"output\": \"8a90bc\"}").is_err());
+
+		// older wallets don't send a checksum
+		let legacy = "{\"output\": \"8a90bc\", \"kernel\": \"f083de\", \
+		              \"key_id\": \"01000100000000000000\"}";
+		let parsed = CbData::from_json(legacy).unwrap();
+		assert!(parsed.checksum.is_empty());
+		assert!(parsed.verify().is_ok());
+
+		// key_id of the wrong length
+		let mut short_key = cb_data.clone();
+		short_key.key_id = "0100".to_string();
+		match CbData::from_json(&short_key.to_json().unwrap()) {
+			Err(Error::Format(msg)) => assert!(msg.contains("key_id must be 10 bytes")),
+			res => panic!("expected a format error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn cb_data_malformed_output() {
+		for output in &["8a90bz", "8a90b", ""] {
+			let cb_data = CbData::new(
+				output.to_string(),
+				"f083de".to_string(),
+				"01000100000000000000".to_string(),
+			);
+			match CbData::from_json(&cb_data.to_json().unwrap()) {
+				Err(Error::Format(msg)) => {
+					assert_eq!(msg, "invalid coinbase data: field output is not valid hex")
+				}
+				res => panic!("expected a format error, got {:?}", res),
+			}
+		}
+	}
+
+	#[test]
+	fn block_fees_coinbase_amount() {
+		let block_fees = |fees, min_fees, max_fees| {
+			BlockFees {
+				fees: fees,
+				height: 1,
+				key_id: None,
+				min_fees: min_fees,
+				max_fees: max_fees,
+			}
+		};
+
+		// no range, reward plus fees
+		assert_eq!(block_fees(30, None, None).coinbase_amount(1000), 1030);
+
+		// within range
+		assert_eq!(block_fees(30, Some(10), Some(50)).coinbase_amount(1000), 1030);
+
+		// clamped to either bound
+		assert_eq!(block_fees(5, Some(10), Some(50)).coinbase_amount(1000), 1010);
+		assert_eq!(block_fees(80, Some(10), Some(50)).coinbase_amount(1000), 1050);
+		assert_eq!(block_fees(5, Some(10), None).coinbase_amount(1000), 1010);
+		assert_eq!(block_fees(80, None, Some(50)).coinbase_amount(1000), 1050);
+	}
+
+	#[test]
+	fn read_wallet_does_not_write() {
+		let dir = test_dir("read_only_access");
+		let lock_config = LockConfig::default();
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+
+		with_test_wallet(&dir, &lock_config, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		let content = fs::read(&data_file_path).unwrap();
+		let modified = fs::metadata(&data_file_path).unwrap().modified().unwrap();
+
+		let config = test_config(&dir);
+		let count = WalletData::read_wallet(&config, |w| w.outputs.len()).unwrap();
+		assert_eq!(count, 1);
+
+		assert_eq!(fs::read(&data_file_path).unwrap(), content);
+		assert_eq!(fs::metadata(&data_file_path).unwrap().modified().unwrap(), modified);
+		assert!(!Path::new(&lock_file_path).exists());
+
+		// nothing gets created for a wallet that doesn't exist yet either
+		let empty_dir = test_dir("read_only_access_empty");
+		WalletData::read_wallet(&test_config(&empty_dir), |wallet_data| {
+			assert!(wallet_data.outputs.is_empty());
+		}).unwrap();
+		assert!(!Path::new(&wallet_file_path(&empty_dir, DAT_FILE)).exists());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn read_only_data_dir() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = test_dir("read_only_dir");
+		let lock_config = LockConfig::default();
+
+		// the probe leaves nothing behind on a writable directory
+		with_test_wallet(&dir, &lock_config, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		let mut files = fs::read_dir(&dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect::<Vec<_>>();
+		files.sort();
+		assert_eq!(files, vec![DAT_FILE.to_string()]);
+
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+		// permissions don't apply to root, nothing to test then
+		let writable = File::create(wallet_file_path(&dir, "probe")).is_ok();
+		if !writable {
+			let expected = format!("data directory is not writable: {}", dir);
+			match with_test_wallet(&dir, &lock_config, |_| ()) {
+				Err(Error::WalletData(msg)) => assert_eq!(msg, expected),
+				res => panic!("expected a wallet data error, got {:?}", res),
+			}
+
+			// but it can still be read
+			let count = WalletData::read_wallet(&test_config(&dir), |w| w.outputs.len());
+			assert_eq!(count.unwrap(), 1);
+		}
+
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+	}
+
+	#[test]
+	fn find_outputs_with_value() {
+		let wallet_data = wallet(vec![
+			output(1, 50, OutputStatus::Unspent),
+			output(2, 50, OutputStatus::Spent),
+			output(3, 60, OutputStatus::Unspent),
+		]);
+
+		let mut found = wallet_data
+			.find_outputs_by_value(50)
+			.iter()
+			.map(|out| out.n_child)
+			.collect::<Vec<_>>();
+		found.sort();
+		assert_eq!(found, vec![1, 2]);
+
+		assert!(wallet_data.find_outputs_by_value(70).is_empty());
+	}
+
+	#[test]
+	fn lock_output_checks() {
+		let mut wallet_data = wallet(vec![output(1, 50, OutputStatus::Unspent)]);
+
+		// matching value locks
+		let out1 = output(1, 50, OutputStatus::Unspent);
+		wallet_data.lock_output(&out1, None).unwrap();
+		assert_eq!(wallet_data.get_output(&out1.key_id).unwrap().status, OutputStatus::Locked);
+
+		// mismatched value errors and doesn't touch the output
+		let mut wallet_data = wallet(vec![output(1, 50, OutputStatus::Unspent)]);
+		match wallet_data.lock_output(&output(1, 60, OutputStatus::Unspent), None) {
+			Err(Error::WalletData(msg)) => {
+				assert!(msg.contains("expected value 60 but found 50"));
+			}
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+		assert_eq!(wallet_data.get_output(&out1.key_id).unwrap().status, OutputStatus::Unspent);
+
+		// absent key errors
+		match wallet_data.lock_output(&output(2, 50, OutputStatus::Unspent), None) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("not found")),
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn unlock_outputs() {
+		let mut wallet_data = wallet(vec![
+			output(1, 50, OutputStatus::Unspent),
+			output(2, 60, OutputStatus::Spent),
+			output(3, 70, OutputStatus::Unconfirmed),
+			output(4, 80, OutputStatus::Locked),
+		]);
+		let out1 = output(1, 50, OutputStatus::Unspent);
+
+		// a locked output is spendable again once unlocked
+		wallet_data.lock_output(&out1, None).unwrap();
+		assert!(wallet_data.select_checked(Identifier::zero(), 50).is_err());
+		wallet_data.unlock_output(&out1.key_id).unwrap();
+		assert_eq!(wallet_data.get_output(&out1.key_id).unwrap().status, OutputStatus::Unspent);
+		let (coins, _) = wallet_data.select_checked(Identifier::zero(), 50).unwrap();
+		assert_eq!(values(&coins), vec![50]);
+
+		// spent, unconfirmed, already unlocked and unknown outputs are refused
+		for n_child in vec![1, 2, 3, 5] {
+			let key_id = output(n_child, 0, OutputStatus::Unspent).key_id;
+			match wallet_data.unlock_output(&key_id) {
+				Err(Error::WalletData(_)) => {}
+				res => panic!("expected a wallet data error, got {:?}", res),
+			}
+		}
+		assert_eq!(values(&wallet_data.unspent_outputs(&Identifier::zero())), vec![50]);
+
+		wallet_data.lock_output(&out1, None).unwrap();
+		assert_eq!(wallet_data.unlock_all_locked(), 2);
+		assert_eq!(values(&wallet_data.unspent_outputs(&Identifier::zero())), vec![50, 80]);
+		assert_eq!(wallet_data.unlock_all_locked(), 0);
+	}
+
+	#[test]
+	fn expire_old_locks() {
+		let mut wallet_data = wallet(vec![
+			output(1, 50, OutputStatus::Unspent),
+			output(2, 60, OutputStatus::Unspent),
+			output(3, 70, OutputStatus::Unspent),
+		]);
+		let window = 10;
+
+		// locked at heights 100 and 120, and one without expiry
+		let out1 = output(1, 50, OutputStatus::Unspent);
+		let out2 = output(2, 60, OutputStatus::Unspent);
+		let out3 = output(3, 70, OutputStatus::Unspent);
+		wallet_data.lock_output(&out1, Some(100 + window)).unwrap();
+		wallet_data.lock_output(&out2, Some(120 + window)).unwrap();
+		wallet_data.lock_output(&out3, None).unwrap();
+
+		// still within the window of both
+		assert_eq!(wallet_data.expire_locks(110), 0);
+
+		// the older lock is past its window, the recent one is not
+		assert_eq!(wallet_data.expire_locks(111), 1);
+		let unlocked = wallet_data.get_output(&out1.key_id).unwrap();
+		assert_eq!(unlocked.status, OutputStatus::Unspent);
+		assert_eq!(unlocked.locked_until, None);
+		assert_eq!(wallet_data.get_output(&out2.key_id).unwrap().status, OutputStatus::Locked);
+
+		// locks without expiry are left alone
+		assert_eq!(wallet_data.expire_locks(1000), 1);
+		assert_eq!(wallet_data.get_output(&out3.key_id).unwrap().status, OutputStatus::Locked);
+	}
+
+	#[test]
+	fn estimate_and_select_fee() {
+		let wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Unspent),
+			output(2, 40, OutputStatus::Unspent),
+		]);
+
+		// the amount alone fits in one input but not with the fee on top, so a
+		// second input gets pulled in and the fee recomputed for both
+		let (coins, fee, change) =
+			wallet_data.estimate_and_select(Identifier::zero(), 100, Some(1), 1, 0).unwrap();
+		assert_eq!(values(&coins), vec![40, 100]);
+		assert_eq!(fee, tx_fee(2, 2, Some(1)));
+		assert_eq!(change, 140 - 100 - fee as i64);
+
+		// enough room for the fee with a single input
+		let (coins, fee, change) =
+			wallet_data.estimate_and_select(Identifier::zero(), 30, Some(1), 1, 0).unwrap();
+		assert_eq!(values(&coins), vec![100]);
+		assert_eq!(fee, tx_fee(1, 2, Some(1)));
+		assert_eq!(change, 100 - 30 - fee as i64);
+
+		// can't cover the fee at all
+		match wallet_data.estimate_and_select(Identifier::zero(), 135, Some(1), 1, 0) {
+			Err(Error::NotEnoughFunds { available, needed }) => {
+				assert_eq!(available, 140);
+				assert_eq!(needed, 135 + tx_fee(1, 2, Some(1)));
+			}
+			res => panic!("expected not enough funds, got {:?}", res.map(|r| r.1)),
+		}
+	}
+
+	#[test]
+	fn estimate_and_select_bad_amounts() {
+		let wallet_data = wallet(vec![output(1, u64::max_value(), OutputStatus::Unspent)]);
+
+		match wallet_data.estimate_and_select(Identifier::zero(), 0, Some(1), 1, 0) {
+			Err(Error::WalletData(msg)) => assert_eq!(msg, "amount must be greater than zero"),
+			res => panic!("expected a wallet data error, got {:?}", res.map(|r| r.1)),
+		}
+
+		// would pass for covered if the total saturated
+		let fee = tx_fee(1, 2, Some(1));
+		let amount = u64::max_value() - fee + 1;
+		match wallet_data.estimate_and_select(Identifier::zero(), amount, Some(1), 1, 0) {
+			Err(Error::WalletData(msg)) => {
+				assert_eq!(msg, format!("amount of {} plus fee of {} overflows", amount, fee))
+			}
+			res => panic!("expected a wallet data error, got {:?}", res.map(|r| r.1)),
+		}
+
+		// right at the limit is fine
+		let (_, _, change) = wallet_data
+			.estimate_and_select(Identifier::zero(), amount - 1, Some(1), 1, 0)
+			.unwrap();
+		assert_eq!(change, 0);
+	}
+
+	#[test]
+	fn estimate_and_select_change_outputs() {
+		let wallet_data = wallet(vec![output(1, 1000, OutputStatus::Unspent)]);
+
+		// each change output adds to the weight and the fee
+		let mut last_fee = 0;
+		for num_change_outputs in 1..5 {
+			let (coins, fee, change) = wallet_data
+				.estimate_and_select(Identifier::zero(), 100, Some(1), num_change_outputs, 0)
+				.unwrap();
+			assert_eq!(values(&coins), vec![1000]);
+			assert_eq!(fee, tx_fee(1, num_change_outputs + 1, Some(1)));
+			assert!(fee > last_fee);
+			assert_eq!(change, 900 - fee as i64);
+			last_fee = fee;
+		}
+
+		// a few nanogrins of change can't be split
+		let amount = 1000 - tx_fee(1, 4, Some(1)) - 2;
+		wallet_data.estimate_and_select(Identifier::zero(), amount, Some(1), 1, 0).unwrap();
+		match wallet_data.estimate_and_select(Identifier::zero(), amount, Some(1), 3, 0) {
+			Err(Error::WalletData(msg)) => {
+				assert_eq!(msg, "change of 2 is too small to split into 3 outputs")
+			}
+			res => panic!("expected a wallet data error, got {:?}", res.map(|r| r.2)),
+		}
+	}
+
+	#[test]
+	fn estimate_inputs_matches_selection() {
+		let wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Unspent),
+			output(2, 40, OutputStatus::Unspent),
+			output(3, 25, OutputStatus::Unspent),
+			output(4, 500, OutputStatus::Locked),
+		]);
+
+		for &amount in &[10, 30, 100, 120, 150] {
+			let (coins, fee, _) = wallet_data
+				.estimate_and_select(Identifier::zero(), amount, Some(1), 1, 0)
+				.unwrap();
+			let estimate = wallet_data.estimate_inputs(Identifier::zero(), amount, Some(1));
+			assert_eq!(estimate.unwrap(), (coins.len(), fee));
+		}
+
+		match wallet_data.estimate_inputs(Identifier::zero(), 165, Some(1)) {
+			Err(Error::NotEnoughFunds { available, .. }) => assert_eq!(available, 165),
+			res => panic!("expected not enough funds, got {:?}", res),
+		}
+		assert!(wallet_data.estimate_inputs(Identifier::zero(), 0, Some(1)).is_err());
+	}
+
+	#[test]
+	fn reserve_for_send_locks_inputs() {
+		let mut wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Unspent),
+			output(2, 200, OutputStatus::Unspent),
+			output(3, 400, OutputStatus::Unspent),
+		]);
+		let status = |wallet_data: &WalletData, n_child| {
+			let key_id = output(n_child, 0, OutputStatus::Unspent).key_id;
+			wallet_data.get_output(&key_id).unwrap().status.clone()
+		};
+
+		let dust_limit = WalletConfig::default().dust_limit;
+		let (coins, change, fee) =
+			wallet_data.reserve_for_send(Identifier::zero(), 250, Some(1), dust_limit).unwrap();
+		let total: u64 = coins.iter().map(|out| out.value).sum();
+		assert_eq!(total, 250 + change + fee);
+		for coin in &coins {
+			assert_eq!(status(&wallet_data, coin.n_child), OutputStatus::Locked);
+		}
+		let reserved = coins.iter().map(|out| out.n_child).collect::<Vec<_>>();
+		for n_child in (1..4).filter(|n| !reserved.contains(n)) {
+			assert_eq!(status(&wallet_data, n_child), OutputStatus::Unspent);
+		}
+
+		// locked inputs can't be reserved again, and a failure locks nothing
+		let before = wallet_data.clone();
+		match wallet_data.reserve_for_send(Identifier::zero(), 10_000, Some(1), dust_limit) {
+			Err(Error::NotEnoughFunds { .. }) => {}
+			res => panic!("expected not enough funds, got {:?}", res.map(|r| r.1)),
+		}
+		assert_eq!(wallet_data.diff(&before), WalletDiff::default());
+		for n_child in (1..4).filter(|n| !reserved.contains(n)) {
+			assert_eq!(status(&wallet_data, n_child), OutputStatus::Unspent);
+		}
+	}
+
+	#[test]
+	fn estimate_and_select_dust_change() {
+		let wallet_data = wallet(vec![output(1, 1000, OutputStatus::Unspent)]);
+		let dust_limit = WalletConfig::default().dust_limit;
+		assert_eq!(dust_limit, DEFAULT_DUST_LIMIT);
+
+		// healthy change gets its output
+		let (coins, fee, change) = wallet_data
+			.estimate_and_select(Identifier::zero(), 500, Some(1), 1, dust_limit)
+			.unwrap();
+		assert_eq!(values(&coins), vec![1000]);
+		assert_eq!(fee, tx_fee(1, 2, Some(1)));
+		assert_eq!(change, 500 - fee as i64);
+
+		// a tiny change goes to the fee, without a change output to pay for
+		let amount = 1000 - tx_fee(1, 2, Some(1)) - dust_limit;
+		let (coins, fee, change) = wallet_data
+			.estimate_and_select(Identifier::zero(), amount, Some(1), 1, dust_limit)
+			.unwrap();
+		assert_eq!(values(&coins), vec![1000]);
+		assert_eq!(change, 0);
+		assert_eq!(fee, 1000 - amount);
+		assert!(fee > tx_fee(1, 1, Some(1)));
+
+		// just above the limit is still worth an output
+		let (_, fee, change) = wallet_data
+			.estimate_and_select(Identifier::zero(), amount - 1, Some(1), 1, dust_limit)
+			.unwrap();
+		assert_eq!(fee, tx_fee(1, 2, Some(1)));
+		assert_eq!(change, dust_limit as i64 + 1);
+
+		// the dust also goes to a reserved send
+		let mut wallet_data = wallet_data;
+		let (coins, change, fee) = wallet_data
+			.reserve_for_send(Identifier::zero(), amount, Some(1), dust_limit)
+			.unwrap();
+		assert_eq!(values(&coins), vec![1000]);
+		assert_eq!((change, fee), (0, 1000 - amount));
+	}
+
+	#[test]
+	fn split_change_values() {
+		assert_eq!(split_change(0, 1).unwrap(), vec![0]);
+		assert_eq!(split_change(90, 1).unwrap(), vec![90]);
+		assert_eq!(split_change(90, 3).unwrap(), vec![30, 30, 30]);
+		assert_eq!(split_change(100, 3).unwrap(), vec![34, 33, 33]);
+		assert_eq!(split_change(3, 3).unwrap(), vec![1, 1, 1]);
+		assert!(split_change(2, 3).is_err());
+		assert!(split_change(100, 0).is_err());
+	}
+
+	#[test]
+	fn transaction_weight_and_fee() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+
+		// go through the JSON encoding, as a receiver would
+		let json = partial_tx_to_json(300, blind_sum, tx).unwrap();
+		let (_, _, tx) = partial_tx_from_json(&keychain, &json).unwrap();
+		assert_eq!(tx.inputs.len(), 1);
+		assert_eq!(tx.outputs.len(), 1);
+
+		// 4 * 1 output - 1 input + 1
+		assert_eq!(transaction_weight(&tx), 4);
+		let policy = FeePolicy::default();
+		let fee = tx_fee_with_policy(1, 1, 1, &policy);
+		assert_eq!(fee, 4 * policy.base_fee);
+		verify_fee(&tx, fee, &policy).unwrap();
+
+		// the fee in that tx was computed for a change output that isn't there
+		match verify_fee(&tx, tx.fee, &policy) {
+			Err(Error::FeeDispute { sender_fee, recipient_fee }) => {
+				assert_eq!(sender_fee, tx.fee);
+				assert_eq!(recipient_fee, fee);
+			}
+			res => panic!("expected a fee dispute, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn parse_amounts() {
+		assert_eq!(amount_from_str("12.5").unwrap(), 12_500_000_000);
+		assert_eq!(amount_from_str("0.000000001").unwrap(), 1);
+		assert_eq!(amount_from_str(" 3 ").unwrap(), 3_000_000_000);
+		assert_eq!(amount_from_str("3.").unwrap(), 3_000_000_000);
+		assert_eq!(amount_from_str(".25").unwrap(), 250_000_000);
+		assert_eq!(amount_from_str("0").unwrap(), 0);
+		assert_eq!(amount_from_str("18446744073.709551615").unwrap(), u64::max_value());
+
+		for bad in vec![
+			"",
+			" ",
+			".",
+			"abc",
+			"1,5",
+			"-1",
+			"+1",
+			"1.2.3",
+			"1 000",
+			"0.0000000001",
+			"18446744073.709551616",
+			"18446744074",
+			"99999999999999999999999",
+		] {
+			match amount_from_str(bad) {
+				Err(Error::Format(msg)) => assert!(msg.contains("invalid amount")),
+				res => panic!("expected {:?} to be rejected, got {:?}", bad, res),
+			}
+		}
+	}
+
+	#[test]
+	fn format_amounts() {
+		assert_eq!(amount_to_str(12_500_000_000), "12.500000000");
+		assert_eq!(amount_to_str(1), "0.000000001");
+		assert_eq!(amount_to_str(0), "0.000000000");
+		assert_eq!(amount_from_str(&amount_to_str(u64::max_value())).unwrap(), u64::max_value());
+	}
+
+	#[test]
+	fn reconcile_fees() {
+		let strict = FeePolicy::default();
+		assert_eq!(reconcile_fee(80, 80, &strict).unwrap(), 80);
+
+		let policy = FeePolicy {
+			fee_tolerance: 5,
+			..FeePolicy::default()
+		};
+		assert_eq!(reconcile_fee(80, 80, &policy).unwrap(), 80);
+		assert_eq!(reconcile_fee(80, 83, &policy).unwrap(), 83);
+		assert_eq!(reconcile_fee(85, 80, &policy).unwrap(), 85);
+
+		for &(sender_fee, recipient_fee, fee_policy) in
+			&[(80, 81, &strict), (80, 86, &policy), (86, 80, &policy)]
+		{
+			match reconcile_fee(sender_fee, recipient_fee, fee_policy) {
+				Err(Error::FeeDispute { sender_fee: s, recipient_fee: r }) => {
+					assert_eq!(s, sender_fee);
+					assert_eq!(r, recipient_fee);
+				}
+				res => panic!("expected a fee dispute, got {:?}", res),
+			}
+		}
+	}
+
+	#[test]
+	fn fee_above_max_rejected() {
+		let policy = FeePolicy {
+			max_fee: Some(50),
+			..FeePolicy::default()
+		};
+		// weight 8 for 1 input and 2 outputs at base fee 10
+		match tx_fee_checked(1, 2, 1, &policy) {
+			Err(Error::FeeTooHigh { fee, max_fee }) => {
+				assert_eq!(fee, 80);
+				assert_eq!(max_fee, 50);
+			}
+			res => panic!("expected fee too high, got {:?}", res),
+		}
+
+		// at or below the cap, or without any cap
+		assert_eq!(tx_fee_checked(4, 2, 1, &policy).unwrap(), 50);
+		assert_eq!(tx_fee_checked(1, 2, 1, &FeePolicy::default()).unwrap(), 80);
+	}
+
+	#[test]
+	fn error_display() {
+		use std::error::Error as StdError;
+
+		assert_eq!(
+			Error::NotEnoughFunds {
+				available: 100,
+				needed: 120,
+			}.to_string(),
+			"not enough funds: have 100, need 120, short by 20"
+		);
+		assert_eq!(
+			Error::FeeDispute {
+				sender_fee: 70,
+				recipient_fee: 80,
+			}.to_string(),
+			"fee dispute: sender fee is 70 but we expected 80"
+		);
+		assert_eq!(
+			Error::FeeTooHigh {
+				fee: 80,
+				max_fee: 50,
+			}.to_string(),
+			"fee too high: 80 is above the maximum of 50"
+		);
+		assert_eq!(
+			Error::WalletData("no file".to_string()).to_string(),
+			"wallet data error: no file"
+		);
+		assert_eq!(Error::Format("bad hex".to_string()).to_string(), "format error: bad hex");
+
+		// node errors are told apart by whether the node answered at all
+		let unreachable: Error = api::Error::Unreachable("refused".to_string()).into();
+		assert_eq!(unreachable.to_string(), "node unreachable: refused");
+		let rejected: Error = api::Error::Argument("bad tx".to_string()).into();
+		assert_eq!(rejected.to_string(), "node rejected the request: Bad arguments: bad tx");
+		match Error::from(api::Error::NotFound) {
+			Error::Node(api::Error::NotFound) => {}
+			e => panic!("expected a not found node error, got {}", e),
+		}
+		// a server error or a garbled answer doesn't mean the request was bad
+		match Error::from(api::Error::Internal("Server error.".to_string())) {
+			Error::Node(api::Error::Internal(_)) => {}
+			e => panic!("expected an internal node error, got {}", e),
+		}
+
+		// wrapped errors show their own message and are exposed as source
+		let wrapped = vec![
+			Error::Keychain(keychain::Error::KeyDerivation("no key".to_string())),
+			Error::Transaction(transaction::Error::OddFee),
+			Error::Secp(secp::Error::InvalidSecretKey),
+			Error::Node(api::Error::NotFound),
+		];
+		let prefixes = vec!["keychain error: ", "transaction error: ", "secp error: ", "node error: "];
+		for (e, prefix) in wrapped.iter().zip(prefixes) {
+			let source = e.source().unwrap().to_string();
+			assert_eq!(e.to_string(), format!("{}{}", prefix, source));
+		}
+		assert!(Error::Format("bad hex".to_string()).source().is_none());
+	}
+
+	#[test]
+	fn read_missing_file_io_error() {
+		let dir = test_dir("missing_file");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+
+		match WalletData::read(&data_file_path) {
+			Err(Error::Io(e)) => {
+				assert_eq!(e.kind(), io::ErrorKind::NotFound);
+				assert!(e.to_string().contains(&data_file_path));
+			}
+			res => panic!("expected an io error, got {:?}", res.map(|w| w.outputs.len())),
+		}
+	}
+
+	#[test]
+	fn validate_wallet_config() {
+		let dir = test_dir("validate_config");
+		let config = WalletConfig {
+			data_file_dir: dir.clone(),
+			..WalletConfig::default()
+		};
+		config.validate().unwrap();
+
+		let missing_scheme = WalletConfig {
+			check_node_api_http_addr: vec!["127.0.0.1:13413".to_string()],
+			..config.clone()
+		};
+		match missing_scheme.validate() {
+			Err(Error::WalletData(msg)) => {
+				assert!(msg.contains("check_node_api_http_addr"));
+				assert!(msg.contains("scheme"));
+			}
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+
+		let bad_port = WalletConfig {
+			check_node_api_http_addr: vec!["http://127.0.0.1:port".to_string()],
+			..config.clone()
+		};
+		match bad_port.validate() {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("invalid port")),
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+
+		let bad_addr = WalletConfig {
+			api_http_addr: "localhost".to_string(),
+			..config.clone()
+		};
+		match bad_addr.validate() {
+			Err(Error::WalletData(msg)) => {
+				assert!(msg.contains("api_http_addr is not a valid socket address"));
+			}
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+
+		let empty_addr = WalletConfig {
+			api_http_addr: "".to_string(),
+			..config.clone()
+		};
+		assert!(empty_addr.validate().is_err());
+	}
+
+	#[test]
+	fn output_ordering() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let commits = (1..9)
+			.map(|n| {
+				let key_id = keychain.derive_key_id(n).unwrap();
+				keychain.commit(n as u64 * 10, &key_id).unwrap()
+			})
+			.collect::<Vec<_>>();
+		let bytes = |commits: &Vec<pedersen::Commitment>| {
+			commits.iter().map(|c| c.0[..].to_vec()).collect::<Vec<_>>()
+		};
+		assert_eq!(WalletConfig::default().output_ordering, OutputOrdering::Sorted);
+
+		// sorting gives the same order whatever the one we started from
+		let mut sorted = commits.clone();
+		OutputOrdering::Sorted.order(&mut sorted, |c| *c);
+		let mut reversed = commits.clone();
+		reversed.reverse();
+		OutputOrdering::Sorted.order(&mut reversed, |c| *c);
+		assert_eq!(bytes(&sorted), bytes(&reversed));
+		let mut expected = bytes(&commits);
+		expected.sort();
+		assert_eq!(bytes(&sorted), expected);
+
+		// shuffling keeps the same outputs
+		let mut shuffled = commits.clone();
+		OutputOrdering::Shuffled.order(&mut shuffled, |c| *c);
+		assert_eq!(shuffled.len(), commits.len());
+		let mut shuffled_bytes = bytes(&shuffled);
+		shuffled_bytes.sort();
+		assert_eq!(shuffled_bytes, expected);
+	}
+
+	#[test]
+	fn data_file_dir_env_override() {
+		// the environment variable wins whenever it's set
+		assert_eq!(
+			pick_data_file_dir("./wallet", Some("/srv/wallet2".to_string())),
+			"/srv/wallet2"
+		);
+		// unset or empty, we fall back to the configured directory
+		assert_eq!(pick_data_file_dir("./wallet", None), "./wallet");
+		assert_eq!(pick_data_file_dir("./wallet", Some("".to_string())), "./wallet");
+
+		// resolved the same way by the backend of a configuration
+		let config = WalletConfig {
+			data_file_dir: "./wallet".to_string(),
+			..WalletConfig::default()
+		};
+		let env_dir = env::var(DATA_DIR_ENV_VAR).ok();
+		assert_eq!(
+			FileBackend::from_config(&config).data_file_dir,
+			pick_data_file_dir("./wallet", env_dir)
+		);
+	}
+
+	#[test]
+	fn listen_addr_schemes() {
+		let tcp = WalletConfig::default();
+		assert_eq!(
+			tcp.listen_addr().unwrap(),
+			ListenAddr::Tcp("127.0.0.1:13416".parse().unwrap())
+		);
+
+		let unix = WalletConfig {
+			api_http_addr: "unix:/tmp/grin/wallet.sock".to_string(),
+			..WalletConfig::default()
+		};
+		assert_eq!(
+			unix.listen_addr().unwrap(),
+			ListenAddr::Unix(PathBuf::from("/tmp/grin/wallet.sock"))
+		);
+
+		let no_path = WalletConfig {
+			api_http_addr: "unix:".to_string(),
+			..WalletConfig::default()
+		};
+		match no_path.validate() {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("no unix socket path")),
+			res => panic!("expected a wallet data error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn node_endpoints_from_config() {
+		let single: WalletConfig = serde_json::from_str(
+			"{\"enable_wallet\":true,\"api_http_addr\":\"127.0.0.1:13416\",\
+			\"check_node_api_http_addr\":\"http://127.0.0.1:13413\",\"data_file_dir\":\".\"}",
+		).unwrap();
+		assert_eq!(single.node_endpoints(), vec!["http://127.0.0.1:13413".to_string()]);
+
+		let list: WalletConfig = serde_json::from_str(
+			"{\"enable_wallet\":true,\"api_http_addr\":\"127.0.0.1:13416\",\
+			\"check_node_api_http_addr\":[\"http://10.0.0.1:13413\",\"http://10.0.0.2:13413\"],\
+			\"data_file_dir\":\".\"}",
+		).unwrap();
+		assert_eq!(
+			list.node_endpoints(),
+			vec![
+				"http://10.0.0.1:13413".to_string(),
+				"http://10.0.0.2:13413".to_string(),
+			]
+		);
+	}
+
+	#[test]
+	fn sorted_outputs_stable() {
+		let wallet_data = wallet(vec![
+			output(3, 30, OutputStatus::Unspent),
+			output(1, 50, OutputStatus::Unspent),
+			output(4, 20, OutputStatus::Spent),
+			output(2, 50, OutputStatus::Unspent),
+			output(5, 40, OutputStatus::Unspent),
+		]);
+
+		let n_childs = |outputs: Vec<&OutputData>| {
+			outputs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+		let first = n_childs(wallet_data.sorted_outputs());
+		assert_eq!(first, vec![1, 2, 3, 4, 5]);
+		assert_eq!(n_childs(wallet_data.sorted_outputs()), first);
+
+		// selection picks the same inputs, in the same order, every time
+		let (coins, _) = wallet_data.select(Identifier::zero(), 85, 0);
+		for _ in 0..10 {
+			let (again, _) = wallet_data.select(Identifier::zero(), 85, 0);
+			assert_eq!(
+				again.iter().map(|out| out.n_child).collect::<Vec<_>>(),
+				coins.iter().map(|out| out.n_child).collect::<Vec<_>>()
+			);
+		}
+	}
+
+	#[test]
+	fn merge_disjoint_wallets() {
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent)]);
+		let other = wallet(vec![
+			output(2, 20, OutputStatus::Unspent),
+			output(3, 30, OutputStatus::Spent),
+		]);
+
+		let report = wallet_data.merge(&other);
+		assert_eq!(
+			report,
+			MergeReport {
+				added: 2,
+				updated: 0,
+				conflicts: 0,
+			}
+		);
+		assert_eq!(values(&wallet_data.outputs.values().cloned().collect()), vec![10, 20, 30]);
+	}
+
+	#[test]
+	fn merge_identical_outputs() {
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Spent),
+		]);
+		let other = wallet_data.clone();
+
+		assert_eq!(wallet_data.merge(&other), MergeReport::default());
+		assert_eq!(wallet_data.outputs.len(), 2);
+	}
+
+	#[test]
+	fn merge_conflicting_statuses() {
+		let mut confirmed = output(2, 20, OutputStatus::Unspent);
+		confirmed.height = 12;
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Unconfirmed),
+			output(3, 30, OutputStatus::Spent),
+			output(4, 40, OutputStatus::Unspent),
+		]);
+		let other = wallet(vec![
+			output(1, 10, OutputStatus::Locked),
+			confirmed,
+			output(3, 30, OutputStatus::Unspent),
+			output(4, 45, OutputStatus::Spent),
+		]);
+
+		let report = wallet_data.merge(&other);
+		assert_eq!(
+			report,
+			MergeReport {
+				added: 0,
+				updated: 2,
+				conflicts: 1,
+			}
+		);
+
+		let status = |n_child| {
+			let key_id = output(n_child, 0, OutputStatus::Unspent).key_id;
+			wallet_data.get_output(&key_id).unwrap().clone()
+		};
+		// the more advanced status and the higher height win
+		assert_eq!(status(1).status, OutputStatus::Locked);
+		assert_eq!(status(2).status, OutputStatus::Unspent);
+		assert_eq!(status(2).height, 12);
+		assert_eq!(status(3).status, OutputStatus::Spent);
+		// mismatched values are left alone
+		assert_eq!(status(4).status, OutputStatus::Unspent);
+		assert_eq!(status(4).value, 40);
+	}
+
+	#[test]
+	fn restore_from_node() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut node = MockNode::new(20);
+		node.add(&keychain, 1000, 1, 5, 0);
+		node.add(&keychain, 2000, 2, 6, 0);
+		// after a gap of 2 unused derivations
+		node.add(&keychain, 1000, 5, 10, 0);
+		// coinbase not mature yet
+		node.add(&keychain, 1000, 6, 15, 25);
+		// any value is found
+		node.add(&keychain, 1234, 7, 15, 0);
+		// too far past the gap to be found
+		node.add(&keychain, 1000, 11, 16, 0);
+		// someone else's
+		let other = Keychain::from_random_seed().unwrap();
+		node.add(&other, 1000, 8, 16, 0);
+
+		let wallet_data = WalletData::restore(&keychain, &node, 3).unwrap();
+
+		let found = wallet_data
+			.sorted_outputs()
+			.iter()
+			.map(|out| (out.n_child, out.value, out.height, out.status.clone()))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			found,
+			vec![
+				(1, 1000, 5, OutputStatus::Unspent),
+				(2, 2000, 6, OutputStatus::Unspent),
+				(5, 1000, 10, OutputStatus::Unspent),
+				(6, 1000, 15, OutputStatus::Immature),
+				(7, 1234, 15, OutputStatus::Unspent),
+			]
+		);
+		for out in wallet_data.sorted_outputs() {
+			assert_eq!(out.key_id, keychain.derive_key_id(out.n_child).unwrap());
+			assert_eq!(out.root_key_id, keychain.root_key_id());
+		}
+	}
+
+	#[test]
+	fn next_child_after_pruning() {
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			output(2, 20, OutputStatus::Spent),
+		]);
+
+		assert_eq!(wallet_data.next_child(Identifier::zero()), 3);
+		// handed out but no output recorded for it (yet)
+		assert_eq!(wallet_data.next_child(Identifier::zero()), 4);
+
+		// pruning the highest output doesn't bring the index back down
+		assert_eq!(wallet_data.prune_spent(10), 1);
+		assert_eq!(wallet_data.next_child(Identifier::zero()), 5);
+
+		// and neither do older wallet files without the counter
+		wallet_data.last_issued_child = 0;
+		wallet_data.add_output(output(7, 70, OutputStatus::Unspent)).unwrap();
+		assert_eq!(wallet_data.next_child(Identifier::zero()), 8);
+	}
+
+	#[test]
+	fn add_output_fresh() {
+		let mut wallet_data = wallet(vec![]);
+
+		wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		wallet_data.add_output(output(2, 20, OutputStatus::Unconfirmed)).unwrap();
+		assert_eq!(wallet_data.outputs.len(), 2);
+		assert_eq!(wallet_data.get_output(&output(2, 0, OutputStatus::Spent).key_id)
+			.unwrap()
+			.value, 20);
+	}
+
+	#[test]
+	fn add_output_upgrades_coinbase() {
+		let mut wallet_data = wallet(vec![output(1, 60, OutputStatus::Unconfirmed)]);
+
+		// coinbase rebuilt for the same derivation, with more fees this time
+		wallet_data.add_output(output(1, 70, OutputStatus::Unconfirmed)).unwrap();
+		assert_eq!(wallet_data.outputs.len(), 1);
+		assert_eq!(wallet_data.get_output(&output(1, 0, OutputStatus::Spent).key_id)
+			.unwrap()
+			.value, 70);
+	}
+
+	#[test]
+	fn add_output_rejects_collision() {
+		let mut change = output(2, 20, OutputStatus::Unconfirmed);
+		change.zero_ok = true;
+		let mut received = output(3, 30, OutputStatus::Unconfirmed);
+		received.tx_hash = Some("abcd".to_owned());
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent),
+			change,
+			received,
+		]);
+
+		for n_child in 1..4 {
+			match wallet_data.add_output(output(n_child, 99, OutputStatus::Unconfirmed)) {
+				Err(Error::WalletData(msg)) => assert!(msg.contains("already tracked")),
+				res => panic!("expected a collision, got {:?}", res),
+			}
+		}
+		let tracked = wallet_data.outputs.values().cloned().collect();
+		assert_eq!(values(&tracked), vec![10, 20, 30]);
+	}
+
+	#[test]
+	fn validate_partial_tx_request() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+		let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+		let blind_hex = util::to_hex(blind_sum.secret_key().as_ref().to_vec());
+		let request = |amount: u64, blind_sum: &str, tx: &str| {
+			serde_json::to_string(&JSONPartialTx {
+				version: PARTIAL_TX_VERSION,
+				amount: amount,
+				blind_sum: blind_sum.to_owned(),
+				tx: tx.to_owned(),
+			}).unwrap()
+		};
+		let format_error = |json: String| match PartialTx::from_json(&json) {
+			Err(Error::Format(msg)) => msg,
+			res => panic!("expected a format error, got {:?}", res),
+		};
+
+		let json = partial_tx_to_json(300, blind_sum, tx).unwrap();
+		let partial_tx = PartialTx::from_json(&json).unwrap();
+		WalletReceiveRequest::PartialTransaction(partial_tx).validate().unwrap();
+		PartialTx::from_json(&request(300, &blind_hex, &tx_hex)).unwrap();
+
+		let msg = format_error(request(0, &blind_hex, &tx_hex));
+		assert!(msg.contains("amount must not be zero"));
+
+		let msg = format_error(request(300, &blind_hex, "not hex at all"));
+		assert!(msg.contains("field tx is not valid hex"));
+		let msg = format_error(request(300, &blind_hex, ""));
+		assert!(msg.contains("field tx is not valid hex"));
+		let msg = format_error(request(300, &blind_hex, &tx_hex[1..]));
+		assert!(msg.contains("field tx is not valid hex"));
+
+		let msg = format_error(request(300, &blind_hex[2..], &tx_hex));
+		assert!(msg.contains("field blind_sum must be 32 bytes, got 31"));
+
+		let msg = format_error("{}".to_owned());
+		assert!(msg.contains("invalid partial transaction"));
+
+		// built programmatically, bypassing the parsing
+		let mut zero = PartialTx::from_json(&json).unwrap();
+		zero.0.amount = 0;
+		match WalletReceiveRequest::PartialTransaction(zero).validate() {
+			Err(Error::Format(msg)) => assert!(msg.contains("amount must not be zero")),
+			res => panic!("expected a format error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn typed_receive_request_roundtrip() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = partial_tx(&keychain);
+		let tx_hash = tx.hash();
+		let json = partial_tx_to_json(300, blind_sum, tx).unwrap();
+
+		// on the wire the partial transaction is still a JSON string
+		let partial_tx = PartialTx::from_json(&json).unwrap();
+		let request = WalletReceiveRequest::PartialTransaction(partial_tx);
+		let wire = serde_json::to_string(&request).unwrap();
+		let legacy: HashMap<String, String> = serde_json::from_str(&wire).unwrap();
+		let legacy_json = &legacy["PartialTransaction"];
+		let (amount, _, legacy_tx) = partial_tx_from_json(&keychain, legacy_json).unwrap();
+		assert_eq!(amount, 300);
+		assert_eq!(legacy_tx.hash(), tx_hash);
+
+		// and reads back as a typed request
+		let legacy_wire = serde_json::to_string(&legacy).unwrap();
+		match serde_json::from_str::<WalletReceiveRequest>(&legacy_wire).unwrap() {
+			WalletReceiveRequest::PartialTransaction(partial_tx) => {
+				assert_eq!(partial_tx.amount(), 300);
+				let (amount, _, read_tx) = partial_tx.decode(&keychain).unwrap();
+				assert_eq!(amount, 300);
+				assert_eq!(read_tx.hash(), tx_hash);
+			}
+			_ => panic!("expected a partial transaction request"),
+		}
+
+		// malformed partial transactions don't even parse
+		let bad_wire = "{\"PartialTransaction\":\"{\\\"amount\\\":0}\"}";
+		assert!(serde_json::from_str::<WalletReceiveRequest>(bad_wire).is_err());
+	}
+
+	#[test]
+	fn next_child_by_root() {
+		let root_a = Identifier::zero();
+		let root_b = Identifier::from_bytes(&[9]);
+		let mut other_root = output(5, 50, OutputStatus::Unspent);
+		other_root.root_key_id = root_b.clone();
+		let mut wallet_data = wallet(vec![output(1, 10, OutputStatus::Unspent), other_root]);
+
+		assert_eq!(wallet_data.next_child(root_a.clone()), 2);
+		assert_eq!(wallet_data.next_child(root_b.clone()), 6);
+		assert_eq!(wallet_data.next_child(root_a.clone()), 3);
+		assert_eq!(wallet_data.next_child(root_a.clone()), 4);
+		assert_eq!(wallet_data.next_child(root_b.clone()), 7);
+
+		// a root we never used starts from the beginning
+		let root_c = Identifier::from_bytes(&[7]);
+		assert_eq!(wallet_data.next_child(root_c.clone()), 1);
+
+		// the counters survive all the formats
+		let dir = test_dir("next_child_by_root");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		for &format in [WalletFormat::Json, WalletFormat::Binary].iter() {
+			wallet_data.write_file(&data_file_path, format, false).unwrap();
+			let mut read_back = WalletData::read(&data_file_path).unwrap();
+			read_back.outputs.clear();
+			assert_eq!(read_back.next_child(root_a.clone()), 5);
+			assert_eq!(read_back.next_child(root_b.clone()), 8);
+			assert_eq!(read_back.next_child(root_c.clone()), 2);
+		}
+		let bytes = ser::ser_vec(&wallet_data).unwrap();
+		let read_back: WalletData = ser::deserialize(&mut &bytes[..]).unwrap();
+		assert_eq!(read_back.issued_children, wallet_data.issued_children);
+
+		// older binary files without them still read
+		let bytes = fs::read(&data_file_path).unwrap();
+		let mut legacy = bytes[..bytes.len() - 1 - 8 - 8 - 3 * (8 + 20 + 4)].to_vec();
+		legacy[WALLET_BIN_MAGIC.len()] = 1;
+		let read_back = WalletData::from_bytes(&legacy).unwrap();
+		assert_eq!(read_back.outputs.len(), 2);
+		assert!(read_back.issued_children.is_empty());
+	}
+
+	#[test]
+	fn last_confirmed_height() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let out = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		let mut wallet_data = wallet(vec![out.clone()]);
+		assert_eq!(wallet_data.last_confirmed_height(), 0);
+		assert_eq!(wallet_data.balance(keychain.root_key_id()).last_confirmed_height, 0);
+
+		let mut node_utxos = HashSet::new();
+		node_utxos.insert(out.commit(&keychain).unwrap());
+		wallet_data.reconcile(&node_utxos, 120, &keychain).unwrap();
+		assert_eq!(wallet_data.last_confirmed_height(), 120);
+		let balance = wallet_data.balance(keychain.root_key_id());
+		assert_eq!((balance.unspent, balance.last_confirmed_height), (10, 120));
+
+		let dir = test_dir("last_confirmed_height");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		for &format in [WalletFormat::Json, WalletFormat::Binary].iter() {
+			wallet_data.write_file(&data_file_path, format, false).unwrap();
+			let read_back = WalletData::read(&data_file_path).unwrap();
+			assert_eq!(read_back.last_confirmed_height(), 120);
+		}
+		let stream_file_path = wallet_file_path(&dir, STREAM_DAT_FILE);
+		wallet_data.write_streaming(&stream_file_path).unwrap();
+		let read_back = WalletData::read_streaming(&stream_file_path).unwrap();
+		assert_eq!(read_back.last_confirmed_height(), 120);
+		let bytes = ser::ser_vec(&wallet_data).unwrap();
+		let read_back: WalletData = ser::deserialize(&mut &bytes[..]).unwrap();
+		assert_eq!(read_back.last_confirmed_height(), 120);
+
+		// binary files from before it read as never checked
+		let bytes = WalletData::to_bytes(&wallet_data).unwrap();
+		let mut legacy = bytes[..bytes.len() - 9].to_vec();
+		legacy[WALLET_BIN_MAGIC.len()] = 2;
+		assert_eq!(WalletData::from_bytes(&legacy).unwrap().last_confirmed_height(), 0);
+	}
+
+	#[test]
+	fn streaming_format_roundtrip() {
+		let dir = test_dir("streaming");
+		let data_file_path = wallet_file_path(&dir, STREAM_DAT_FILE);
+		let mut wallet_data = wallet(
+			(1..10_001).map(|n| output(n, n as u64, OutputStatus::Unspent)).collect(),
+		);
+		wallet_data.last_issued_child = 10_000;
+		wallet_data.log_tx(TxLogEntryType::Received, None, 10, 0, 0);
+		wallet_data.write_streaming(&data_file_path).unwrap();
+
+		// a header line and one line per output
+		let content = fs::read_to_string(&data_file_path).unwrap();
+		assert_eq!(content.lines().count(), 10_001);
+		assert!(!Path::new(&wallet_file_path(&dir, DAT_FILE)).exists());
+
+		// outputs come one at a time, in derivation order
+		let mut count = 0;
+		let mut total = 0;
+		WalletData::stream_outputs(&data_file_path, |out| {
+			count += 1;
+			assert_eq!(out.n_child, count);
+			total += out.value;
+			Ok(())
+		}).unwrap();
+		assert_eq!(count, 10_000);
+		assert_eq!(total, 10_000 * 10_001 / 2);
+
+		let read_back = WalletData::read_streaming(&data_file_path).unwrap();
+		assert_eq!(read_back.outputs.len(), 10_000);
+		assert_eq!(read_back.last_issued_child, 10_000);
+		assert_eq!(read_back.tx_log().len(), 1);
+		let key_id = output(42, 0, OutputStatus::Spent).key_id;
+		assert_eq!(read_back.get_output(&key_id).unwrap().value, 42);
+
+		// the function processing the outputs can stop the stream
+		let res = WalletData::stream_outputs(&data_file_path, |out| if out.n_child < 3 {
+			Ok(())
+		} else {
+			Err(Error::WalletData("stop".to_owned()))
+		});
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn outputs_by_height() {
+		let mut outputs = vec![];
+		let seeds = vec![(1, 50, 10), (2, 20, 10), (3, 30, 11), (4, 20, 12), (5, 5, 20)];
+		for (n_child, value, height) in seeds {
+			let mut out = output(n_child, value, OutputStatus::Unspent);
+			out.height = height;
+			outputs.push(out);
+		}
+		let wallet_data = wallet(outputs);
+		let children = |outs: Vec<&OutputData>| {
+			outs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+
+		assert_eq!(children(wallet_data.outputs_at_height(10)), vec![2, 1]);
+		assert_eq!(children(wallet_data.outputs_at_height(11)), vec![3]);
+		assert!(wallet_data.outputs_at_height(15).is_empty());
+
+		// equal values keep a stable order
+		assert_eq!(children(wallet_data.outputs_in_range(10, 12)), vec![2, 4, 3, 1]);
+		assert_eq!(children(wallet_data.outputs_in_range(11, 20)), vec![5, 4, 3]);
+		assert_eq!(children(wallet_data.outputs_in_range(13, 19)), vec![]);
+		assert_eq!(children(wallet_data.outputs_in_range(12, 10)), vec![]);
+	}
+
+	#[test]
+	fn list_outputs_paginated() {
+		let mut outputs = vec![];
+		for n_child in 1..8 {
+			let status = if n_child % 2 == 0 {
+				OutputStatus::Spent
+			} else {
+				OutputStatus::Unspent
+			};
+			outputs.push(output(n_child, n_child as u64 * 10, status));
+		}
+		let wallet_data = wallet(outputs);
+		let children = |outs: Vec<OutputData>| {
+			outs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+
+		let (first, total) = wallet_data.list_outputs(0, 3, None);
+		assert_eq!((children(first), total), (vec![1, 2, 3], 7));
+		let (middle, total) = wallet_data.list_outputs(3, 3, None);
+		assert_eq!((children(middle), total), (vec![4, 5, 6], 7));
+		let (last, total) = wallet_data.list_outputs(6, 3, None);
+		assert_eq!((children(last), total), (vec![7], 7));
+
+		// past the end
+		let (page, total) = wallet_data.list_outputs(10, 3, None);
+		assert!(page.is_empty());
+		assert_eq!(total, 7);
+
+		// total is for the filter
+		let (page, total) = wallet_data.list_outputs(1, 2, Some(OutputStatus::Unspent));
+		assert_eq!((children(page), total), (vec![3, 5], 4));
+		let (page, total) = wallet_data.list_outputs(0, 10, Some(OutputStatus::Spent));
+		assert_eq!((children(page), total), (vec![2, 4, 6], 3));
+		let (page, total) = wallet_data.list_outputs(0, 10, Some(OutputStatus::Locked));
+		assert!(page.is_empty());
+		assert_eq!(total, 0);
+	}
+
+	#[test]
+	fn state_hash_stable() {
+		let outputs = (1..20)
+			.map(|n_child| output(n_child, n_child as u64 * 10, OutputStatus::Unspent))
+			.collect::<Vec<_>>();
+		let mut reversed = outputs.clone();
+		reversed.reverse();
+		let wallet_data = wallet(outputs.clone());
+		let hash = wallet_data.state_hash();
+		assert_eq!(wallet(reversed).state_hash(), hash);
+		assert_eq!(wallet_data.state_hash(), hash);
+
+		let mut changed = wallet(outputs.clone());
+		changed.lock_output(&outputs[3], None).unwrap();
+		assert!(changed.state_hash() != hash);
+
+		let mut moved = wallet(outputs.clone());
+		moved.outputs.get_mut(&outputs[3].key()).unwrap().height = 7;
+		assert!(moved.state_hash() != hash);
+
+		assert!(wallet(vec![]).state_hash() != hash);
+	}
+
+	#[test]
+	fn corrupt_wallet_data() {
+		let dir = test_dir("corrupt");
+		let lock_config = LockConfig::default();
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+
+		// a wallet.dat cut short halfway through
+		with_test_wallet(&dir, &lock_config, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		let content = fs::read(&data_file_path).unwrap();
+		let truncated = content[..content.len() / 2].to_vec();
+		fs::write(&data_file_path, &truncated).unwrap();
+
+		match with_test_wallet(&dir, &lock_config, |_| ()) {
+			Err(Error::WalletCorrupt(msg)) => {
+				assert!(msg.contains(&data_file_path));
+				assert!(msg.contains("backup"));
+			}
+			res => panic!("expected a corrupt wallet error, got {:?}", res),
+		}
+		match WalletData::read_wallet(&test_config(&dir), |_| ()) {
+			Err(Error::WalletCorrupt(_)) => {}
+			res => panic!("expected a corrupt wallet error, got {:?}", res),
+		}
+
+		// left untouched for the user to deal with
+		assert_eq!(fs::read(&data_file_path).unwrap(), truncated);
+	}
+
+	#[test]
+	fn rolling_backups() {
+		let dir = test_dir("backups");
+		let lock_config = LockConfig::default();
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		let backup = format!("{}.bak", data_file_path);
+		let older_backup = format!("{}.bak.1", data_file_path);
+		let config = WalletConfig {
+			keep_backups: true,
+			..test_config(&dir)
+		};
+		let add = |n_child: u32| {
+			WalletData::with_wallet(&config, |wallet_data| {
+				wallet_data.add_output(output(n_child, 10, OutputStatus::Unspent)).unwrap();
+			}).unwrap();
+			fs::read(&data_file_path).unwrap()
+		};
+
+		// nothing to back up on the first write
+		let first = add(1);
+		assert!(!Path::new(&backup).exists());
+
+		let second = add(2);
+		assert_eq!(fs::read(&backup).unwrap(), first);
+		assert!(!Path::new(&older_backup).exists());
+
+		let third = add(3);
+		assert_eq!(fs::read(&backup).unwrap(), second);
+		assert_eq!(fs::read(&older_backup).unwrap(), first);
+
+		// the oldest one gets dropped
+		add(4);
+		assert_eq!(fs::read(&backup).unwrap(), third);
+		assert_eq!(fs::read(&older_backup).unwrap(), second);
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+		// no backups unless asked for
+		let dir = test_dir("no_backups");
+		for n_child in 1..3 {
+			with_test_wallet(&dir, &lock_config, |wallet_data| {
+				wallet_data.add_output(output(n_child, 10, OutputStatus::Unspent)).unwrap();
+			}).unwrap();
+		}
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+	}
+
+	#[test]
+	fn wallet_formats() {
+		let dir = test_dir("formats");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		let mut labelled = output(2, 20, OutputStatus::Locked);
+		labelled.label = Some("savings".to_string());
+		labelled.tx_hash = Some("abcd".to_string());
+		labelled.locked_until = Some(1_500);
+
+		let formats = [WalletFormat::JsonCompact, WalletFormat::Binary, WalletFormat::Json];
+		let mut written = vec![];
+		for &format in formats.iter() {
+			let mut wallet_data =
+				wallet(vec![output(1, 10, OutputStatus::Unspent), labelled.clone()]);
+			wallet_data.tx_log.push(TxLogEntry {
+				id: 1,
+				tx_type: TxLogEntryType::Received,
+				tx_hash: None,
+				amount_credited: 10,
+				amount_debited: 0,
+				fee: 0,
+				timestamp: 1_000,
+				confirmed_height: Some(5),
+			});
+			wallet_data.write_file(&data_file_path, format, false).unwrap();
+			written.push(fs::read(&data_file_path).unwrap());
+
+			// readable whatever the format configured, and written back in it
+			for &other in formats.iter() {
+				fs::write(&data_file_path, &written[written.len() - 1]).unwrap();
+				let config = WalletConfig {
+					data_format: other,
+					..test_config(&dir)
+				};
+				WalletData::with_wallet(&config, |wallet_data| {
+					assert_eq!(wallet_data.outputs.len(), 2);
+					let out = &wallet_data.outputs[&labelled.key()];
+					assert_eq!(out.status, OutputStatus::Locked);
+					assert_eq!(out.label, Some("savings".to_string()));
+					assert_eq!(out.tx_hash, Some("abcd".to_string()));
+					assert_eq!(out.locked_until, Some(1_500));
+					assert_eq!(wallet_data.tx_log.len(), 1);
+					assert_eq!(wallet_data.tx_log[0].confirmed_height, Some(5));
+				}).unwrap();
+				let rewritten = fs::read(&data_file_path).unwrap();
+				let is_binary = rewritten.starts_with(&WALLET_BIN_MAGIC);
+				assert_eq!(is_binary, other == WalletFormat::Binary);
+			}
+		}
+		let (compact, binary, pretty) = (&written[0], &written[1], &written[2]);
+		assert!(binary.starts_with(&WALLET_BIN_MAGIC));
+		assert!(compact.len() < pretty.len());
+		assert!(!compact.contains(&b'\n'));
+		assert!(binary.len() < compact.len());
+
+		// a damaged binary wallet is reported as corrupt too
+		fs::write(&data_file_path, &binary[..binary.len() / 2]).unwrap();
+		let config = WalletConfig {
+			data_format: WalletFormat::Binary,
+			..test_config(&dir)
+		};
+		match WalletData::with_wallet(&config, |_| ()) {
+			Err(Error::WalletCorrupt(msg)) => assert!(msg.contains(&data_file_path)),
+			res => panic!("expected a corrupt wallet error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn trailing_separator_dir() {
+		let dir = test_dir("trailing_separator");
+		let with_separator = format!("{}{}", dir, path::MAIN_SEPARATOR);
+		let data_file_path = wallet_file_path(&with_separator, DAT_FILE);
+		assert_eq!(data_file_path, wallet_file_path(&dir, DAT_FILE));
+		assert_eq!(Path::new(&data_file_path), Path::new(&dir).join(DAT_FILE));
+		let doubled = format!("{}{}", path::MAIN_SEPARATOR, path::MAIN_SEPARATOR);
+		assert!(!data_file_path.contains(&doubled));
+
+		let lock_config = LockConfig::default();
+		with_test_wallet(&with_separator, &lock_config, |w| {
+			w.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		assert!(Path::new(&dir).join(DAT_FILE).exists());
+		assert!(!Path::new(&dir).join(LOCK_FILE).exists());
+		WalletData::read_wallet(&test_config(&dir), |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 1);
+		}).unwrap();
+	}
+
+	#[test]
+	fn memory_backend_roundtrip() {
+		let mut backend = MemoryBackend::new();
+		WalletData::read_backend(&mut backend, |wallet_data| {
+			assert!(wallet_data.outputs.is_empty());
+		}).unwrap();
+		assert!(backend.wallet_data().is_none());
+
+		WalletData::with_backend(&mut backend, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+			wallet_data.add_output(output(2, 20, OutputStatus::Locked)).unwrap();
+		}).unwrap();
+		assert_eq!(backend.writes(), 1);
+		WalletData::read_backend(&mut backend, |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 2);
+			let out = wallet_data.get_output(&output(2, 0, OutputStatus::Spent).key_id).unwrap();
+			assert_eq!(out.value, 20);
+			assert_eq!(out.status, OutputStatus::Locked);
+		}).unwrap();
+		assert_eq!(backend.writes(), 1);
+
+		// several operations in a session, written once
+		let mut session = WalletData::open_backend_session(&mut backend).unwrap();
+		session.run(|wallet_data| {
+			wallet_data.add_output(output(3, 30, OutputStatus::Unspent)).unwrap();
+		});
+		session.run(|wallet_data| wallet_data.unlock_all_locked());
+		session.commit().unwrap();
+		assert_eq!(backend.writes(), 2);
+		let wallet_data = backend.wallet_data().unwrap();
+		assert_eq!(wallet_data.balance(Identifier::zero()).unspent, 60);
+	}
+
+	#[test]
+	fn same_selection_on_all_backends() {
+		let outputs = (1..30)
+			.map(|n_child| output(n_child, (n_child as u64 * 7) % 31 + 1, OutputStatus::Unspent))
+			.collect::<Vec<_>>();
+		let fill = |wallet_data: &mut WalletData| {
+			for out in &outputs {
+				wallet_data.add_output(out.clone()).unwrap();
+			}
+		};
+		let dir = test_dir("backends");
+		let lock_config = LockConfig::default();
+		let mut file_backend = FileBackend::new(&dir, &lock_config);
+		file_backend.sync_writes = false;
+		let mut memory_backend = MemoryBackend::new();
+		WalletData::with_backend(&mut file_backend, &fill).unwrap();
+		WalletData::with_backend(&mut memory_backend, &fill).unwrap();
+		assert!(Path::new(&wallet_file_path(&dir, DAT_FILE)).exists());
+
+		let select = |wallet_data: &WalletData, amount| {
+			let (coins, change) = wallet_data.select(Identifier::zero(), amount, 3);
+			(coins.iter().map(|out| out.n_child).collect::<Vec<_>>(), change)
+		};
+		for &amount in [1, 17, 60, 200, 1_000].iter() {
+			let from_file = WalletData::read_backend(&mut file_backend, |w| select(w, amount));
+			let from_memory = WalletData::read_backend(&mut memory_backend, |w| select(w, amount));
+			assert_eq!(from_file.unwrap(), from_memory.unwrap());
+		}
+	}
+
+	#[test]
+	fn session_writes_once() {
+		let dir = test_dir("session");
+		let data_file_path = wallet_file_path(&dir, DAT_FILE);
+		let lock_file_path = wallet_file_path(&dir, LOCK_FILE);
+		let backup = format!("{}.bak", data_file_path);
+		let config = WalletConfig {
+			keep_backups: true,
+			..test_config(&dir)
+		};
+		WalletData::with_wallet(&config, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent)).unwrap();
+		}).unwrap();
+		let before = fs::read(&data_file_path).unwrap();
+
+		let mut session = WalletData::open_session(&config).unwrap();
+		session.run(|wallet_data| {
+			wallet_data.add_output(output(2, 20, OutputStatus::Unspent)).unwrap();
+		});
+		let key_id = session.run(|wallet_data| {
+			let out = output(3, 30, OutputStatus::Unspent);
+			wallet_data.add_output(out.clone()).unwrap();
+			out.key_id
+		});
+		assert!(Path::new(&lock_file_path).exists());
+		assert_eq!(fs::read(&data_file_path).unwrap(), before);
+		session.commit().unwrap();
+
+		// a single write, rotating a single backup
+		assert!(!Path::new(&lock_file_path).exists());
+		assert_eq!(fs::read(&backup).unwrap(), before);
+		assert!(!Path::new(&format!("{}.1", backup)).exists());
+		WalletData::read_wallet(&config, |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 3);
+			assert!(wallet_data.outputs.contains_key(&OutputKey::new(&key_id)));
+		}).unwrap();
+
+		// dropping the session discards the changes and releases the lock
+		{
+			let mut session = WalletData::open_session(&test_config(&dir)).unwrap();
+			session.run(|wallet_data| wallet_data.outputs.clear());
+		}
+		assert!(!Path::new(&lock_file_path).exists());
+		WalletData::read_wallet(&config, |wallet_data| {
+			assert_eq!(wallet_data.outputs.len(), 3);
+		}).unwrap();
+	}
+
+	#[test]
+	fn verify_output_commitments() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let good = keyed_output(&keychain, 1, 10, OutputStatus::Unspent);
+		// recorded with the key of another derivation
+		let mut bad = keyed_output(&keychain, 2, 20, OutputStatus::Unspent);
+		bad.key_id = keychain.derive_key_id(3).unwrap();
+		let bad_key = bad.key_id.to_hex();
+		// not ours to check
+		let mut other_root = output(4, 40, OutputStatus::Unspent);
+		other_root.root_key_id = Identifier::from_bytes(&[9]);
+
+		let wallet_data = wallet(vec![good.clone()]);
+		assert!(wallet_data.verify_commitments(&keychain).unwrap().is_empty());
+
+		let wallet_data = wallet(vec![good, bad, other_root]);
+		assert_eq!(wallet_data.verify_commitments(&keychain).unwrap(), vec![bad_key]);
+	}
+
+	#[test]
+	fn select_all_spendable_outputs() {
+		let mut mature_coinbase = output(1, 60, OutputStatus::Unspent);
+		mature_coinbase.is_coinbase = true;
+		mature_coinbase.height = 10;
+		let mut immature_coinbase = output(2, 60, OutputStatus::Unspent);
+		immature_coinbase.is_coinbase = true;
+		immature_coinbase.height = 95;
+		let mut not_ours = output(7, 1000, OutputStatus::Unspent);
+		not_ours.root_key_id = Identifier::from_bytes(&[9]);
+		let wallet_data = wallet(vec![
+			mature_coinbase,
+			immature_coinbase,
+			output(3, 5, OutputStatus::Unspent),
+			output(4, 7, OutputStatus::Immature),
+			output(5, 11, OutputStatus::Locked),
+			output(6, 13, OutputStatus::Unconfirmed),
+			output(8, 17, OutputStatus::Unspent),
+			not_ours,
+		]);
+
+		let (coins, total) = wallet_data.select_all_spendable(Identifier::zero(), 100, 10, 1);
+		assert_eq!(coins.iter().map(|out| out.n_child).collect::<Vec<_>>(), vec![1, 3, 8]);
+		assert_eq!(total, 82);
+
+		let (coins, total) = wallet_data.select_all_spendable(Identifier::zero(), 105, 10, 1);
+		assert_eq!(coins.len(), 4);
+		assert_eq!(total, 142);
+	}
+
+	#[test]
+	fn import_output_json_valid() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let out = keyed_output(&keychain, 3, 30, OutputStatus::Unspent);
+		let source = wallet(vec![out.clone()]);
+		let json = source.export_output_json(&out.key_id, &keychain).unwrap();
+
+		let mut wallet_data = WalletData::new();
+		let key_id = wallet_data.import_output_json(&json, &keychain).unwrap();
+		assert_eq!(key_id, out.key_id);
+		let imported = wallet_data.get_output(&key_id).unwrap();
+		assert_eq!(imported.value, 30);
+		assert_eq!(imported.n_child, 3);
+
+		// importing it twice is refused by add_output
+		assert!(wallet_data.import_output_json(&json, &keychain).is_err());
+	}
+
+	#[test]
+	fn import_output_json_tampered() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let out = keyed_output(&keychain, 3, 30, OutputStatus::Unspent);
+		let source = wallet(vec![out.clone()]);
+		let json = source.export_output_json(&out.key_id, &keychain).unwrap();
+
+		let mut blob: serde_json::Value = serde_json::from_str(&json).unwrap();
+		blob["output"]["value"] = serde_json::Value::from(3000u64);
+		let tampered = serde_json::to_string(&blob).unwrap();
+
+		let mut wallet_data = WalletData::new();
+		match wallet_data.import_output_json(&tampered, &keychain) {
+			Err(Error::WalletData(msg)) => assert!(msg.contains("commitment")),
+			res => panic!("tampered output imported: {:?}", res),
+		}
+		assert!(wallet_data.outputs.is_empty());
+
+		let other = Keychain::from_random_seed().unwrap();
+		assert!(wallet_data.import_output_json(&json, &other).is_err());
+		assert!(wallet_data.import_output_json("{}", &keychain).is_err());
+	}
+
+	#[test]
+	fn output_key_formatting() {
+		let out = output(1, 50, OutputStatus::Unspent);
+		let mut wallet_data = WalletData::new();
+		wallet_data.add_output(out.clone()).unwrap();
+
+		let key = OutputKey::new(&out.key_id);
+		assert_eq!(out.key(), key);
+		assert_eq!(key.as_str(), out.key_id.to_hex());
+		assert_eq!(wallet_data.outputs.keys().collect::<Vec<_>>(), vec![&key]);
+		assert_eq!(wallet_data.get_output(&out.key_id).unwrap().value, 50);
+
+		// locking finds the very entry add_output inserted
+		wallet_data.lock_output(&out, None).unwrap();
+		assert_eq!(wallet_data.outputs.len(), 1);
+		assert_eq!(wallet_data.outputs[&key].status, OutputStatus::Locked);
+		assert_eq!(wallet_data.get_output(&out.key_id).unwrap().status, OutputStatus::Locked);
+
+		// the JSON keys are still the plain key_id hex
+		let json: serde_json::Value = serde_json::to_value(&wallet_data).unwrap();
+		let outputs = json.get("outputs").and_then(|o| o.as_object()).unwrap();
+		assert!(outputs.contains_key(&out.key_id.to_hex()));
+		let read: WalletData = serde_json::from_value(json.clone()).unwrap();
+		assert_eq!(read.outputs[&key].status, OutputStatus::Locked);
+	}
 }